@@ -3,30 +3,193 @@
 
 #include <stdint.h>
 #include <stdbool.h>
+#include <stddef.h>
 
 #ifdef __cplusplus
 extern "C" {
 #endif
 
+// Thread safety: a HarmonyEncodingWrapper is immutable once created and may be
+// shared by any number of threads calling the functions that take it as const.
+// A StreamableParserWrapper, HarmonyDecoderWrapper or any builder must only be
+// used by one thread at a time.
+
+// Input arrays: any (pointer, length) input with length 0 is treated as empty,
+// and its pointer may be null.
+
+// Panics: no Rust panic unwinds into the caller. If a function panics
+// internally it fails like any other error: a HarmonyResult with
+// HARMONY_ERROR_PANIC, or null / false / 0 with the panic message available
+// from harmony_last_error_message.
+
+// Handles: HarmonyEncodingWrapper and StreamableParserWrapper handles are
+// tagged, and their free functions clear the tag. A handle that was already
+// freed is then usually rejected with HARMONY_ERROR_INVALID_HANDLE (or null /
+// false / 0 and the last error) instead of being used, and freeing it again
+// does nothing. This catches use-after-free and double-free bugs during
+// development but cannot detect every case, since freed memory may be reused.
+
 // Opaque types
 typedef struct HarmonyEncodingWrapper HarmonyEncodingWrapper;
 typedef struct StreamableParserWrapper StreamableParserWrapper;
+typedef struct HarmonyDecoderWrapper HarmonyDecoderWrapper;
+typedef struct SystemContentBuilder SystemContentBuilder;
+typedef struct DeveloperContentBuilder DeveloperContentBuilder;
+typedef struct ConversationBuilder ConversationBuilder;
+
+// Stable error codes reported in HarmonyResult.error_code
+typedef enum {
+    HARMONY_ERROR_OK = 0,
+    HARMONY_ERROR_NULL_POINTER = 1,
+    HARMONY_ERROR_INVALID_UTF8 = 2,
+    HARMONY_ERROR_RENDER_FAILED = 3,
+    HARMONY_ERROR_PARSE_FAILED = 4,
+    HARMONY_ERROR_TOKENIZE_FAILED = 5,
+    HARMONY_ERROR_INVALID_ARGUMENT = 6,
+    HARMONY_ERROR_SERIALIZE_FAILED = 7,
+    HARMONY_ERROR_BUFFER_TOO_SMALL = 8,
+    HARMONY_ERROR_TOO_LONG = 9,
+    HARMONY_ERROR_PANIC = 10,
+    HARMONY_ERROR_INVALID_HANDLE = 11,
+} HarmonyErrorCode;
+
+// Message author roles. Functions take and return roles as int32_t holding a
+// HarmonyRole value, or -1 for no role; any other value is rejected.
+typedef enum {
+    HARMONY_ROLE_SYSTEM = 0,
+    HARMONY_ROLE_DEVELOPER = 1,
+    HARMONY_ROLE_USER = 2,
+    HARMONY_ROLE_ASSISTANT = 3,
+    HARMONY_ROLE_TOOL = 4,
+} HarmonyRole;
+
+// Formatting token classification returned by
+// harmony_encoding_token_role_marker as an int32_t
+typedef enum {
+    HARMONY_TOKEN_MARKER_NONE = 0,
+    HARMONY_TOKEN_MARKER_START = 1,
+    HARMONY_TOKEN_MARKER_MESSAGE = 2,
+    HARMONY_TOKEN_MARKER_END = 3,
+    HARMONY_TOKEN_MARKER_RETURN = 4,
+    HARMONY_TOKEN_MARKER_CALL = 5,
+    HARMONY_TOKEN_MARKER_CHANNEL = 6,
+    HARMONY_TOKEN_MARKER_CONSTRAIN = 7,
+    HARMONY_TOKEN_MARKER_REFUSAL = 8,
+    HARMONY_TOKEN_MARKER_BEGIN_UNTRUSTED = 9,
+    HARMONY_TOKEN_MARKER_END_UNTRUSTED = 10,
+} HarmonyTokenMarker;
+
+// Who speaks next after a conversation, returned by
+// harmony_encoding_conversation_needs_new_turn as an int32_t
+typedef enum {
+    HARMONY_NEXT_TURN_ASSISTANT_NEW = 0,
+    HARMONY_NEXT_TURN_ASSISTANT_CONTINUE = 1,
+    HARMONY_NEXT_TURN_TOOL = 2,
+    HARMONY_NEXT_TURN_USER = 3,
+} HarmonyNextTurn;
 
 // Result type for operations that can fail
 typedef struct {
     bool success;
     char* error_message;
+    int32_t error_code; // a HarmonyErrorCode
 } HarmonyResult;
 
+// Where and why completion tokens failed to parse. The parse functions reset
+// it on entry when passed non-null; on a parse failure code is
+// HARMONY_ERROR_PARSE_FAILED, token_index is the offending token (tokens_len if
+// the last message is unterminated) and message describes the problem. Release
+// the message with harmony_parse_error_free.
+typedef struct {
+    int32_t code; // a HarmonyErrorCode
+    size_t token_index;
+    char* message;
+} HarmonyParseError;
+
+// Result of harmony_encoding_encode_plain_counted. On failure tokens is null
+// and both lengths are 0.
+typedef struct {
+    HarmonyResult result;
+    uint32_t* tokens;       // free with harmony_free_tokens(tokens, tokens_len)
+    size_t tokens_len;
+    size_t text_byte_len;   // length of the input text in bytes
+} HarmonyEncodedText;
+
+// Version of the harmony crate, e.g. "0.0.3". The string is static and must
+// not be freed; this may be called before any encoding is loaded.
+const char* harmony_version(void);
+
+// Last error on the calling thread. Every failing function records its error
+// message here, including those that only signal failure by returning null
+// (harmony_encoding_new, harmony_encoding_decode, harmony_parser_new, ...).
+// The returned string is owned by the library and stays valid until the next
+// failing call or harmony_clear_last_error on the same thread; do not free it.
+// Returns null when no error has been recorded. Successful calls leave it as is.
+// Error messages never lose text to embedded NUL bytes: those are written as
+// the four characters \x00.
+const char* harmony_last_error_message(void);
+void harmony_clear_last_error(void);
+
 // Memory management
+// Every function returning a HarmonyResult sets error_message to null on
+// success. On failure error_message is owned by the caller and must be freed,
+// either with harmony_result_free or harmony_free_string(result.error_message).
+// harmony_result_free also resets error_message to null, so calling it again on
+// the same result is harmless.
+void harmony_result_free(HarmonyResult* result);
+void harmony_parse_error_free(HarmonyParseError* error);
 void harmony_free_string(char* s);
 void harmony_free_tokens(uint32_t* tokens, size_t len);
+void harmony_free_offsets(size_t* offsets, size_t len);
+void harmony_free_lengths(size_t* lengths, size_t len);
+void harmony_free_mask(uint8_t* mask, size_t len);
+void harmony_free_bytes(uint8_t* bytes, size_t len);
+void harmony_free_tokens_i32(int32_t* tokens, size_t len);
+
+// Custom allocation. By default every buffer and string handed to the caller
+// comes from Rust's global allocator. harmony_set_allocators makes them come
+// from alloc_fn instead, and the harmony_free_* functions release them with
+// free_fn, so they can also be released directly with free_fn. alloc_fn must
+// return memory aligned for any type, as malloc does; returning null aborts.
+// Empty buffers are returned as null. Pass null for both to restore the
+// default; passing only one fails with HARMONY_ERROR_INVALID_ARGUMENT. The
+// allocators may be changed at any time: the harmony_free_* functions release
+// each buffer with the free_fn of the allocator it came from.
+typedef void* (*HarmonyAllocFn)(size_t size);
+typedef void (*HarmonyFreeFn)(void* ptr);
+HarmonyResult harmony_set_allocators(HarmonyAllocFn alloc_fn, HarmonyFreeFn free_fn);
 
 // Harmony Encoding functions
+// harmony_encoding_new loads the default encoding (HarmonyGptOss).
 HarmonyEncodingWrapper* harmony_encoding_new(void);
+// Load an encoding by name, e.g. "HarmonyGptOss". Returns null for unknown
+// names or if the encoding fails to load.
+HarmonyEncodingWrapper* harmony_encoding_new_by_name(const char* name);
+// Load an encoding from an in-memory copy of its tokenizer's vocab file (for
+// HarmonyGptOss, the contents of o200k_base.tiktoken), e.g. one shipped as an
+// app resource. name selects the encoding as in harmony_encoding_new_by_name;
+// null or empty means HarmonyGptOss. The data must match the published vocab
+// file. Returns null with a harmony_last_error_message on failure. The data is
+// not referenced after the call returns.
+HarmonyEncodingWrapper* harmony_encoding_new_from_data(
+    const uint8_t* data,
+    size_t len,
+    const char* name
+);
+
+// New handle sharing an already loaded encoding's vocabulary, so it costs no
+// reload. Each handle is freed independently with harmony_encoding_free.
+HarmonyEncodingWrapper* harmony_encoding_clone(const HarmonyEncodingWrapper* wrapper);
 void harmony_encoding_free(HarmonyEncodingWrapper* wrapper);
 
+// Identify an encoding as "<encoding name>/<tokenizer name>", e.g.
+// "HarmonyGptOss/o200k_harmony". Free the string with harmony_free_string.
+char* harmony_encoding_version(const HarmonyEncodingWrapper* wrapper);
+
 // Plain text encoding - encode text without Harmony formatting
+// Text passed to the encode, count, render and builder functions must be valid
+// UTF-8; anything else fails with HARMONY_ERROR_INVALID_UTF8 instead of
+// encoding nothing. Optional strings are only unset when null or empty.
 HarmonyResult harmony_encoding_encode_plain(
     const HarmonyEncodingWrapper* wrapper,
     const char* text,
@@ -34,6 +197,254 @@ HarmonyResult harmony_encoding_encode_plain(
     size_t* tokens_len
 );
 
+// Plain text encoding as harmony_encoding_encode_plain. When include_header is
+// true the tokens are preceded by the header of a message from role (a
+// HarmonyRole, or -1 for assistant), e.g. <|start|>assistant<|message|>, so a
+// model continues the text as that message without building a conversation.
+// role is ignored when include_header is false.
+HarmonyResult harmony_encoding_encode_plain_with_header(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    bool include_header,
+    int32_t role,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Length limits: the encode functions that take max_tokens stop tokenizing as
+// soon as the text produces more than max_tokens tokens and fail with
+// HARMONY_ERROR_TOO_LONG, returning no tokens. 0 means no limit.
+HarmonyResult harmony_encoding_encode_plain_limited(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    size_t max_tokens,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Plain text encoding into int32_t token ids, for runtimes that take signed
+// ids. Every id is checked to fit; one above INT32_MAX fails with
+// HARMONY_ERROR_INVALID_ARGUMENT and returns no tokens. Free the buffer with
+// harmony_free_tokens_i32.
+HarmonyResult harmony_encoding_encode_plain_i32(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    int32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Plain text encoding of exactly text_len bytes of text, which need not be
+// NUL-terminated and may contain NUL bytes (e.g. binary tool output), unlike
+// the functions taking a C string, which stop at the first NUL. text may be
+// null when text_len is 0. max_tokens is as for
+// harmony_encoding_encode_plain_limited.
+HarmonyResult harmony_encoding_encode_plain_len(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    size_t text_len,
+    size_t max_tokens,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Vocabulary bounds for sizing logit buffers. harmony_encoding_vocab_size
+// counts every token id, ordinary and special; ids are dense, so it equals
+// harmony_encoding_max_token_id(wrapper) + 1. Both return 0 and set
+// harmony_last_error_message for a null wrapper.
+size_t harmony_encoding_vocab_size(const HarmonyEncodingWrapper* wrapper);
+uint32_t harmony_encoding_max_token_id(const HarmonyEncodingWrapper* wrapper);
+
+// Token classification for constrained sampling; both run in constant time.
+// harmony_encoding_is_special_token returns whether a token is any special
+// token. harmony_encoding_token_role_marker returns the HarmonyTokenMarker of a
+// formatting token, or HARMONY_TOKEN_MARKER_NONE for ordinary text and other
+// special tokens. Both treat a null wrapper as an ordinary token.
+bool harmony_encoding_is_special_token(const HarmonyEncodingWrapper* wrapper, uint32_t token);
+int32_t harmony_encoding_token_role_marker(const HarmonyEncodingWrapper* wrapper, uint32_t token);
+
+// Diagnostic for checking the bridge: encodes text as plain text, decodes the
+// tokens again and returns whether the result equals the input. Returns false
+// and sets harmony_last_error_message on invalid arguments.
+bool harmony_encoding_roundtrip_ok(const HarmonyEncodingWrapper* wrapper, const char* text);
+
+// Plain text encoding that returns the tokens, their count and the byte length
+// of the input text in one struct. Check result.success before using the rest.
+HarmonyEncodedText harmony_encoding_encode_plain_counted(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    size_t max_tokens
+);
+
+// Header tokens for a channel or recipient, for building logit masks. marker
+// is "channel:<name>" (e.g. "channel:analysis"), rendered as <|channel|> and
+// the name, or "recipient:<name>" (e.g. "recipient:functions.get_weather"),
+// rendered as " to=<name>". The tokens are exactly those a rendered message
+// header contains; the recipient "all" is implied and yields no tokens. Free
+// tokens_out with harmony_free_tokens.
+HarmonyResult harmony_encoding_tokens_for_marker(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* marker,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Encoding that maps special-token markers such as <|start|> in the text to
+// their ids. allowed_special_json is a JSON array of the special-token strings
+// to recognize (e.g. ["<|start|>", "<|end|>"]); null allows every known special
+// token. Markers that are not allowed are encoded as ordinary text.
+HarmonyResult harmony_encoding_encode_with_special(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    const char* allowed_special_json,
+    size_t max_tokens,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Batch plain text encoding - encode texts_len strings in one call. The tokens
+// of all texts are concatenated into one buffer of total_len tokens; text i owns
+// tokens [offsets[i], offsets[i + 1]). The caller owns both buffers and frees
+// them with harmony_free_tokens(tokens, total_len) and
+// harmony_free_offsets(offsets, texts_len + 1). max_tokens limits each text.
+HarmonyResult harmony_encoding_encode_plain_batch(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* const* texts,
+    size_t texts_len,
+    size_t max_tokens,
+    uint32_t** tokens_out,
+    size_t** offsets_out,
+    size_t* total_len
+);
+
+// Encode plain text once and split its tokens into windows of at most window
+// tokens, each overlapping the previous one by stride tokens (stride must be
+// less than window). The last window ends at the final token and may be
+// shorter; empty text gives no windows. The windows are concatenated into one
+// buffer in the layout of harmony_encoding_encode_plain_batch: window i owns
+// tokens [offsets[i], offsets[i + 1]) and count_out receives the number of
+// windows. Free the buffers with harmony_free_tokens(tokens, offsets[count])
+// and harmony_free_offsets(offsets, count + 1).
+HarmonyResult harmony_encoding_encode_windows(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    size_t window,
+    size_t stride,
+    uint32_t** tokens_out,
+    size_t** offsets_out,
+    size_t* count_out
+);
+
+// Count the tokens of plain text without returning them
+HarmonyResult harmony_encoding_count_tokens(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* text,
+    size_t* count_out
+);
+
+// Count the tokens harmony_encoding_render_conversation_json would produce
+HarmonyResult harmony_encoding_count_conversation_tokens(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    const bool* auto_drop_analysis,
+    size_t* count_out
+);
+
+// Token count of each message of a conversation supplied as JSON (as in
+// harmony_encoding_render_conversation_json), e.g. for truncating a history to
+// a budget one whole message at a time. lengths_out receives count_out entries,
+// one per message in order; free them with harmony_free_lengths(lengths,
+// count). Each length covers the message's header (<|start|>, role, channel,
+// recipient), its content and its closing token. There are no tokens outside
+// the messages, so the lengths add up to harmony_encoding_count_conversation_tokens
+// with the same auto_drop_analysis, and messages it drops count 0. Lengths are
+// not independent of the rest of the conversation: the system message grows
+// when a developer message declares function tools, and removing the last
+// final message can stop analysis messages from being dropped, so recount
+// after removing messages.
+HarmonyResult harmony_encoding_message_token_lengths(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    const bool* auto_drop_analysis,
+    size_t** lengths_out,
+    size_t* count_out
+);
+
+// Drop the oldest messages of a JSON conversation until it renders to at most
+// max_tokens tokens (0 means no limit) and write the remaining conversation as
+// JSON to conversation_out; free it with harmony_free_string. System and
+// developer messages are never dropped. With keep_last_user the most recent
+// user message is kept as well, even if the result then exceeds the budget.
+// Returns HARMONY_ERROR_TOO_LONG when the messages that are kept do not fit.
+// auto_drop_analysis is as for harmony_encoding_render_conversation_json.
+HarmonyResult harmony_encoding_truncate_to_budget(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    const bool* auto_drop_analysis,
+    size_t max_tokens,
+    bool keep_last_user,
+    char** conversation_out
+);
+
+// Classify where a JSON conversation stands, based on its last message, as a
+// HarmonyNextTurn written to next_turn_out:
+// - ASSISTANT_NEW: empty, or the last message is from the user, system or
+//   developer; the assistant starts a new turn.
+// - ASSISTANT_CONTINUE: the last message is an assistant analysis or commentary
+//   message, or a tool result; the assistant's turn is still open and its next
+//   message continues it.
+// - TOOL: the last message is an assistant tool call (a recipient other than
+//   "all"); run the tool and append its result first.
+// - USER: the last message is on the assistant's final channel; its turn is
+//   complete and the next message should come from the user.
+// For ASSISTANT_NEW and ASSISTANT_CONTINUE, render the conversation for
+// completion with the assistant as next role. Every message in the JSON is
+// rendered complete, so that render appends exactly one
+// <|start|>assistant header; do not add another.
+HarmonyResult harmony_encoding_conversation_needs_new_turn(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    int32_t* next_turn_out
+);
+
+// Render only the static start of a conversation, for prompt caching: the
+// system and developer messages, each passed as a JSON message object exactly
+// as it appears in the conversation JSON (see
+// harmony_encoding_render_conversation_json), or null to omit it. The tokens
+// are a prefix of every render of a conversation that starts with these
+// messages, with or without auto_drop_analysis and for completion, as long as
+// no later developer message declares function tools (which changes the
+// system message). Free the tokens with harmony_free_tokens.
+HarmonyResult harmony_encoding_render_prefix(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* system_json,
+    const char* developer_json,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Prefix cache for sessions whose system and developer messages rarely change.
+// harmony_encoding_set_cached_prefix renders the two messages, taken as in
+// harmony_encoding_render_prefix, and stores them and their tokens in the
+// wrapper, replacing any earlier prefix in one step; passing null for both
+// clears the cache. harmony_encoding_render_with_cached_prefix then renders the
+// cached prefix, a user message and <|start|>assistant for completion without
+// rendering the prefix again. The tokens equal
+// harmony_encoding_render_for_completion of the same messages.
+// It fails with HARMONY_ERROR_INVALID_ARGUMENT when no prefix is cached. Both
+// are safe to call from several threads on one wrapper; a render sees either
+// the old or the new prefix. Free the tokens with harmony_free_tokens.
+HarmonyResult harmony_encoding_set_cached_prefix(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* system_json,
+    const char* developer_json
+);
+HarmonyResult harmony_encoding_render_with_cached_prefix(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* user_msg,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
 // Harmony prompt rendering
 HarmonyResult harmony_encoding_render_prompt(
     const HarmonyEncodingWrapper* wrapper,
@@ -44,6 +455,211 @@ HarmonyResult harmony_encoding_render_prompt(
     size_t* tokens_len
 );
 
+// Harmony prompt rendering with developer instructions and system settings.
+// system_msg, developer_msg and assistant_prefix may be null or empty to omit
+// them. reasoning_effort is 0=low, 1=medium, 2=high, or -1 to keep the default;
+// any other value returns an error. knowledge_cutoff (e.g. "2024-06") and
+// conversation_start_date (e.g. "2025-08-05") may be null or empty to keep the
+// defaults. A system message is rendered when any of system_msg,
+// reasoning_effort, knowledge_cutoff or conversation_start_date is set.
+// assistant_channel (e.g. "analysis") and assistant_recipient (e.g.
+// "functions.get_weather") set the channel and recipient of the assistant
+// message; null or empty leaves them unset. The assistant message is rendered
+// when any of assistant_prefix, assistant_channel or assistant_recipient is set.
+HarmonyResult harmony_encoding_render_prompt_v2(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* system_msg,
+    int32_t reasoning_effort,
+    const char* knowledge_cutoff,
+    const char* conversation_start_date,
+    const char* developer_msg,
+    const char* user_msg,
+    const char* assistant_prefix,
+    const char* assistant_channel,
+    const char* assistant_recipient,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render a conversation of plain-text turns. roles and texts are parallel
+// arrays of count entries each: roles[i] is a HarmonyRole value (-1 is
+// rejected) and texts[i] the non-null content of that turn. Messages are
+// rendered in order without channels or a default system message.
+HarmonyResult harmony_encoding_render_turns(
+    const HarmonyEncodingWrapper* wrapper,
+    const int32_t* roles,
+    const char* const* texts,
+    size_t count,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render a developer message carrying function tools, followed by a user
+// message. tools_json is a JSON array of tool definitions, e.g.
+// [{"name": "get_weather", "description": "Gets the weather.",
+//   "parameters": {"type": "object", "properties": {...}}}]
+// and is rendered as the "functions" tool namespace. Every tool needs a
+// non-empty name; description and parameters are optional.
+// developer_instructions may be null or empty to omit them.
+HarmonyResult harmony_encoding_render_with_tools(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* developer_instructions,
+    const char* tools_json,
+    const char* user_msg,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render a full conversation supplied as JSON, e.g.
+// {"messages": [{"role": "user", "content": "Hi"},
+//               {"role": "assistant", "channel": "final", "content": "Hello"}]}
+// Malformed JSON or a schema mismatch returns a descriptive error.
+// Tool results use role "tool" with the tool's name in "name" (or "author"),
+// e.g. {"role": "tool", "name": "functions.get_weather", "recipient":
+// "assistant", "channel": "commentary", "content": "{\"temperature\": 20}"}.
+// Messages may also set "recipient" and "content_type". The content type is
+// rendered verbatim in the header after the channel, e.g. "<|constrain|>json",
+// which gpt-oss uses for JSON tool-call arguments; the renderer does not
+// interpret it. When parsing tokens back, a content type is only recognized on
+// messages that also have a recipient.
+// System and developer messages can carry their full structured settings
+// instead of a string: "content" is then a list holding an object whose "type"
+// is "system_content" or "developer_content", e.g.
+// {"role": "system", "content": [{"type": "system_content",
+//   "model_identity": "You are ChatGPT, ...", "reasoning_effort": "high",
+//   "knowledge_cutoff": "2024-06", "conversation_start_date": "2025-08-05",
+//   "channel_config": {"valid_channels": ["analysis", "commentary", "final"],
+//                      "channel_required": true},
+//   "tools": {"browser": {"name": "browser", "description": "...", "tools": [...]}}}]}
+// {"role": "developer", "content": [{"type": "developer_content",
+//   "instructions": "Answer briefly.",
+//   "tools": {"functions": {"name": "functions", "tools": [
+//     {"name": "get_weather", "description": "Gets the weather.",
+//      "parameters": {"type": "object", "properties": {...}}}]}}}]}
+// Every field of these objects is optional; omitted ones are unset rather than
+// taking the defaults of harmony_system_content_new. reasoning_effort is
+// "low", "medium" or "high" (or capitalized). tools maps each namespace name to
+// a namespace of the same "name"; a tool needs "name" and "description", and
+// "parameters", if present, must be an object. Unknown fields and structured
+// objects in a message of another role fail with HARMONY_ERROR_INVALID_ARGUMENT
+// and a message naming the offending path, e.g.
+// "messages[0].content[0]: unknown field `reasoning`"; so do values of the
+// wrong type. The other functions taking conversation JSON accept the same
+// schema.
+// auto_drop_analysis may be null to render without a config, which keeps all
+// analysis messages. When it points to true and the last assistant message is
+// on the final channel, analysis messages before the first final message are
+// dropped, so earlier chain-of-thought is not fed back to the model.
+HarmonyResult harmony_encoding_render_conversation_json(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    const bool* auto_drop_analysis,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render a conversation supplied as JSON to text with the special tokens spelled
+// out, e.g. "<|start|>user<|message|>Hi<|end|>", for logging and previews.
+// next_role (a HarmonyRole, or -1 for none) starts a new turn as
+// harmony_encoding_render_for_completion does. auto_drop_analysis is as in
+// harmony_encoding_render_conversation_json. Free the text with
+// harmony_free_string.
+HarmonyResult harmony_encoding_render_conversation_text(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    int32_t next_role,
+    const bool* auto_drop_analysis,
+    char** text_out
+);
+
+// Render only the messages after the first prefix_messages of a JSON
+// conversation, for appending to cached prefix tokens. When the cached tokens
+// are harmony_encoding_render_conversation_json of exactly those first messages
+// with the same auto_drop_analysis, prefix + suffix equals the render of the
+// whole conversation. If the new messages would change how the prefix renders
+// (new function tools, or analysis messages in the prefix that
+// auto_drop_analysis now drops), this fails with HARMONY_ERROR_RENDER_FAILED
+// and the whole conversation has to be rendered again.
+HarmonyResult harmony_encoding_render_suffix(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    size_t prefix_messages,
+    const bool* auto_drop_analysis,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render two conversations supplied as JSON, as
+// harmony_encoding_render_conversation_json does with a null
+// auto_drop_analysis, and write the tokens the new render appends to the
+// previous one, for extending a KV cache filled with the previous render. If
+// the previous render is not a prefix of the new one (history was edited, or
+// the new messages change how earlier ones render), this fails with
+// HARMONY_ERROR_RENDER_FAILED naming the first differing token, and the new
+// conversation has to be rendered in full. Free the tokens with
+// harmony_free_tokens.
+HarmonyResult harmony_encoding_render_delta(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* prev_conversation_json,
+    const char* new_conversation_json,
+    uint32_t** delta_tokens_out,
+    size_t* delta_len_out
+);
+
+// Render a JSON conversation for completion: the output ends with the header
+// start of next_role (e.g. <|start|>assistant) so the model continues in that
+// role. auto_drop_analysis is interpreted as in
+// harmony_encoding_render_conversation_json.
+// omit_final_terminator is for continuing generation across turns: when true
+// and the last message is an assistant message on the final channel, the
+// output ends with that message's content instead of
+// "<|end|><|start|>{next_role}", so the model extends the message. Otherwise it
+// has no effect. Pass false for the normal behavior.
+HarmonyResult harmony_encoding_render_for_completion(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    int32_t next_role,
+    const bool* auto_drop_analysis,
+    bool omit_final_terminator,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render a JSON conversation that ends in an assistant tool call together with
+// the tool's output, for completion of the assistant's next message. The output
+// is appended as the tool response the model expects, e.g.
+// <|start|>functions.get_weather to=assistant<|channel|>commentary<|message|>
+// {"temp": 20}<|end|>, named after the call's recipient and on the call's
+// channel, followed by <|start|>assistant. Analysis from the ongoing turn is
+// kept even with auto_drop_analysis (interpreted as in
+// harmony_encoding_render_conversation_json). Returns
+// HARMONY_ERROR_INVALID_ARGUMENT if the last message is not a tool call (see
+// HARMONY_NEXT_TURN_TOOL). Free the tokens with harmony_free_tokens.
+HarmonyResult harmony_encoding_render_tool_response(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    const char* tool_output,
+    const bool* auto_drop_analysis,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render a JSON conversation for training (a trailing final assistant message
+// ends with <|return|>) together with a loss mask of tokens_len bytes: 1 marks a
+// token the model should learn to produce, 0 a prompt token. Assistant messages
+// are trainable after their <|start|>assistant prefix, from the channel header
+// through the closing token; every other token is prompt. Free the buffers with
+// harmony_free_tokens(tokens, tokens_len) and harmony_free_mask(mask, tokens_len).
+// auto_drop_analysis is interpreted as in harmony_encoding_render_conversation_json.
+HarmonyResult harmony_encoding_render_for_training(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* conversation_json,
+    const bool* auto_drop_analysis,
+    uint32_t** tokens_out,
+    size_t* tokens_len,
+    uint8_t** mask_out
+);
+
 // Decode tokens to text
 char* harmony_encoding_decode(
     const HarmonyEncodingWrapper* wrapper,
@@ -51,6 +667,83 @@ char* harmony_encoding_decode(
     size_t tokens_len
 );
 
+// Check that decoding tokens and encoding the text again with all special
+// tokens allowed gives back exactly the same tokens, e.g. to guard recorded
+// transcripts against tokenizer drift. stable_out receives the answer and
+// mismatch_index_out, which may be null, the number of leading tokens that
+// matched (tokens_len when stable). Tokens the encoder would have split
+// differently, or that end inside a multi-byte character, are not stable.
+// Unknown token ids fail with HARMONY_ERROR_INVALID_ARGUMENT.
+HarmonyResult harmony_encoding_stable_roundtrip(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    bool* stable_out,
+    size_t* mismatch_index_out
+);
+
+// Decode tokens to text, replacing invalid UTF-8 (e.g. a multi-byte character
+// split across a partial token sequence) with U+FFFD instead of failing.
+// Unknown token ids still fail. Free the result with harmony_free_string.
+char* harmony_encoding_decode_lossy(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len
+);
+
+// Decode tokens to text like harmony_encoding_decode_lossy, but skip token ids
+// outside the vocabulary (e.g. from a faulty sampler) instead of failing, and
+// write how many were skipped to skipped_out, which may be null. Bytes left
+// incomplete by a skipped id become U+FFFD. harmony_encoding_decode remains
+// the strict variant. Free text_out with harmony_free_string.
+HarmonyResult harmony_encoding_decode_skip_invalid(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    char** text_out,
+    size_t* skipped_out
+);
+
+// Decode tokens into the caller's buffer of buf_cap bytes without allocating.
+// On success written_out holds the number of UTF-8 bytes written; no NUL
+// terminator is added. If the text does not fit, the buffer is left untouched,
+// written_out holds the required size and HARMONY_ERROR_BUFFER_TOO_SMALL is
+// returned. Tokens that do not decode to valid UTF-8 return
+// HARMONY_ERROR_INVALID_UTF8 with written_out set to 0.
+HarmonyResult harmony_encoding_decode_into(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    uint8_t* buf,
+    size_t buf_cap,
+    size_t* written_out
+);
+
+// Decode tokens to their raw bytes, which need not be valid UTF-8 (e.g. when
+// the tokens end inside a multi-byte character). Free the buffer with
+// harmony_free_bytes(bytes, bytes_len).
+HarmonyResult harmony_encoding_decode_bytes(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    uint8_t** bytes_out,
+    size_t* bytes_len
+);
+
+// Whether the bytes of prior_tokens followed by token end on a UTF-8 character
+// boundary, so decoded text can be flushed now rather than after the next
+// token. Returns false while the bytes end inside a multi-byte character that
+// later tokens may complete; bytes that can never form a character count as a
+// boundary. Only the last few bytes are examined, so passing just the tokens
+// since the last flush is enough. Returns false and sets
+// harmony_last_error_message for unknown tokens or invalid arguments.
+bool harmony_encoding_token_completes_utf8(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* prior_tokens,
+    size_t prior_len,
+    uint32_t token
+);
+
 // Get stop tokens
 HarmonyResult harmony_encoding_stop_tokens(
     const HarmonyEncodingWrapper* wrapper,
@@ -58,6 +751,576 @@ HarmonyResult harmony_encoding_stop_tokens(
     size_t* tokens_len
 );
 
+// The end-of-turn token to close a finished assistant message with: always
+// <|end|> (200007 in o200k_harmony), the token every rendered message other
+// than a tool call ends with. It is not the token the model samples to stop:
+// that is <|return|> for a final answer or <|call|> for a tool call (see
+// harmony_encoding_stop_tokens_for_assistant_actions). When a turn ended with
+// <|return|> is kept in the history, replace that token with this one; tool
+// calls keep their <|call|>.
+HarmonyResult harmony_encoding_eot_token(
+    const HarmonyEncodingWrapper* wrapper,
+    uint32_t* token_out
+);
+
+// Get stop tokens for assistant actions (<|return|> and <|call|>, without
+// <|end|>). Free the buffer with harmony_free_tokens.
+HarmonyResult harmony_encoding_stop_tokens_for_assistant_actions(
+    const HarmonyEncodingWrapper* wrapper,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Both stop token sets with their symbolic text, for logging and checking a
+// sampler's configuration, as JSON:
+// {"stop_tokens": [{"id": 200007, "text": "<|end|>"}, ...],
+//  "stop_tokens_for_assistant_actions": [{"id": 200002, "text": "<|return|>"}, ...]}
+// The sets match harmony_encoding_stop_tokens and
+// harmony_encoding_stop_tokens_for_assistant_actions, each sorted by id. Free
+// the JSON with harmony_free_string.
+HarmonyResult harmony_encoding_stop_tokens_json(
+    const HarmonyEncodingWrapper* wrapper,
+    char** json_out
+);
+
+// Tokens that begin an assistant message, e.g. to prefill a partial completion:
+// <|start|>assistant<|channel|>{channel}<|message|>, or
+// <|start|>assistant<|message|> when channel is null or empty. They match the
+// start of the message in a full render. Free with harmony_free_tokens.
+HarmonyResult harmony_encoding_assistant_header_tokens(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* channel,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Special-token vocabulary as JSON, ordered by id:
+// {"special_tokens": {"<|start|>": 200006, ...},
+//  "reserved_ranges": [[200013, 201088], ...]}
+// Reserved placeholder tokens are listed as inclusive [first, last] id ranges.
+// Free the JSON with harmony_free_string.
+HarmonyResult harmony_encoding_special_tokens_json(
+    const HarmonyEncodingWrapper* wrapper,
+    char** json_out
+);
+
+// Each token as a JSON array of {"id": ..., "text": ...} objects for debugging
+// how text was chunked. Special tokens show their symbolic form ("<|start|>"),
+// ordinary tokens their decoded bytes with invalid UTF-8 replaced by U+FFFD.
+// Unknown ids fail with HARMONY_ERROR_INVALID_ARGUMENT. Free the JSON with
+// harmony_free_string.
+HarmonyResult harmony_encoding_explain_tokens(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    char** json_out
+);
+
+// Channels messages of the given role (a HarmonyRole) may use, as a JSON array
+// such as ["analysis","commentary","final"] for the assistant. Only assistant
+// messages carry channels, so other roles get an empty array. The list is the
+// one the default system message advertises to the model. Free the JSON with
+// harmony_free_string.
+HarmonyResult harmony_encoding_valid_channels(
+    const HarmonyEncodingWrapper* wrapper,
+    int32_t role,
+    char** json_out
+);
+
+// Reasoning effort encoded by the first system message in tokens, which must
+// be complete messages starting with <|start|>. effort_out receives 0=low,
+// 1=medium, 2=high, or -1 when there is no system message or it sets no
+// effort. Tokens that fail to parse return HARMONY_ERROR_PARSE_FAILED.
+HarmonyResult harmony_encoding_parse_reasoning_effort(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    int32_t* effort_out
+);
+
+// Parse completion tokens into messages, returned as a JSON array (see
+// harmony_parser_messages_json). role is the author role assumed for the first
+// message as a HarmonyRole, or -1 when the tokens begin with <|start|>. Free the
+// JSON with harmony_free_string. error_out, which may be null, receives the
+// position of a parse failure.
+HarmonyResult harmony_encoding_parse_messages(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    int32_t role,
+    char** json_out,
+    HarmonyParseError* error_out
+);
+
+// Parse completion tokens like harmony_encoding_parse_messages, adding to each
+// message object where it sits in the decoded text of all the tokens (as from
+// harmony_encoding_decode, special tokens spelled out): text_start/text_end
+// span the whole message from the token after the previous message through its
+// terminator, and content_start/content_end just its content. Offsets are
+// UTF-8 byte indices, end exclusive. Free the JSON with harmony_free_string.
+HarmonyResult harmony_encoding_parse_with_spans(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    int32_t role,
+    char** json_out,
+    HarmonyParseError* error_out
+);
+
+// Parse rendered conversation tokens (starting at a <|start|> token) and read
+// the channel config back from the first system message's
+// "# Valid channels: ..." line, as JSON:
+// {"valid_channels": ["analysis", "commentary", "final"], "channel_required": true}
+// Writes null to json_out when there is no system message or it declares no
+// channels. Free the JSON with harmony_free_string.
+HarmonyResult harmony_encoding_parse_channel_config(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    char** json_out
+);
+
+// Parse completion tokens as harmony_encoding_parse_messages does and return
+// per-transcript statistics as JSON:
+// {"messages": 5, "tokens": 84, "groups": [{"role": "assistant",
+//   "channel": "analysis", "messages": 2, "tool_calls": 0, "tokens": 31}, ...]}
+// Messages are grouped by role and channel (null when a message has none), in
+// the order each group first appears. tool_calls counts assistant messages
+// addressed to a recipient. A message's tokens run from the token after the
+// previous message through its own closing token, so the group counts add up
+// to the total. Free the JSON with harmony_free_string.
+HarmonyResult harmony_encoding_conversation_stats(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    int32_t role,
+    char** json_out,
+    HarmonyParseError* error_out
+);
+
+// Parse assistant completion tokens and return only the messages addressed to
+// a recipient (tool calls) as a JSON array of
+// [{"recipient": "functions.get_weather", "channel": "commentary",
+//   "content": "{\"city\": \"Paris\"}"}]
+// channel is null when the message had none. Free the JSON with
+// harmony_free_string. error_out is as in harmony_encoding_parse_messages.
+HarmonyResult harmony_encoding_parse_tool_calls(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    char** json_out,
+    HarmonyParseError* error_out
+);
+
+// Parse assistant completion tokens, as harmony_encoding_parse_tool_calls
+// does, and return the text of every message on the given channel joined
+// without separators, e.g. the user-visible answer for "final". The text is
+// empty when no message uses the channel. Free it with harmony_free_string.
+// error_out is as in harmony_encoding_parse_messages.
+HarmonyResult harmony_encoding_extract_channel_text(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    const char* channel,
+    char** text_out,
+    HarmonyParseError* error_out
+);
+
+// Check that completion tokens form complete, well-formed harmony messages.
+// role is interpreted as in harmony_encoding_parse_messages. On failure the
+// result has HARMONY_ERROR_PARSE_FAILED and a message naming the index of the
+// offending token, or tokens_len when the last message is unterminated.
+HarmonyResult harmony_encoding_validate_tokens(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    int32_t role
+);
+
+// Remove analysis-channel messages (chain of thought) from a transcript that
+// begins with <|start|>, e.g. before persisting it. The tokens of all other
+// messages are copied unchanged rather than re-rendered, so their content is
+// preserved exactly. Free the output with harmony_free_tokens.
+HarmonyResult harmony_encoding_drop_analysis(
+    const HarmonyEncodingWrapper* wrapper,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    uint32_t** tokens_out,
+    size_t* out_len
+);
+
+// Streaming parser functions
+// role is a HarmonyRole, or -1 for no role (the parser then expects a
+// <|start|> token). Returns null for unknown roles.
+StreamableParserWrapper* harmony_parser_new(
+    const HarmonyEncodingWrapper* wrapper,
+    int32_t role
+);
+void harmony_parser_free(StreamableParserWrapper* parser);
+
+// Serialize the full state of a parser as JSON, for checkpointing an in-flight
+// stream and resuming it in another process with
+// harmony_parser_deserialize_state. The object holds the fields of the parser
+// state ("state" plus "header_tokens" or "header" and "content_tokens") along
+// with the tokens, messages and undecoded bytes seen so far; treat the rest as
+// opaque. Free json_out with harmony_free_string.
+HarmonyResult harmony_parser_serialize_state(
+    const StreamableParserWrapper* parser,
+    char** json_out
+);
+
+// Create a parser from harmony_parser_serialize_state output. Processing further
+// tokens with it gives the same results as the original parser would have.
+// The encoding must be the one the state was taken with. Returns null on
+// invalid JSON; see harmony_last_error_message. Free with harmony_parser_free.
+StreamableParserWrapper* harmony_parser_deserialize_state(
+    const HarmonyEncodingWrapper* wrapper,
+    const char* json
+);
+
+// Create a parser in the same state as one from harmony_parser_new that has
+// processed tokens one by one, e.g. to resume an interrupted stream. Returns
+// null if a token is rejected; harmony_last_error_message names its index.
+StreamableParserWrapper* harmony_parser_new_with_tokens(
+    const HarmonyEncodingWrapper* wrapper,
+    int32_t role,
+    const uint32_t* tokens,
+    size_t tokens_len
+);
+
+// Reset a parser to the state of a freshly created one for the given role
+// (-1 for none), keeping its buffers for reuse across generations
+HarmonyResult harmony_parser_reset(StreamableParserWrapper* parser, int32_t role);
+
+// Feed a single token into the parser
+HarmonyResult harmony_parser_process(
+    StreamableParserWrapper* parser,
+    uint32_t token
+);
+
+// Feed a single token into the parser like harmony_parser_process, but recover
+// from tokens that do not fit the message structure instead of failing: an
+// unexpected token outside a message starts a message with the expected role
+// (assistant if none) holding it as content, and a header that cannot be
+// parsed becomes the content of such a message. Each recovery is recorded as a
+// warning; see harmony_parser_warnings_json.
+HarmonyResult harmony_parser_process_lenient(
+    StreamableParserWrapper* parser,
+    uint32_t token
+);
+
+// Write the warnings recorded by harmony_parser_process_lenient since the parser
+// was created or reset as a JSON array of strings, oldest first. Free the string
+// with harmony_free_string.
+HarmonyResult harmony_parser_warnings_json(
+    const StreamableParserWrapper* parser,
+    char** json_out
+);
+
+// Feed a batch of tokens into the parser, as if each was passed to
+// harmony_parser_process in turn. After every token that produces visible text,
+// delta_callback receives the current channel (null if the message has none),
+// the new text and user_data; both strings are borrowed for the duration of the
+// callback only. delta_callback may be null to just update the parser state.
+// If a token is rejected the batch stops there with HARMONY_ERROR_PARSE_FAILED
+// naming its index; the tokens before it stay processed.
+typedef void (*HarmonyDeltaCallback)(const char* channel, const char* delta, void* user_data);
+HarmonyResult harmony_parser_process_batch(
+    StreamableParserWrapper* parser,
+    const uint32_t* tokens,
+    size_t tokens_len,
+    HarmonyDeltaCallback delta_callback,
+    void* user_data
+);
+
+// What a token did when processed with harmony_parser_process_event
+typedef enum {
+    HARMONY_PARSER_EVENT_NONE = 0,            // header token or partial character
+    HARMONY_PARSER_EVENT_CONTENT_DELTA = 1,   // delta holds new content text
+    HARMONY_PARSER_EVENT_CHANNEL_START = 2,   // header complete, no recipient
+    HARMONY_PARSER_EVENT_TOOL_CALL_START = 3, // header complete with a recipient
+    HARMONY_PARSER_EVENT_MESSAGE_END = 4,     // message closed by <|end|>
+    HARMONY_PARSER_EVENT_STOP = 5,            // message closed by <|return|> or <|call|>
+} HarmonyParserEventKind;
+
+typedef struct {
+    int32_t kind;     // a HarmonyParserEventKind
+    char* channel;    // channel of the message, or null
+    char* recipient;  // recipient of the message, or null
+    char* delta;      // content text for CONTENT_DELTA, otherwise null
+} HarmonyParserEvent;
+
+// Feed one token into the parser, like harmony_parser_process, and describe
+// its effect in event_out, so a stream can be driven as a state machine
+// without polling the other accessors. Exactly one event is reported per
+// token: CHANNEL_START or TOOL_CALL_START on the <|message|> that completes a
+// header, CONTENT_DELTA for decoded content, and MESSAGE_END or STOP on the
+// terminator, which also carry the finished message's channel and recipient.
+// STOP is reported for the tokens of
+// harmony_encoding_stop_tokens_for_assistant_actions, after which the
+// assistant's sampling ends. event_out is reset to
+// HARMONY_PARSER_EVENT_NONE with null strings on entry. Its strings are owned
+// by the caller; release them with harmony_parser_event_free or each with
+// harmony_free_string.
+HarmonyResult harmony_parser_process_event(
+    StreamableParserWrapper* parser,
+    uint32_t token,
+    HarmonyParserEvent* event_out
+);
+void harmony_parser_event_free(HarmonyParserEvent* event);
+
+// Signal end of stream, finalizing any in-progress message
+HarmonyResult harmony_parser_process_eos(StreamableParserWrapper* parser);
+
+// Text decoded by the most recent process call. Writes an empty string when the
+// token produced no visible text, and null while the parser is waiting for the
+// rest of a multi-byte character. Free the string with harmony_free_string.
+HarmonyResult harmony_parser_last_content_delta(
+    const StreamableParserWrapper* parser,
+    char** text_out
+);
+
+// Text of the message being parsed, accumulated since its <|message|> token.
+// Writes an empty string when no message content is in progress. Free the text
+// with harmony_free_string.
+HarmonyResult harmony_parser_current_content(
+    const StreamableParserWrapper* parser,
+    char** text_out
+);
+
+// Copy of every token the parser has consumed since it was created or reset,
+// in order. The buffer is owned by the caller; free it with harmony_free_tokens.
+HarmonyResult harmony_parser_tokens(
+    const StreamableParserWrapper* parser,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Channel of the message being parsed. Writes null until the message header has
+// been read or when the message has no channel. Free with harmony_free_string.
+HarmonyResult harmony_parser_current_channel(
+    const StreamableParserWrapper* parser,
+    char** channel_out
+);
+
+// Recipient of the message being parsed, e.g. "functions.get_weather" for a
+// tool call. Writes null until the message header has been read or when the
+// message has no recipient. Free with harmony_free_string.
+HarmonyResult harmony_parser_current_recipient(
+    const StreamableParserWrapper* parser,
+    char** recipient_out
+);
+
+// Whether the tool call being parsed can be dispatched before its terminator:
+// true when the current message has a recipient and its content so far,
+// decoded as UTF-8, parses as exactly one JSON object, optionally surrounded by
+// whitespace. False while the content is empty, an unfinished or non-object
+// JSON value, or ends in the middle of a character, once the message is
+// complete, and for a null parser.
+bool harmony_parser_arguments_complete(const StreamableParserWrapper* parser);
+
+// Role of the message being parsed as a HarmonyRole. Writes -1 when the role is
+// not known yet.
+HarmonyResult harmony_parser_current_role(
+    const StreamableParserWrapper* parser,
+    int32_t* role_out
+);
+
+// Whether the parser has consumed <|return|> or <|call|> (the tokens of
+// harmony_encoding_stop_tokens_for_assistant_actions) since it was created or
+// reset, meaning the assistant's sampling should have ended and nothing
+// further should stream. An <|end|> closing an analysis or commentary message
+// does not count. Stays true until harmony_parser_reset. False for a null
+// parser.
+bool harmony_parser_stopped(const StreamableParserWrapper* parser);
+
+// Whether the parser is between messages after completing one: true right
+// after a message terminator (<|end|>, <|return|> or <|call|>) or
+// harmony_parser_process_eos finishes a message, and false again as soon as the
+// <|start|> token of the next message is processed, even if that message only
+// switches channel. False before the first message is complete, after a reset
+// and for a null parser.
+bool harmony_parser_current_message_complete(const StreamableParserWrapper* parser);
+
+// Fully parsed messages as a JSON array of Message objects (role, name,
+// recipient, channel, content_type, content). Free with harmony_free_string.
+HarmonyResult harmony_parser_messages_json(
+    const StreamableParserWrapper* parser,
+    char** json_out
+);
+
+// Incremental access to the fully parsed messages, so a stream only needs to
+// fetch the ones that are new since it last looked. harmony_parser_message_count
+// returns how many messages are complete, excluding the one in progress (0 for
+// a null parser), and harmony_parser_message_at writes the message at index
+// (0-based) as a JSON Message object like those in
+// harmony_parser_messages_json; an index past the end returns
+// HARMONY_ERROR_INVALID_ARGUMENT. Free the JSON with harmony_free_string.
+size_t harmony_parser_message_count(const StreamableParserWrapper* parser);
+HarmonyResult harmony_parser_message_at(
+    const StreamableParserWrapper* parser,
+    size_t index,
+    char** json_out
+);
+
+// Incremental decoder functions
+// A decoder turns tokens into text one at a time, buffering the bytes of a
+// character that is split across tokens until the character is complete.
+HarmonyDecoderWrapper* harmony_decoder_new(const HarmonyEncodingWrapper* wrapper);
+void harmony_decoder_free(HarmonyDecoderWrapper* decoder);
+
+// Decode one token and write the text it completes to text_out, which is an
+// empty string while a character is still incomplete. Bytes that can never form
+// valid UTF-8 are emitted as U+FFFD. Free the text with harmony_free_string.
+HarmonyResult harmony_decoder_push(
+    HarmonyDecoderWrapper* decoder,
+    uint32_t token,
+    char** text_out
+);
+
+// System content builder functions
+// A builder starts from the default system message; each setter replaces one
+// field. Passing null or an empty string removes a text field, and
+// reasoning_effort takes 0=low, 1=medium, 2=high, or -1 to remove it.
+SystemContentBuilder* harmony_system_content_new(void);
+void harmony_system_content_free(SystemContentBuilder* builder);
+HarmonyResult harmony_system_content_set_model_identity(
+    SystemContentBuilder* builder,
+    const char* model_identity
+);
+HarmonyResult harmony_system_content_set_reasoning_effort(
+    SystemContentBuilder* builder,
+    int32_t reasoning_effort
+);
+HarmonyResult harmony_system_content_set_knowledge_cutoff(
+    SystemContentBuilder* builder,
+    const char* knowledge_cutoff
+);
+
+// Developer content builder functions
+// A builder starts with no instructions and no tools. Passing null or an empty
+// string to harmony_developer_content_set_instructions removes them.
+DeveloperContentBuilder* harmony_developer_content_new(void);
+void harmony_developer_content_free(DeveloperContentBuilder* builder);
+HarmonyResult harmony_developer_content_set_instructions(
+    DeveloperContentBuilder* builder,
+    const char* instructions
+);
+
+// Add a tool to the "functions" namespace. name is required; description may
+// be null. parameters_json is the tool's JSON schema and must be an object, or
+// null for a tool without parameters.
+HarmonyResult harmony_developer_content_add_function_tool(
+    DeveloperContentBuilder* builder,
+    const char* name,
+    const char* description,
+    const char* parameters_json
+);
+
+// Add a tool to the namespace with the given non-empty name, creating it on
+// first use; the tool is addressed as "<namespace>.<name>". The other
+// arguments are as in harmony_developer_content_add_function_tool.
+HarmonyResult harmony_developer_content_add_namespace_tool(
+    DeveloperContentBuilder* builder,
+    const char* namespace_name,
+    const char* name,
+    const char* description,
+    const char* parameters_json
+);
+
+// Name of the builder's tool namespace that a parsed message recipient such as
+// "crm.lookup" addresses: a namespace named exactly like the recipient, or the
+// one named by the part before the "." that declares the rest as a tool.
+// namespace_out receives null when no namespace matches; otherwise free it
+// with harmony_free_string.
+HarmonyResult harmony_developer_content_recipient_namespace(
+    const DeveloperContentBuilder* builder,
+    const char* recipient,
+    char** namespace_out
+);
+
+// Check the arguments of a tool call (a JSON value, usually the content of a
+// message addressed to the tool) against the tool's JSON schema parameters, as
+// passed to harmony_developer_content_add_function_tool. Only type, enum,
+// required, properties, additionalProperties: false and items are checked;
+// other schema keywords are ignored. Returns HARMONY_ERROR_INVALID_ARGUMENT with
+// a message naming the offending field (e.g. "arguments.location: expected
+// string, got number") when the arguments do not match or either JSON is
+// malformed.
+HarmonyResult harmony_validate_tool_arguments(
+    const char* tool_parameters_json,
+    const char* arguments_json
+);
+
+// Render the system message described by a builder. The builder is not
+// modified and still has to be freed with harmony_system_content_free.
+HarmonyResult harmony_encoding_render_system_content(
+    const HarmonyEncodingWrapper* wrapper,
+    const SystemContentBuilder* builder,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render only the developer message described by a builder, e.g. to inspect
+// the tool namespace section. The tokens are exactly those the message has in
+// a full render such as harmony_encoding_render_prompt_with_builders.
+HarmonyResult harmony_encoding_render_developer(
+    const HarmonyEncodingWrapper* wrapper,
+    const DeveloperContentBuilder* builder,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Render a system message, a developer message and a user message. Either
+// builder may be null to omit its message; neither builder is modified.
+HarmonyResult harmony_encoding_render_prompt_with_builders(
+    const HarmonyEncodingWrapper* wrapper,
+    const SystemContentBuilder* system,
+    const DeveloperContentBuilder* developer,
+    const char* user_msg,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
+// Conversation builder functions
+// A conversation builder collects text messages without going through JSON.
+ConversationBuilder* harmony_conversation_new(void);
+void harmony_conversation_free(ConversationBuilder* conversation);
+
+// Append a message. role is a HarmonyRole value; channel and recipient may be
+// null or empty to leave them unset. The strings are copied.
+HarmonyResult harmony_conversation_add_message(
+    ConversationBuilder* conversation,
+    int32_t role,
+    const char* channel,
+    const char* recipient,
+    const char* content
+);
+
+// Append a message with an author name, which is rendered in the header in
+// place of the role for tool results, e.g. <|start|>functions.get_weather
+// to=assistant..., and after the role otherwise. name may be null or empty
+// except for HARMONY_ROLE_TOOL messages, which need the name of the responding
+// tool. harmony_conversation_add_message is this without a name, so it rejects
+// tool messages.
+HarmonyResult harmony_conversation_add_message_with_author(
+    ConversationBuilder* conversation,
+    int32_t role,
+    const char* name,
+    const char* channel,
+    const char* recipient,
+    const char* content
+);
+
+// Render all messages added so far. The builder is not modified, so it can be
+// extended and rendered again on the next turn.
+HarmonyResult harmony_conversation_render(
+    const ConversationBuilder* conversation,
+    const HarmonyEncodingWrapper* wrapper,
+    uint32_t** tokens_out,
+    size_t* tokens_len
+);
+
 #ifdef __cplusplus
 }
 #endif
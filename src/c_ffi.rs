@@ -1,24 +1,178 @@
+// The exported functions take raw pointers from C callers and check them for
+// null before use; the remaining safety contract is documented in harmony_ffi.h.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
+use crate::chat::{
+    validate_tool_arguments, Author, ChannelConfig, Content, Conversation, DeveloperContent,
+    Message, ReasoningEffort, Role, SystemContent, ToolDescription, ToolNamespaceConfig,
+};
+use crate::encoding::{FormattingToken, RenderConversationConfig, StreamState};
+use crate::tiktoken::CoreBPE;
+use crate::{
+    load_harmony_encoding, load_harmony_encoding_from_data, HarmonyEncoding, HarmonyEncodingName,
+    StreamableParser,
+};
+use std::alloc::Layout;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
-use crate::{HarmonyEncoding, load_harmony_encoding, HarmonyEncodingName, StreamableParser};
-use crate::chat::{Conversation, Message, Role, SystemContent};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 
 // Opaque pointers for Rust types
 pub struct HarmonyEncodingWrapper {
+    tag: u64,
     encoding: HarmonyEncoding,
+    cached_prefix: RwLock<Option<CachedPrefix>>,
+}
+
+// System and developer messages set with harmony_encoding_set_cached_prefix,
+// together with their rendered tokens
+struct CachedPrefix {
+    messages: Vec<Message>,
+    tokens: Vec<u32>,
 }
 
 pub struct StreamableParserWrapper {
-    #[allow(dead_code)] // Reserved for future streaming functionality
+    tag: u64,
     parser: StreamableParser,
 }
 
+// Encoding and parser handles carry a tag that is cleared when they are freed,
+// so a handle that is used after being freed (or is not a handle at all) is
+// usually rejected with HarmonyErrorCode::InvalidHandle rather than corrupting
+// memory. This is a debugging aid, not a guarantee, since freed memory can be
+// reused.
+const ENCODING_TAG: u64 = 0x4841_524d_4f4e_5945; // "HARMONYE"
+const PARSER_TAG: u64 = 0x4841_524d_4f4e_5950; // "HARMONYP"
+
+impl HarmonyEncodingWrapper {
+    fn new(encoding: HarmonyEncoding) -> Self {
+        HarmonyEncodingWrapper {
+            tag: ENCODING_TAG,
+            encoding,
+            cached_prefix: RwLock::new(None),
+        }
+    }
+}
+
+impl StreamableParserWrapper {
+    fn new(parser: StreamableParser) -> Self {
+        StreamableParserWrapper {
+            tag: PARSER_TAG,
+            parser,
+        }
+    }
+}
+
+trait Handle {
+    const TAG: u64;
+    const NAME: &'static str;
+
+    // Read the tag without creating a reference to possibly freed memory.
+    unsafe fn tag(handle: *const Self) -> u64;
+    unsafe fn clear_tag(handle: *mut Self);
+}
+
+impl Handle for HarmonyEncodingWrapper {
+    const TAG: u64 = ENCODING_TAG;
+    const NAME: &'static str = "encoding wrapper";
+
+    unsafe fn tag(handle: *const Self) -> u64 {
+        ptr::addr_of!((*handle).tag).read_volatile()
+    }
+
+    unsafe fn clear_tag(handle: *mut Self) {
+        ptr::addr_of_mut!((*handle).tag).write_volatile(0)
+    }
+}
+
+impl Handle for StreamableParserWrapper {
+    const TAG: u64 = PARSER_TAG;
+    const NAME: &'static str = "parser";
+
+    unsafe fn tag(handle: *const Self) -> u64 {
+        ptr::addr_of!((*handle).tag).read_volatile()
+    }
+
+    unsafe fn clear_tag(handle: *mut Self) {
+        ptr::addr_of_mut!((*handle).tag).write_volatile(0)
+    }
+}
+
+// Check a handle argument before it is dereferenced.
+fn check_handle<H: Handle, R: FfiFailure>(handle: *const H) -> Result<(), R> {
+    if handle.is_null() {
+        return Err(R::failure(
+            HarmonyErrorCode::NullPointer,
+            format!("Null {}", H::NAME),
+        ));
+    }
+    if unsafe { H::tag(handle) } != H::TAG {
+        return Err(R::failure(
+            HarmonyErrorCode::InvalidHandle,
+            format!("Invalid {} handle (freed or never created)", H::NAME),
+        ));
+    }
+    Ok(())
+}
+
+// Free a boxed handle, clearing its tag first so a second free is refused.
+// Null is ignored as usual.
+fn free_handle<H: Handle>(handle: *mut H) {
+    if handle.is_null() || check_handle::<H, ()>(handle).is_err() {
+        return;
+    }
+    unsafe {
+        H::clear_tag(handle);
+        drop(Box::from_raw(handle));
+    }
+}
+
+pub struct HarmonyDecoderWrapper {
+    tokenizer: Arc<CoreBPE>,
+    pending: Vec<u8>,
+}
+
+pub struct SystemContentBuilder {
+    content: SystemContent,
+}
+
+pub struct DeveloperContentBuilder {
+    content: DeveloperContent,
+}
+
+pub struct ConversationBuilder {
+    messages: Vec<Message>,
+}
+
 // Error handling
+// Stable error codes reported in HarmonyResult::error_code
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HarmonyErrorCode {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidUtf8 = 2,
+    RenderFailed = 3,
+    ParseFailed = 4,
+    TokenizeFailed = 5,
+    InvalidArgument = 6,
+    SerializeFailed = 7,
+    BufferTooSmall = 8,
+    TooLong = 9,
+    Panic = 10,
+    InvalidHandle = 11,
+}
+
 #[repr(C)]
 pub struct HarmonyResult {
     success: bool,
     error_message: *mut c_char,
+    error_code: i32,
 }
 
 impl HarmonyResult {
@@ -26,237 +180,9430 @@ impl HarmonyResult {
         HarmonyResult {
             success: true,
             error_message: ptr::null_mut(),
+            error_code: HarmonyErrorCode::Ok as i32,
         }
     }
-    
-    fn err(msg: String) -> Self {
-        let c_string = CString::new(msg).unwrap_or_else(|_| CString::new("Error").unwrap());
+
+    fn err(code: HarmonyErrorCode, msg: String) -> Self {
+        let c_string = set_last_error(msg);
         HarmonyResult {
             success: false,
-            error_message: c_string.into_raw(),
+            error_message: into_raw_c_string(c_string),
+            error_code: code as i32,
         }
     }
 }
 
-// Free functions
-#[no_mangle]
-pub extern "C" fn harmony_free_string(s: *mut c_char) {
-    if !s.is_null() {
-        unsafe { 
-            let _ = CString::from_raw(s);
+// Where and why a completion failed to parse, filled in through an optional
+// out-parameter by the parse functions
+#[repr(C)]
+pub struct HarmonyParseError {
+    code: i32,
+    token_index: usize,
+    message: *mut c_char,
+}
+
+impl HarmonyParseError {
+    fn none() -> Self {
+        HarmonyParseError {
+            code: HarmonyErrorCode::Ok as i32,
+            token_index: 0,
+            message: ptr::null_mut(),
         }
     }
 }
 
-#[no_mangle]
-pub extern "C" fn harmony_free_tokens(tokens: *mut u32, len: usize) {
-    if !tokens.is_null() {
-        unsafe {
-            let _ = Vec::from_raw_parts(tokens, len, len);
-        }
+// Reset an optional parse error out-parameter before parsing starts.
+fn clear_parse_error(error_out: *mut HarmonyParseError) {
+    if !error_out.is_null() {
+        unsafe { error_out.write(HarmonyParseError::none()) };
     }
 }
 
-// Harmony Encoding functions
-#[no_mangle]
-pub extern "C" fn harmony_encoding_new() -> *mut HarmonyEncodingWrapper {
-    match load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss) {
-        Ok(encoding) => {
-            let wrapper = Box::new(HarmonyEncodingWrapper { encoding });
-            Box::into_raw(wrapper)
+// Parse completion tokens, keeping track of the token a failure happened at.
+// Running out of tokens inside a message fails at index `tokens.len()`. An
+// empty completion (e.g. the first chunk of a stream) has no messages yet
+// rather than an unterminated one.
+fn parse_completion(
+    encoding: &HarmonyEncoding,
+    tokens: &[u32],
+    role: Option<Role>,
+) -> Result<Vec<Message>, (usize, anyhow::Error)> {
+    parse_completion_with_token_counts(encoding, tokens, role)
+        .map(|parsed| parsed.into_iter().map(|(message, _)| message).collect())
+}
+
+// Like parse_completion, also returning how many of the tokens each message
+// spans, from the token after the previous message up to its closing token.
+fn parse_completion_with_token_counts(
+    encoding: &HarmonyEncoding,
+    tokens: &[u32],
+    role: Option<Role>,
+) -> Result<Vec<(Message, usize)>, (usize, anyhow::Error)> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut parser = StreamableParser::new(encoding.clone(), role).map_err(|e| (0, e))?;
+    let mut counts = Vec::new();
+    let mut message_start = 0;
+    for (index, &token) in tokens.iter().enumerate() {
+        parser.process(token).map_err(|e| (index, e))?;
+        if parser.messages().len() > counts.len() {
+            counts.push(index + 1 - message_start);
+            message_start = index + 1;
         }
-        Err(_) => ptr::null_mut()
     }
+    parser.process_eos().map_err(|e| (tokens.len(), e))?;
+    if parser.messages().len() > counts.len() {
+        counts.push(tokens.len() - message_start);
+    }
+    Ok(parser.into_messages().into_iter().zip(counts).collect())
 }
 
-#[no_mangle]
-pub extern "C" fn harmony_encoding_free(wrapper: *mut HarmonyEncodingWrapper) {
-    if !wrapper.is_null() {
+// Report a parse failure both as a result and, if requested, as a HarmonyParseError.
+fn parse_failed(
+    token_index: usize,
+    error: anyhow::Error,
+    error_out: *mut HarmonyParseError,
+) -> HarmonyResult {
+    let message = error.to_string();
+    if !error_out.is_null() {
+        let message = error_c_string(&message);
         unsafe {
-            let _ = Box::from_raw(wrapper);
+            error_out.write(HarmonyParseError {
+                code: HarmonyErrorCode::ParseFailed as i32,
+                token_index,
+                message: into_raw_c_string(message),
+            })
+        };
+    }
+    HarmonyResult::err(
+        HarmonyErrorCode::ParseFailed,
+        format!(
+            "Failed to parse messages at token {}: {}",
+            token_index, message
+        ),
+    )
+}
+
+// Tokens of an encoded text together with the text's length in bytes,
+// returned by value so a single call carries everything back
+#[repr(C)]
+pub struct HarmonyEncodedText {
+    result: HarmonyResult,
+    tokens: *mut u32,
+    tokens_len: usize,
+    text_byte_len: usize,
+}
+
+impl HarmonyEncodedText {
+    fn err(result: HarmonyResult) -> Self {
+        HarmonyEncodedText {
+            result,
+            tokens: ptr::null_mut(),
+            tokens_len: 0,
+            text_byte_len: 0,
         }
     }
 }
 
-// Plain text encoding - encode text without Harmony formatting
-#[no_mangle]
-pub extern "C" fn harmony_encoding_encode_plain(
-    wrapper: *const HarmonyEncodingWrapper,
-    text: *const c_char,
-    tokens_out: *mut *mut u32,
-    tokens_len: *mut usize,
-) -> HarmonyResult {
-    if wrapper.is_null() {
-        return HarmonyResult::err("Null encoding wrapper".to_string());
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Convert an error message to a C string. Messages can quote arbitrary text,
+/// so NUL bytes are escaped as `\x00` instead of truncating the message.
+fn error_c_string(msg: &str) -> CString {
+    CString::new(msg.replace('\0', "\\x00")).expect("NUL bytes were escaped")
+}
+
+/// Record `msg` as this thread's last error and return a copy for the caller.
+fn set_last_error(msg: String) -> CString {
+    let c_string = error_c_string(&msg);
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(c_string.clone()));
+    c_string
+}
+
+/// Record `msg` as the last error of a function that signals failure with null.
+fn null_with_error<T>(msg: impl Into<String>) -> *mut T {
+    set_last_error(msg.into());
+    ptr::null_mut()
+}
+
+// The value an entry point returns when it fails: an error result, or for
+// functions returning a plain value null / false / 0 with the message recorded
+// as the last error.
+trait FfiFailure {
+    fn failure(code: HarmonyErrorCode, message: String) -> Self;
+}
+
+impl FfiFailure for HarmonyResult {
+    fn failure(code: HarmonyErrorCode, message: String) -> Self {
+        HarmonyResult::err(code, message)
+    }
+}
+
+impl FfiFailure for HarmonyEncodedText {
+    fn failure(code: HarmonyErrorCode, message: String) -> Self {
+        HarmonyEncodedText::err(HarmonyResult::err(code, message))
     }
-    
-    let encoding = unsafe { &(*wrapper).encoding };
-    
-    // Get text string
-    if text.is_null() {
-        return HarmonyResult::err("Null text".to_string());
+}
+
+impl<T> FfiFailure for *mut T {
+    fn failure(_code: HarmonyErrorCode, message: String) -> Self {
+        null_with_error(message)
     }
-    
-    let text_str = unsafe { CStr::from_ptr(text) }
-        .to_str()
-        .unwrap_or("");
-    
-    // Use the official harmony tokenizer for plain encoding
-    let tokens = encoding.tokenizer.encode_ordinary(text_str);
-    
-    // Convert to raw pointer
-    let mut tokens_vec = tokens;
-    tokens_vec.shrink_to_fit();
-    let len = tokens_vec.len();
-    let ptr = tokens_vec.as_mut_ptr();
-    std::mem::forget(tokens_vec);
-    
-    unsafe {
-        *tokens_len = len;
-        *tokens_out = ptr;
+}
+
+impl<T> FfiFailure for *const T {
+    fn failure(_code: HarmonyErrorCode, message: String) -> Self {
+        set_last_error(message);
+        ptr::null()
     }
-    
-    HarmonyResult::ok()
 }
 
-// Harmony prompt rendering
+impl FfiFailure for bool {
+    fn failure(_code: HarmonyErrorCode, message: String) -> Self {
+        set_last_error(message);
+        false
+    }
+}
+
+impl FfiFailure for i32 {
+    fn failure(_code: HarmonyErrorCode, message: String) -> Self {
+        set_last_error(message);
+        0
+    }
+}
+
+impl FfiFailure for u32 {
+    fn failure(_code: HarmonyErrorCode, message: String) -> Self {
+        set_last_error(message);
+        0
+    }
+}
+
+impl FfiFailure for usize {
+    fn failure(_code: HarmonyErrorCode, message: String) -> Self {
+        set_last_error(message);
+        0
+    }
+}
+
+impl FfiFailure for () {
+    fn failure(_code: HarmonyErrorCode, message: String) {
+        set_last_error(message);
+    }
+}
+
+// Unwinding out of an `extern "C"` function is undefined behavior, so every
+// entry point runs its body through `ffi_boundary`, which turns a panic into
+// the function's ordinary failure value and records the panic message as the
+// last error.
+fn ffi_boundary<R: FfiFailure>(body: impl FnOnce() -> R) -> R {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let detail = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic payload".to_string()
+        };
+        R::failure(
+            HarmonyErrorCode::Panic,
+            format!("Internal panic: {}", detail),
+        )
+    })
+}
+
+// Message of the most recent error on the calling thread
 #[no_mangle]
-pub extern "C" fn harmony_encoding_render_prompt(
-    wrapper: *const HarmonyEncodingWrapper,
-    system_msg: *const c_char,
-    user_msg: *const c_char,
-    assistant_prefix: *const c_char,
-    tokens_out: *mut *mut u32,
-    tokens_len: *mut usize,
-) -> HarmonyResult {
-    if wrapper.is_null() {
-        return HarmonyResult::err("Null encoding wrapper".to_string());
-    }
-    
-    let encoding = unsafe { &(*wrapper).encoding };
-    
-    let mut messages = Vec::new();
-    
-    // Add system message if provided
-    if !system_msg.is_null() {
-        let system_text = unsafe { CStr::from_ptr(system_msg) }
-            .to_str()
-            .unwrap_or("");
-        
-        if !system_text.is_empty() {
-            // Create a system message with the text as model_identity
-            let system_content = SystemContent::new().with_model_identity(system_text);
-            let message = Message::from_role_and_content(Role::System, system_content);
-            messages.push(message);
+pub extern "C" fn harmony_last_error_message() -> *const c_char {
+    ffi_boundary(|| {
+        LAST_ERROR.with(|last| {
+            last.borrow()
+                .as_ref()
+                .map_or(ptr::null(), |message| message.as_ptr())
+        })
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_clear_last_error() {
+    ffi_boundary(|| {
+        LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    })
+}
+
+// Roles shared with C callers. Functions still take and return the role as an
+// `i32` (-1 meaning "no role") so that unknown values can be rejected instead
+// of being undefined behavior.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HarmonyRole {
+    System = 0,
+    Developer = 1,
+    User = 2,
+    Assistant = 3,
+    Tool = 4,
+}
+
+impl TryFrom<i32> for HarmonyRole {
+    type Error = String;
+
+    fn try_from(role: i32) -> Result<Self, Self::Error> {
+        match role {
+            0 => Ok(HarmonyRole::System),
+            1 => Ok(HarmonyRole::Developer),
+            2 => Ok(HarmonyRole::User),
+            3 => Ok(HarmonyRole::Assistant),
+            4 => Ok(HarmonyRole::Tool),
+            _ => Err(format!("Invalid role: {}", role)),
         }
     }
-    
-    // Add user message
-    if user_msg.is_null() {
-        return HarmonyResult::err("Null user message".to_string());
+}
+
+impl From<HarmonyRole> for Role {
+    fn from(role: HarmonyRole) -> Self {
+        match role {
+            HarmonyRole::System => Role::System,
+            HarmonyRole::Developer => Role::Developer,
+            HarmonyRole::User => Role::User,
+            HarmonyRole::Assistant => Role::Assistant,
+            HarmonyRole::Tool => Role::Tool,
+        }
     }
-    
-    let user_text = unsafe { CStr::from_ptr(user_msg) }
-        .to_str()
-        .unwrap_or("");
-    
-    let user_message = Message::from_role_and_content(Role::User, user_text.to_string());
-    messages.push(user_message);
-    
-    // Add assistant prefix if provided
-    if !assistant_prefix.is_null() {
-        let assistant_text = unsafe { CStr::from_ptr(assistant_prefix) }
-            .to_str()
-            .unwrap_or("");
-        
-        if !assistant_text.is_empty() {
-            let assistant_message = Message::from_role_and_content(Role::Assistant, assistant_text.to_string());
-            messages.push(assistant_message);
+}
+
+impl From<&Role> for HarmonyRole {
+    fn from(role: &Role) -> Self {
+        match role {
+            Role::System => HarmonyRole::System,
+            Role::Developer => HarmonyRole::Developer,
+            Role::User => HarmonyRole::User,
+            Role::Assistant => HarmonyRole::Assistant,
+            Role::Tool => HarmonyRole::Tool,
+        }
+    }
+}
+
+// Structural classification of a token, returned as an `i32` holding one of
+// these values by harmony_encoding_token_role_marker.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HarmonyTokenMarker {
+    None = 0,
+    Start = 1,
+    Message = 2,
+    End = 3,
+    Return = 4,
+    Call = 5,
+    Channel = 6,
+    Constrain = 7,
+    Refusal = 8,
+    BeginUntrusted = 9,
+    EndUntrusted = 10,
+}
+
+// Who speaks next after a conversation, returned as an `i32` holding one of
+// these values by harmony_encoding_conversation_needs_new_turn.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HarmonyNextTurn {
+    AssistantNew = 0,
+    AssistantContinue = 1,
+    Tool = 2,
+    User = 3,
+}
+
+impl HarmonyNextTurn {
+    fn after(messages: &[Message]) -> Self {
+        let Some(last) = messages.last() else {
+            return HarmonyNextTurn::AssistantNew;
+        };
+        match last.author.role {
+            Role::Assistant if last.recipient.as_deref().is_some_and(|r| r != "all") => {
+                HarmonyNextTurn::Tool
+            }
+            Role::Assistant if last.channel.as_deref() == Some("final") => HarmonyNextTurn::User,
+            Role::Assistant | Role::Tool => HarmonyNextTurn::AssistantContinue,
+            Role::User | Role::System | Role::Developer => HarmonyNextTurn::AssistantNew,
+        }
+    }
+}
+
+impl From<Option<FormattingToken>> for HarmonyTokenMarker {
+    fn from(token: Option<FormattingToken>) -> Self {
+        match token {
+            Some(FormattingToken::Start) => HarmonyTokenMarker::Start,
+            Some(FormattingToken::Message) => HarmonyTokenMarker::Message,
+            Some(FormattingToken::EndMessage) => HarmonyTokenMarker::End,
+            Some(FormattingToken::EndMessageDoneSampling) => HarmonyTokenMarker::Return,
+            Some(FormattingToken::EndMessageAssistantToTool) => HarmonyTokenMarker::Call,
+            Some(FormattingToken::Channel) => HarmonyTokenMarker::Channel,
+            Some(FormattingToken::ConstrainedFormat) => HarmonyTokenMarker::Constrain,
+            Some(FormattingToken::Refusal) => HarmonyTokenMarker::Refusal,
+            Some(FormattingToken::BeginUntrusted) => HarmonyTokenMarker::BeginUntrusted,
+            Some(FormattingToken::EndUntrusted) => HarmonyTokenMarker::EndUntrusted,
+            Some(FormattingToken::MetaSep | FormattingToken::MetaEnd) | None => {
+                HarmonyTokenMarker::None
+            }
+        }
+    }
+}
+
+// Only the handful of mapped formatting tokens are compared, so this takes
+// constant time regardless of vocabulary size.
+fn formatting_token_for_rank(encoding: &HarmonyEncoding, token: u32) -> Option<FormattingToken> {
+    if !encoding.tokenizer.is_special_token(token) {
+        return None;
+    }
+    let bytes = encoding.tokenizer.token_bytes(token)?;
+    encoding
+        .format_token_mapping
+        .iter()
+        .find(|(_, mapped)| mapped.as_bytes() == bytes)
+        .map(|(t, _)| *t)
+}
+
+fn role_from_c(role: i32) -> Result<Option<Role>, String> {
+    if role == -1 {
+        return Ok(None);
+    }
+    HarmonyRole::try_from(role).map(|role| Some(role.into()))
+}
+
+// Reasoning effort crosses the FFI boundary as 0=low, 1=medium, 2=high; -1 means unset.
+fn reasoning_effort_from_c(effort: i32) -> Result<Option<ReasoningEffort>, String> {
+    match effort {
+        -1 => Ok(None),
+        0 => Ok(Some(ReasoningEffort::Low)),
+        1 => Ok(Some(ReasoningEffort::Medium)),
+        2 => Ok(Some(ReasoningEffort::High)),
+        _ => Err(format!("Invalid reasoning effort: {}", effort)),
+    }
+}
+
+fn reasoning_effort_to_c(effort: Option<ReasoningEffort>) -> i32 {
+    match effort {
+        None => -1,
+        Some(ReasoningEffort::Low) => 0,
+        Some(ReasoningEffort::Medium) => 1,
+        Some(ReasoningEffort::High) => 2,
+    }
+}
+
+// Reasoning effort carried by a system message. Parsed messages hold the
+// rendered text, so it is read back from the "Reasoning: <effort>" line.
+fn system_reasoning_effort(message: &Message) -> Option<ReasoningEffort> {
+    message.content.iter().find_map(|content| match content {
+        Content::SystemContent(system) => system.reasoning_effort,
+        Content::Text(text) => {
+            text.text
+                .lines()
+                .find_map(|line| match line.strip_prefix("Reasoning:")?.trim() {
+                    "low" => Some(ReasoningEffort::Low),
+                    "medium" => Some(ReasoningEffort::Medium),
+                    "high" => Some(ReasoningEffort::High),
+                    _ => None,
+                })
         }
+        Content::DeveloperContent(_) => None,
+    })
+}
+
+fn role_to_c(role: Option<&Role>) -> i32 {
+    role.map_or(-1, |role| HarmonyRole::from(role) as i32)
+}
+
+// Read an optional C string argument; null and empty strings both mean unset,
+// while invalid UTF-8 is an error as in utf8_c_str.
+fn optional_c_str<'a>(s: *const c_char, what: &str) -> Result<Option<&'a str>, HarmonyResult> {
+    if s.is_null() {
+        return Ok(None);
     }
-    
-    // Create conversation and render it
-    let conversation = Conversation::from_messages(messages);
-    match encoding.render_conversation(&conversation, None) {
-        Ok(tokens) => {
-            // Convert to raw pointer
-            let mut tokens_vec = tokens;
-            tokens_vec.shrink_to_fit();
-            let len = tokens_vec.len();
-            let ptr = tokens_vec.as_mut_ptr();
-            std::mem::forget(tokens_vec);
-            
+    let text = utf8_c_str(s, what)?;
+    Ok((!text.is_empty()).then_some(text))
+}
+
+// Read a required text argument. Null is an error, and invalid UTF-8 is an
+// error rather than being encoded as an empty string.
+fn utf8_c_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, HarmonyResult> {
+    if s.is_null() {
+        return Err(HarmonyResult::err(
+            HarmonyErrorCode::NullPointer,
+            format!("{} is null", what),
+        ));
+    }
+    unsafe { CStr::from_ptr(s) }.to_str().map_err(|e| {
+        HarmonyResult::err(
+            HarmonyErrorCode::InvalidUtf8,
+            format!("{} is not valid UTF-8: {}", what, e),
+        )
+    })
+}
+
+// Hand a string to the caller; it must be released with harmony_free_string.
+fn write_c_string(text: String, text_out: *mut *mut c_char) -> HarmonyResult {
+    match CString::new(text) {
+        Ok(c_str) => {
             unsafe {
-                *tokens_len = len;
-                *tokens_out = ptr;
+                *text_out = into_raw_c_string(c_str);
             }
-            
             HarmonyResult::ok()
         }
-        Err(e) => HarmonyResult::err(format!("Failed to render conversation: {}", e))
+        Err(_) => HarmonyResult::err(
+            HarmonyErrorCode::SerializeFailed,
+            "String contains an interior NUL byte".to_string(),
+        ),
     }
 }
 
-// Decode tokens to text
-#[no_mangle]
-pub extern "C" fn harmony_encoding_decode(
-    wrapper: *const HarmonyEncodingWrapper,
-    tokens: *const u32,
-    tokens_len: usize,
-) -> *mut c_char {
-    if wrapper.is_null() || tokens.is_null() {
+// View a caller-provided array as a slice. C callers commonly pass a null
+// pointer for an empty array, which `slice::from_raw_parts` does not allow.
+unsafe fn c_slice<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+    if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, len)
+    }
+}
+
+// Allocation callbacks installed with harmony_set_allocators
+pub type HarmonyAllocFn = unsafe extern "C" fn(size: usize) -> *mut c_void;
+pub type HarmonyFreeFn = unsafe extern "C" fn(ptr: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Allocator {
+    alloc: HarmonyAllocFn,
+    free: HarmonyFreeFn,
+}
+
+// None means buffers come from Rust's global allocator.
+static ALLOCATOR: RwLock<Option<Allocator>> = RwLock::new(None);
+
+fn custom_allocator() -> Option<Allocator> {
+    *ALLOCATOR.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The free function for each outstanding buffer from a custom allocator, so it
+// is released by the allocator that made it even if harmony_set_allocators was
+// called again since. Pointers not listed came from the global allocator.
+static CUSTOM_ALLOCATIONS: Mutex<Option<HashMap<usize, HarmonyFreeFn>>> = Mutex::new(None);
+// Set on the first custom allocation so frees skip the lookup until then.
+static CUSTOM_ALLOCATED: AtomicBool = AtomicBool::new(false);
+
+fn custom_allocations() -> std::sync::MutexGuard<'static, Option<HashMap<usize, HarmonyFreeFn>>> {
+    CUSTOM_ALLOCATIONS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+// The free function to release `ptr` with, if a custom allocator made it.
+fn take_custom_free(ptr: *mut c_void) -> Option<HarmonyFreeFn> {
+    if !CUSTOM_ALLOCATED.load(Ordering::Acquire) {
+        return None;
+    }
+    custom_allocations()
+        .as_mut()
+        .and_then(|allocations| allocations.remove(&(ptr as usize)))
+}
+
+// Copy `data` into memory from the caller's allocator. Empty data is handed
+// out as null, which every free function accepts, so the callback never sees
+// a zero-sized request. Running out of memory aborts, as it does with the
+// global allocator.
+fn custom_alloc_copy<T: Copy>(allocator: Allocator, data: &[T]) -> *mut T {
+    if data.is_empty() {
         return ptr::null_mut();
     }
-    
-    let encoding = unsafe { &(*wrapper).encoding };
-    let tokens_slice = unsafe { std::slice::from_raw_parts(tokens, tokens_len) };
-    
-    match encoding.tokenizer.decode_bytes(tokens_slice) {
-        Ok(bytes) => {
-            match String::from_utf8(bytes) {
-                Ok(text) => {
-                    match CString::new(text) {
-                        Ok(c_str) => c_str.into_raw(),
-                        Err(_) => ptr::null_mut(),
-                    }
-                }
-                Err(_) => ptr::null_mut(),
-            }
-        }
-        Err(_) => ptr::null_mut(),
+    let layout = Layout::array::<T>(data.len()).expect("buffer size overflows isize");
+    let ptr = unsafe { (allocator.alloc)(layout.size()) }.cast::<T>();
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
     }
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+    custom_allocations()
+        .get_or_insert_with(HashMap::new)
+        .insert(ptr as usize, allocator.free);
+    CUSTOM_ALLOCATED.store(true, Ordering::Release);
+    ptr
 }
 
-// Get stop tokens
-#[no_mangle]
-pub extern "C" fn harmony_encoding_stop_tokens(
-    wrapper: *const HarmonyEncodingWrapper,
+// Convert to raw pointer. With the global allocator the allocation is exactly
+// `len` elements long so it can be reclaimed with Vec::from_raw_parts(ptr, len, len).
+fn into_raw_buffer<T: Copy>(buffer: Vec<T>) -> (*mut T, usize) {
+    let len = buffer.len();
+    let ptr = match custom_allocator() {
+        Some(allocator) => custom_alloc_copy(allocator, &buffer),
+        None => Box::into_raw(buffer.into_boxed_slice()) as *mut T,
+    };
+    (ptr, len)
+}
+
+// Release a buffer created by into_raw_buffer with the allocator that made it.
+// Empty buffers own no memory under either allocator.
+unsafe fn free_raw_buffer<T>(buffer: *mut T, len: usize) {
+    if buffer.is_null() || len == 0 {
+        return;
+    }
+    match take_custom_free(buffer.cast()) {
+        Some(free) => free(buffer.cast()),
+        None => drop(Vec::from_raw_parts(buffer, len, len)),
+    }
+}
+
+// Hand a C string to the caller; it must be released with harmony_free_string.
+fn into_raw_c_string(c_str: CString) -> *mut c_char {
+    match custom_allocator() {
+        Some(allocator) => custom_alloc_copy(allocator, c_str.as_bytes_with_nul()).cast(),
+        None => c_str.into_raw(),
+    }
+}
+
+// Hand a token buffer to the caller; it must be released with harmony_free_tokens.
+fn write_tokens(
+    tokens: Vec<u32>,
     tokens_out: *mut *mut u32,
     tokens_len: *mut usize,
 ) -> HarmonyResult {
-    if wrapper.is_null() {
-        return HarmonyResult::err("Null encoding wrapper".to_string());
+    if tokens_out.is_null() || tokens_len.is_null() {
+        return HarmonyResult::err(
+            HarmonyErrorCode::NullPointer,
+            "Null tokens output".to_string(),
+        );
     }
-    
-    let encoding = unsafe { &(*wrapper).encoding };
-    
-    // Get stop tokens from the encoding
-    let stop_tokens = match encoding.stop_tokens() {
-        Ok(tokens) => tokens.into_iter().collect::<Vec<_>>(),
-        Err(e) => return HarmonyResult::err(format!("Failed to get stop tokens: {}", e)),
-    };
-    
-    // Convert to raw pointer
-    let mut tokens_vec = stop_tokens;
-    tokens_vec.shrink_to_fit();
-    let len = tokens_vec.len();
-    let ptr = tokens_vec.as_mut_ptr();
-    std::mem::forget(tokens_vec);
-    
+
+    let (ptr, len) = into_raw_buffer(tokens);
+
     unsafe {
         *tokens_len = len;
         *tokens_out = ptr;
     }
-    
+
     HarmonyResult::ok()
-}
\ No newline at end of file
+}
+
+// A null `auto_drop_analysis` renders without a config, which keeps every
+// analysis message; otherwise it sets `RenderConversationConfig::auto_drop_analysis`.
+fn render_config_from_c(auto_drop_analysis: *const bool) -> Option<RenderConversationConfig> {
+    (!auto_drop_analysis.is_null()).then(|| RenderConversationConfig {
+        auto_drop_analysis: unsafe { *auto_drop_analysis },
+    })
+}
+
+fn parse_conversation_json(
+    conversation_json: *const c_char,
+) -> Result<Conversation, HarmonyResult> {
+    if conversation_json.is_null() {
+        return Err(HarmonyResult::err(
+            HarmonyErrorCode::NullPointer,
+            "Null conversation JSON".to_string(),
+        ));
+    }
+    let json = unsafe { CStr::from_ptr(conversation_json) }
+        .to_str()
+        .map_err(|e| {
+            HarmonyResult::err(
+                HarmonyErrorCode::InvalidUtf8,
+                format!("Conversation JSON is not valid UTF-8: {}", e),
+            )
+        })?;
+    let invalid = |e: &dyn std::fmt::Display| {
+        HarmonyResult::err(
+            HarmonyErrorCode::InvalidArgument,
+            format!("Invalid conversation JSON: {}", e),
+        )
+    };
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| invalid(&e))?;
+    check_structured_content(&value).map_err(|e| invalid(&e))?;
+    serde_json::from_value(value).map_err(|e| invalid(&e))
+}
+
+const SYSTEM_CONTENT_FIELDS: &[&str] = &[
+    "type",
+    "model_identity",
+    "reasoning_effort",
+    "tools",
+    "conversation_start_date",
+    "knowledge_cutoff",
+    "channel_config",
+];
+const DEVELOPER_CONTENT_FIELDS: &[&str] = &["type", "instructions", "tools"];
+const CHANNEL_CONFIG_FIELDS: &[&str] = &["valid_channels", "channel_required"];
+const TOOL_NAMESPACE_FIELDS: &[&str] = &["name", "description", "tools"];
+const TOOL_FIELDS: &[&str] = &["name", "description", "parameters"];
+
+// Serde ignores unknown fields, which would silently drop a misspelled setting
+// of a structured system or developer message, so the conversation JSON is
+// checked against the fields the Rust types have before it is deserialized.
+// Type errors are left to serde.
+fn check_fields(value: &serde_json::Value, allowed: &[&str], path: &str) -> Result<(), String> {
+    let unknown = value
+        .as_object()
+        .and_then(|object| object.keys().find(|key| !allowed.contains(&key.as_str())));
+    match unknown {
+        Some(key) => Err(format!("{}: unknown field `{}`", path, key)),
+        None => Ok(()),
+    }
+}
+
+fn check_tool_namespaces(tools: &serde_json::Value, path: &str) -> Result<(), String> {
+    let Some(namespaces) = tools.as_object() else {
+        return Ok(());
+    };
+    for (key, namespace) in namespaces {
+        let path = format!("{}.tools.{}", path, key);
+        check_fields(namespace, TOOL_NAMESPACE_FIELDS, &path)?;
+        if let Some(name) = namespace.get("name").and_then(serde_json::Value::as_str) {
+            if name != key {
+                return Err(format!("{}: namespace is named `{}`", path, name));
+            }
+        }
+        let tools = namespace.get("tools").and_then(serde_json::Value::as_array);
+        for (index, tool) in tools.into_iter().flatten().enumerate() {
+            let path = format!("{}.tools[{}]", path, index);
+            check_fields(tool, TOOL_FIELDS, &path)?;
+            match tool.get("parameters") {
+                None | Some(serde_json::Value::Null) | Some(serde_json::Value::Object(_)) => {}
+                Some(_) => return Err(format!("{}.parameters: must be a JSON object", path)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_structured_content(conversation: &serde_json::Value) -> Result<(), String> {
+    let messages = conversation
+        .get("messages")
+        .and_then(serde_json::Value::as_array);
+    for (i, message) in messages.into_iter().flatten().enumerate() {
+        let role = message.get("role").and_then(serde_json::Value::as_str);
+        let contents = message.get("content").and_then(serde_json::Value::as_array);
+        for (j, content) in contents.into_iter().flatten().enumerate() {
+            let path = format!("messages[{}].content[{}]", i, j);
+            let (expected_role, fields) =
+                match content.get("type").and_then(serde_json::Value::as_str) {
+                    Some("system_content") => ("system", SYSTEM_CONTENT_FIELDS),
+                    Some("developer_content") => ("developer", DEVELOPER_CONTENT_FIELDS),
+                    _ => continue,
+                };
+            if role != Some(expected_role) {
+                return Err(format!(
+                    "{}: {} content belongs in a {} message",
+                    path, expected_role, expected_role
+                ));
+            }
+            check_fields(content, fields, &path)?;
+            if let Some(config) = content.get("channel_config") {
+                check_fields(
+                    config,
+                    CHANNEL_CONFIG_FIELDS,
+                    &format!("{}.channel_config", path),
+                )?;
+            }
+            if let Some(tools) = content.get("tools") {
+                check_tool_namespaces(tools, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove and return the longest prefix of `pending` that decodes to complete
+/// characters, leaving the bytes of a trailing partial codepoint in place.
+/// Byte sequences that can never become valid UTF-8 are replaced with U+FFFD.
+fn take_complete_utf8(pending: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut rest = pending.as_slice();
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                rest = &[];
+                break;
+            }
+            Err(e) => {
+                let (valid, tail) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                match e.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &tail[len..];
+                    }
+                    None => {
+                        rest = tail;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    let consumed = pending.len() - rest.len();
+    pending.drain(..consumed);
+    text
+}
+
+/// Parse a JSON array of `{name, description, parameters}` objects into
+/// function tool descriptions. Every tool must have a non-empty string name;
+/// `description` defaults to empty and `parameters` to none.
+fn parse_function_tools_json(
+    tools_json: *const c_char,
+) -> Result<Vec<ToolDescription>, HarmonyResult> {
+    if tools_json.is_null() {
+        return Err(HarmonyResult::err(
+            HarmonyErrorCode::NullPointer,
+            "Null tools JSON".to_string(),
+        ));
+    }
+    let json = unsafe { CStr::from_ptr(tools_json) }
+        .to_str()
+        .map_err(|e| {
+            HarmonyResult::err(
+                HarmonyErrorCode::InvalidUtf8,
+                format!("Tools JSON is not valid UTF-8: {}", e),
+            )
+        })?;
+    let invalid = |msg: String| HarmonyResult::err(HarmonyErrorCode::InvalidArgument, msg);
+    let tools: Vec<serde_json::Value> =
+        serde_json::from_str(json).map_err(|e| invalid(format!("Invalid tools JSON: {}", e)))?;
+
+    tools
+        .into_iter()
+        .enumerate()
+        .map(|(index, tool)| {
+            let name = match tool.get("name").and_then(|name| name.as_str()) {
+                Some(name) if !name.is_empty() => name,
+                _ => return Err(invalid(format!("Tool {} is missing a name", index))),
+            };
+            let description = match tool.get("description") {
+                None | Some(serde_json::Value::Null) => "",
+                Some(serde_json::Value::String(description)) => description.as_str(),
+                Some(_) => {
+                    return Err(invalid(format!(
+                        "Tool {} has a non-string description",
+                        name
+                    )))
+                }
+            };
+            let parameters = tool.get("parameters").filter(|p| !p.is_null()).cloned();
+            Ok(ToolDescription::new(name, description, parameters))
+        })
+        .collect()
+}
+
+// Crate version, usable before any encoding is loaded
+#[no_mangle]
+pub extern "C" fn harmony_version() -> *const c_char {
+    ffi_boundary(|| concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast())
+}
+
+// Free functions
+#[no_mangle]
+pub extern "C" fn harmony_free_string(s: *mut c_char) {
+    ffi_boundary(|| {
+        if !s.is_null() {
+            match take_custom_free(s.cast()) {
+                Some(free) => unsafe { free(s.cast()) },
+                None => unsafe { drop(CString::from_raw(s)) },
+            }
+        }
+    })
+}
+
+// Route every buffer and string handed to the caller through alloc_fn and
+// free_fn, or back to the global allocator when both are null.
+#[no_mangle]
+pub extern "C" fn harmony_set_allocators(
+    alloc_fn: Option<HarmonyAllocFn>,
+    free_fn: Option<HarmonyFreeFn>,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        let allocator = match (alloc_fn, free_fn) {
+            (Some(alloc), Some(free)) => Some(Allocator { alloc, free }),
+            (None, None) => None,
+            _ => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    "alloc_fn and free_fn must both be set or both be null".to_string(),
+                )
+            }
+        };
+        *ALLOCATOR.write().unwrap_or_else(PoisonError::into_inner) = allocator;
+        HarmonyResult::ok()
+    })
+}
+
+// Free a parse error's message and reset it to null so it cannot be freed twice
+#[no_mangle]
+pub extern "C" fn harmony_parse_error_free(error: *mut HarmonyParseError) {
+    ffi_boundary(|| {
+        if error.is_null() {
+            return;
+        }
+        let error = unsafe { &mut *error };
+        harmony_free_string(error.message);
+        error.message = ptr::null_mut();
+    })
+}
+
+// Free a result's error message and reset it to null so it cannot be freed twice
+#[no_mangle]
+pub extern "C" fn harmony_result_free(result: *mut HarmonyResult) {
+    ffi_boundary(|| {
+        if result.is_null() {
+            return;
+        }
+        let result = unsafe { &mut *result };
+        harmony_free_string(result.error_message);
+        result.error_message = ptr::null_mut();
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_free_tokens(tokens: *mut u32, len: usize) {
+    ffi_boundary(|| unsafe { free_raw_buffer(tokens, len) })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_free_tokens_i32(tokens: *mut i32, len: usize) {
+    ffi_boundary(|| unsafe { free_raw_buffer(tokens, len) })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_free_offsets(offsets: *mut usize, len: usize) {
+    ffi_boundary(|| unsafe { free_raw_buffer(offsets, len) })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_free_lengths(lengths: *mut usize, len: usize) {
+    ffi_boundary(|| unsafe { free_raw_buffer(lengths, len) })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_free_mask(mask: *mut u8, len: usize) {
+    ffi_boundary(|| unsafe { free_raw_buffer(mask, len) })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_free_bytes(bytes: *mut u8, len: usize) {
+    ffi_boundary(|| unsafe { free_raw_buffer(bytes, len) })
+}
+
+// Harmony Encoding functions
+#[no_mangle]
+pub extern "C" fn harmony_encoding_new() -> *mut HarmonyEncodingWrapper {
+    ffi_boundary(|| new_encoding_wrapper(HarmonyEncodingName::HarmonyGptOss))
+}
+
+// Load an encoding by its HarmonyEncodingName, e.g. "HarmonyGptOss"
+#[no_mangle]
+pub extern "C" fn harmony_encoding_new_by_name(name: *const c_char) -> *mut HarmonyEncodingWrapper {
+    ffi_boundary(|| {
+        if name.is_null() {
+            return null_with_error("Null encoding name");
+        }
+
+        let name = match unsafe { CStr::from_ptr(name) }.to_str() {
+            Ok(name) => name,
+            Err(e) => return null_with_error(format!("Encoding name is not valid UTF-8: {}", e)),
+        };
+
+        match name.parse::<HarmonyEncodingName>() {
+            Ok(name) => new_encoding_wrapper(name),
+            Err(e) => null_with_error(e.to_string()),
+        }
+    })
+}
+
+// Load an encoding from an in-memory copy of its vocab file
+#[no_mangle]
+pub extern "C" fn harmony_encoding_new_from_data(
+    data: *const u8,
+    len: usize,
+    name: *const c_char,
+) -> *mut HarmonyEncodingWrapper {
+    ffi_boundary(|| {
+        if data.is_null() && len != 0 {
+            return null_with_error("Null vocab data");
+        }
+
+        let name = match optional_c_str(name, "Encoding name") {
+            Ok(Some(name)) => match name.parse::<HarmonyEncodingName>() {
+                Ok(name) => name,
+                Err(e) => return null_with_error(e.to_string()),
+            },
+            Ok(None) => HarmonyEncodingName::HarmonyGptOss,
+            Err(e) => {
+                // The message is already recorded as the last error
+                harmony_free_string(e.error_message);
+                return ptr::null_mut();
+            }
+        };
+
+        let data = unsafe { c_slice(data, len) };
+        match load_harmony_encoding_from_data(name, data) {
+            Ok(encoding) => Box::into_raw(Box::new(HarmonyEncodingWrapper::new(encoding))),
+            Err(e) => null_with_error(format!("Failed to load encoding from data: {}", e)),
+        }
+    })
+}
+
+fn new_encoding_wrapper(name: HarmonyEncodingName) -> *mut HarmonyEncodingWrapper {
+    match load_harmony_encoding(name) {
+        Ok(encoding) => {
+            let wrapper = Box::new(HarmonyEncodingWrapper::new(encoding));
+            Box::into_raw(wrapper)
+        }
+        Err(e) => null_with_error(format!("Failed to load encoding: {}", e)),
+    }
+}
+
+// New handle to an already loaded encoding; the vocabulary is shared, not copied
+#[no_mangle]
+pub extern "C" fn harmony_encoding_clone(
+    wrapper: *const HarmonyEncodingWrapper,
+) -> *mut HarmonyEncodingWrapper {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        Box::into_raw(Box::new(HarmonyEncodingWrapper::new(encoding.clone())))
+    })
+}
+
+// Identify an encoding as "<encoding name>/<tokenizer name>"
+#[no_mangle]
+pub extern "C" fn harmony_encoding_version(wrapper: *const HarmonyEncodingWrapper) -> *mut c_char {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        match CString::new(format!("{}/{}", encoding.name(), encoding.tokenizer_name())) {
+            Ok(version) => into_raw_c_string(version),
+            Err(_) => null_with_error("Encoding version contains a NUL byte"),
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_encoding_free(wrapper: *mut HarmonyEncodingWrapper) {
+    ffi_boundary(|| free_handle(wrapper))
+}
+
+// Encode plain text, giving up once it exceeds `max_tokens`; 0 means no limit.
+fn encode_ordinary_limited(
+    encoding: &HarmonyEncoding,
+    text: &str,
+    max_tokens: usize,
+) -> Option<Vec<u32>> {
+    if max_tokens == 0 {
+        Some(encoding.tokenizer.encode_ordinary(text))
+    } else {
+        encoding.tokenizer.encode_ordinary_up_to(text, max_tokens)
+    }
+}
+
+fn too_long(max_tokens: usize) -> HarmonyResult {
+    HarmonyResult::err(
+        HarmonyErrorCode::TooLong,
+        format!("Text is too long: more than {} tokens", max_tokens),
+    )
+}
+
+// Plain text encoding - encode text without Harmony formatting
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_plain(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| harmony_encoding_encode_plain_limited(wrapper, text, 0, tokens_out, tokens_len))
+}
+
+// Plain text encoding, optionally preceded by the header of a message from
+// `role` (-1 for assistant) so the text continues as that message's content
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_plain_with_header(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    include_header: bool,
+    role: i32,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        if text.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null text".to_string());
+        }
+
+        let text_str = match utf8_c_str(text, "Text") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let mut tokens = Vec::new();
+        if include_header {
+            let role = match role_from_c(role) {
+                Ok(role) => role.unwrap_or(Role::Assistant),
+                Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+            };
+            let message = Message::from_role_and_content(role, "");
+            match header_tokens(encoding, &message) {
+                Ok(header) => tokens = header,
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::RenderFailed,
+                        format!("Failed to render message header: {}", e),
+                    )
+                }
+            }
+        }
+        tokens.extend(encoding.tokenizer.encode_ordinary(text_str));
+
+        write_tokens(tokens, tokens_out, tokens_len)
+    })
+}
+
+// Plain text encoding that stops once more than `max_tokens` tokens (0 = no limit) are produced
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_plain_limited(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    max_tokens: usize,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        // Get text string
+        if text.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null text".to_string());
+        }
+
+        let text_str = match utf8_c_str(text, "Text") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        // Use the official harmony tokenizer for plain encoding
+        match encode_ordinary_limited(encoding, text_str, max_tokens) {
+            Some(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            None => too_long(max_tokens),
+        }
+    })
+}
+
+// Plain text encoding into signed token ids
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_plain_i32(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    tokens_out: *mut *mut i32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if text.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null text".to_string());
+        }
+        if tokens_out.is_null() || tokens_len.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null tokens output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text_str = match utf8_c_str(text, "Text") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let tokens = encoding.tokenizer.encode_ordinary(text_str);
+        let signed = match tokens
+            .iter()
+            .enumerate()
+            .map(|(index, &token)| i32::try_from(token).map_err(|_| (index, token)))
+            .collect::<Result<Vec<i32>, _>>()
+        {
+            Ok(signed) => signed,
+            Err((index, token)) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!(
+                        "Token id {} at index {} does not fit in int32_t",
+                        token, index
+                    ),
+                )
+            }
+        };
+
+        let (ptr, len) = into_raw_buffer(signed);
+        unsafe {
+            *tokens_out = ptr;
+            *tokens_len = len;
+        }
+        HarmonyResult::ok()
+    })
+}
+
+// Plain text encoding of `text_len` bytes, which may include NUL bytes
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_plain_len(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    text_len: usize,
+    max_tokens: usize,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if text.is_null() && text_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null text".to_string());
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text_bytes = unsafe { c_slice(text.cast::<u8>(), text_len) };
+        let text_str = match std::str::from_utf8(text_bytes) {
+            Ok(text) => text,
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidUtf8,
+                    format!("Text is not valid UTF-8: {}", e),
+                )
+            }
+        };
+
+        match encode_ordinary_limited(encoding, text_str, max_tokens) {
+            Some(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            None => too_long(max_tokens),
+        }
+    })
+}
+
+// Number of token ids, ordinary and special
+#[no_mangle]
+pub extern "C" fn harmony_encoding_vocab_size(wrapper: *const HarmonyEncodingWrapper) -> usize {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        encoding.tokenizer.vocab_size()
+    })
+}
+
+// Largest token id, ordinary or special
+#[no_mangle]
+pub extern "C" fn harmony_encoding_max_token_id(wrapper: *const HarmonyEncodingWrapper) -> u32 {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        encoding.tokenizer.max_token_id().unwrap_or(0)
+    })
+}
+
+// Whether a token is a special token rather than ordinary text
+#[no_mangle]
+pub extern "C" fn harmony_encoding_is_special_token(
+    wrapper: *const HarmonyEncodingWrapper,
+    token: u32,
+) -> bool {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        encoding.tokenizer.is_special_token(token)
+    })
+}
+
+// Which formatting token, if any, a token is
+#[no_mangle]
+pub extern "C" fn harmony_encoding_token_role_marker(
+    wrapper: *const HarmonyEncodingWrapper,
+    token: u32,
+) -> i32 {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        HarmonyTokenMarker::from(formatting_token_for_rank(encoding, token)) as i32
+    })
+}
+
+// Diagnostic: whether encoding then decoding `text` reproduces it exactly
+#[no_mangle]
+pub extern "C" fn harmony_encoding_roundtrip_ok(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+) -> bool {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if text.is_null() {
+            set_last_error("Null text".to_string());
+            return false;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text = match unsafe { CStr::from_ptr(text) }.to_str() {
+            Ok(text) => text,
+            Err(e) => {
+                set_last_error(format!("Text is not valid UTF-8: {}", e));
+                return false;
+            }
+        };
+
+        let tokens = encoding.tokenizer.encode_ordinary(text);
+        match encoding.tokenizer.decode_utf8(&tokens) {
+            Ok(decoded) => decoded == text,
+            Err(e) => {
+                set_last_error(format!("Failed to decode tokens: {}", e));
+                false
+            }
+        }
+    })
+}
+
+// Plain text encoding returning tokens, token count and text byte length at once
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_plain_counted(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    max_tokens: usize,
+) -> HarmonyEncodedText {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if text.is_null() {
+            return HarmonyEncodedText::err(HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null text".to_string(),
+            ));
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text_str = match utf8_c_str(text, "Text") {
+            Ok(text) => text,
+            Err(e) => return HarmonyEncodedText::err(e),
+        };
+
+        let tokens = match encode_ordinary_limited(encoding, text_str, max_tokens) {
+            Some(tokens) => tokens,
+            None => return HarmonyEncodedText::err(too_long(max_tokens)),
+        };
+        let (tokens, tokens_len) = into_raw_buffer(tokens);
+        HarmonyEncodedText {
+            result: HarmonyResult::ok(),
+            tokens,
+            tokens_len,
+            text_byte_len: text_str.len(),
+        }
+    })
+}
+
+// Header tokens for a channel or recipient marker
+#[no_mangle]
+pub extern "C" fn harmony_encoding_tokens_for_marker(
+    wrapper: *const HarmonyEncodingWrapper,
+    marker: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if marker.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null marker".to_string());
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let marker = match utf8_c_str(marker, "Marker") {
+            Ok(marker) => marker,
+            Err(e) => return e,
+        };
+
+        let rendered = match marker.split_once(':') {
+            Some((_, "")) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!("Empty name in marker '{}'", marker),
+                )
+            }
+            Some(("channel", channel)) => encoding.render_channel_marker(channel),
+            Some(("recipient", recipient)) => encoding.render_recipient_marker(recipient),
+            _ => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!(
+                        "Invalid marker '{}': expected channel:<name> or recipient:<name>",
+                        marker
+                    ),
+                )
+            }
+        };
+
+        match rendered {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render marker: {}", e),
+            ),
+        }
+    })
+}
+
+// Encoding that maps allowed special-token markers to their ids
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_with_special(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    allowed_special_json: *const c_char,
+    max_tokens: usize,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if text.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null text".to_string());
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text_str = match utf8_c_str(text, "Text") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let known_special = encoding.tokenizer.special_tokens();
+        let allowed_names: Vec<String>;
+        let allowed_special: HashSet<&str> = if allowed_special_json.is_null() {
+            known_special
+        } else {
+            let json = match unsafe { CStr::from_ptr(allowed_special_json) }.to_str() {
+                Ok(json) => json,
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::InvalidUtf8,
+                        format!("Allowed special JSON is not valid UTF-8: {}", e),
+                    )
+                }
+            };
+            allowed_names = match serde_json::from_str(json) {
+                Ok(names) => names,
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::InvalidArgument,
+                        format!("Invalid allowed special JSON: {}", e),
+                    )
+                }
+            };
+            if let Some(unknown) = allowed_names
+                .iter()
+                .find(|name| !known_special.contains(name.as_str()))
+            {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!("Unknown special token: {}", unknown),
+                );
+            }
+            allowed_names.iter().map(String::as_str).collect()
+        };
+
+        if max_tokens == 0 {
+            let (tokens, _) = encoding.tokenizer.encode(text_str, &allowed_special);
+            return write_tokens(tokens, tokens_out, tokens_len);
+        }
+        match encoding
+            .tokenizer
+            .encode_up_to(text_str, &allowed_special, max_tokens)
+        {
+            Some(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            None => too_long(max_tokens),
+        }
+    })
+}
+
+// Batch plain text encoding - encode many strings in one call
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_plain_batch(
+    wrapper: *const HarmonyEncodingWrapper,
+    texts: *const *const c_char,
+    texts_len: usize,
+    max_tokens: usize,
+    tokens_out: *mut *mut u32,
+    offsets_out: *mut *mut usize,
+    total_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if texts.is_null() && texts_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null texts".to_string());
+        }
+        if tokens_out.is_null() || offsets_out.is_null() || total_len.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null batch output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text_ptrs = unsafe { c_slice(texts, texts_len) };
+
+        let mut text_strs = Vec::with_capacity(texts_len);
+        for (i, &text) in text_ptrs.iter().enumerate() {
+            if text.is_null() {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::NullPointer,
+                    format!("Null text at index {}", i),
+                );
+            }
+            match utf8_c_str(text, &format!("Text at index {}", i)) {
+                Ok(text) => text_strs.push(text),
+                Err(e) => return e,
+            }
+        }
+
+        // Spread the texts over the available cores; the tokenizer is Sync.
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = text_strs.len().div_ceil(threads).max(1);
+        let encoded: Vec<Option<Vec<u32>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = text_strs
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|text| encode_ordinary_limited(encoding, text, max_tokens))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let mut texts_tokens = Vec::with_capacity(texts_len);
+        for (i, text_tokens) in encoded.into_iter().enumerate() {
+            match text_tokens {
+                Some(text_tokens) => texts_tokens.push(text_tokens),
+                None => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::TooLong,
+                        format!(
+                            "Text at index {} is too long: more than {} tokens",
+                            i, max_tokens
+                        ),
+                    )
+                }
+            }
+        }
+
+        let mut offsets = Vec::with_capacity(texts_len + 1);
+        let mut tokens = Vec::with_capacity(texts_tokens.iter().map(Vec::len).sum());
+        offsets.push(0);
+        for text_tokens in texts_tokens {
+            tokens.extend(text_tokens);
+            offsets.push(tokens.len());
+        }
+
+        let (tokens_ptr, len) = into_raw_buffer(tokens);
+        let (offsets_ptr, _) = into_raw_buffer(offsets);
+
+        unsafe {
+            *tokens_out = tokens_ptr;
+            *offsets_out = offsets_ptr;
+            *total_len = len;
+        }
+
+        HarmonyResult::ok()
+    })
+}
+
+// Encode plain text once and split the tokens into overlapping windows
+#[no_mangle]
+pub extern "C" fn harmony_encoding_encode_windows(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    window: usize,
+    stride: usize,
+    tokens_out: *mut *mut u32,
+    offsets_out: *mut *mut usize,
+    count_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens_out.is_null() || offsets_out.is_null() || count_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null windows output".to_string(),
+            );
+        }
+        if text.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null text".to_string());
+        }
+        if window == 0 || stride >= window {
+            return HarmonyResult::err(
+                HarmonyErrorCode::InvalidArgument,
+                format!(
+                    "Window must be positive and larger than the stride, got window {} and stride {}",
+                    window, stride
+                ),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text = match utf8_c_str(text, "Text") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let encoded = encoding.tokenizer().encode_ordinary(text);
+
+        // Each window starts window - stride tokens after the previous one; the
+        // last one ends at the final token and may be shorter.
+        let step = window - stride;
+        let mut offsets = vec![0];
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        while start < encoded.len() {
+            let end = (start + window).min(encoded.len());
+            tokens.extend_from_slice(&encoded[start..end]);
+            offsets.push(tokens.len());
+            if end == encoded.len() {
+                break;
+            }
+            start += step;
+        }
+
+        let count = offsets.len() - 1;
+        let (tokens_ptr, _) = into_raw_buffer(tokens);
+        let (offsets_ptr, _) = into_raw_buffer(offsets);
+
+        unsafe {
+            *tokens_out = tokens_ptr;
+            *offsets_out = offsets_ptr;
+            *count_out = count;
+        }
+
+        HarmonyResult::ok()
+    })
+}
+
+// Count the tokens of plain text without returning them
+#[no_mangle]
+pub extern "C" fn harmony_encoding_count_tokens(
+    wrapper: *const HarmonyEncodingWrapper,
+    text: *const c_char,
+    count_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if text.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null text".to_string());
+        }
+        if count_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null count output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text_str = match utf8_c_str(text, "Text") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let count = encoding.tokenizer.encode_ordinary(text_str).len();
+
+        unsafe {
+            *count_out = count;
+        }
+
+        HarmonyResult::ok()
+    })
+}
+
+// Sink that counts rendered tokens instead of storing them
+#[derive(Default)]
+struct TokenCounter(usize);
+
+impl Extend<u32> for TokenCounter {
+    fn extend<I: IntoIterator<Item = u32>>(&mut self, iter: I) {
+        self.0 += iter.into_iter().count();
+    }
+}
+
+// Count the tokens of a rendered JSON conversation without returning them
+#[no_mangle]
+pub extern "C" fn harmony_encoding_count_conversation_tokens(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    auto_drop_analysis: *const bool,
+    count_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if count_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null count output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        let mut counter = TokenCounter::default();
+        match encoding.render_conversation_into(&conversation, &mut counter, config.as_ref()) {
+            Ok(()) => {
+                unsafe {
+                    *count_out = counter.0;
+                }
+                HarmonyResult::ok()
+            }
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Token count of every message of a conversation supplied as JSON
+#[no_mangle]
+pub extern "C" fn harmony_encoding_message_token_lengths(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    auto_drop_analysis: *const bool,
+    lengths_out: *mut *mut usize,
+    count_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if lengths_out.is_null() || count_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null lengths output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        match encoding.render_conversation_message_lengths(&conversation, config.as_ref()) {
+            Ok(lengths) => {
+                let (ptr, len) = into_raw_buffer(lengths);
+                unsafe {
+                    *lengths_out = ptr;
+                    *count_out = len;
+                }
+                HarmonyResult::ok()
+            }
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Drop the oldest messages of a JSON conversation until it fits a token budget
+#[no_mangle]
+pub extern "C" fn harmony_encoding_truncate_to_budget(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    auto_drop_analysis: *const bool,
+    max_tokens: usize,
+    keep_last_user: bool,
+    conversation_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if conversation_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null conversation output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+        let budget = if max_tokens == 0 {
+            usize::MAX
+        } else {
+            max_tokens
+        };
+
+        let (messages, len) = match encoding.truncate_conversation_to_budget(
+            &conversation,
+            budget,
+            keep_last_user,
+            config.as_ref(),
+        ) {
+            Ok(truncated) => truncated,
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::RenderFailed,
+                    format!("Failed to render conversation: {}", e),
+                )
+            }
+        };
+        let kept_user = messages.iter().any(|msg| msg.author.role == Role::User);
+        if len > budget && !(keep_last_user && kept_user) {
+            return HarmonyResult::err(
+                HarmonyErrorCode::TooLong,
+                format!(
+                    "Conversation does not fit in {} tokens; the messages that are always kept need {}",
+                    max_tokens, len
+                ),
+            );
+        }
+
+        match serde_json::to_string(&Conversation::from_messages(messages)) {
+            Ok(json) => write_c_string(json, conversation_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize conversation to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Whether a JSON conversation leaves the assistant's turn open, finished or waiting on a tool
+#[no_mangle]
+pub extern "C" fn harmony_encoding_conversation_needs_new_turn(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    next_turn_out: *mut i32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if next_turn_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null next turn output".to_string(),
+            );
+        }
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        unsafe { *next_turn_out = HarmonyNextTurn::after(&conversation.messages) as i32 };
+        HarmonyResult::ok()
+    })
+}
+
+// A system or developer message passed on its own as a JSON message object,
+// checked like a message of a conversation JSON. Null means no message.
+fn parse_prefix_message_json(
+    json: *const c_char,
+    role: Role,
+    what: &str,
+) -> Result<Option<Message>, HarmonyResult> {
+    if json.is_null() {
+        return Ok(None);
+    }
+    let json = utf8_c_str(json, what)?;
+    let invalid = |e: &dyn std::fmt::Display| {
+        HarmonyResult::err(
+            HarmonyErrorCode::InvalidArgument,
+            format!("Invalid {}: {}", what.to_lowercase(), e),
+        )
+    };
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| invalid(&e))?;
+    let wrapped = serde_json::json!({ "messages": [value] });
+    check_structured_content(&wrapped)
+        .map_err(|e| invalid(&e.strip_prefix("messages[0].").unwrap_or(&e)))?;
+    let message: Message = serde_json::from_value(value).map_err(|e| invalid(&e))?;
+    if message.author.role != role {
+        return Err(invalid(&format!(
+            "expected a {} message, got {}",
+            role.as_str(),
+            message.author.role.as_str()
+        )));
+    }
+    Ok(Some(message))
+}
+
+// Render only the system and developer messages that start a conversation
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_prefix(
+    wrapper: *const HarmonyEncodingWrapper,
+    system_json: *const c_char,
+    developer_json: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let system =
+            match parse_prefix_message_json(system_json, Role::System, "System message JSON") {
+                Ok(system) => system,
+                Err(e) => return e,
+            };
+        let developer = match parse_prefix_message_json(
+            developer_json,
+            Role::Developer,
+            "Developer message JSON",
+        ) {
+            Ok(developer) => developer,
+            Err(e) => return e,
+        };
+
+        // Rendered as a conversation so the system message sees the developer
+        // message's function tools, exactly as in the full render
+        let prefix: Vec<&Message> = system.iter().chain(developer.iter()).collect();
+        match encoding.render_conversation(prefix, None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render prefix: {}", e),
+            ),
+        }
+    })
+}
+
+// Render the system and developer messages once and keep them in the wrapper
+// for harmony_encoding_render_with_cached_prefix
+#[no_mangle]
+pub extern "C" fn harmony_encoding_set_cached_prefix(
+    wrapper: *const HarmonyEncodingWrapper,
+    system_json: *const c_char,
+    developer_json: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let wrapper = unsafe { &*wrapper };
+
+        let system =
+            match parse_prefix_message_json(system_json, Role::System, "System message JSON") {
+                Ok(system) => system,
+                Err(e) => return e,
+            };
+        let developer = match parse_prefix_message_json(
+            developer_json,
+            Role::Developer,
+            "Developer message JSON",
+        ) {
+            Ok(developer) => developer,
+            Err(e) => return e,
+        };
+
+        let messages: Vec<Message> = system.into_iter().chain(developer).collect();
+        let cached = if messages.is_empty() {
+            None
+        } else {
+            match wrapper.encoding.render_conversation(&messages, None) {
+                Ok(tokens) => Some(CachedPrefix { messages, tokens }),
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::RenderFailed,
+                        format!("Failed to render prefix: {}", e),
+                    )
+                }
+            }
+        };
+
+        *wrapper
+            .cached_prefix
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = cached;
+        HarmonyResult::ok()
+    })
+}
+
+// Render the cached prefix, a user message and the assistant header for completion
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_with_cached_prefix(
+    wrapper: *const HarmonyEncodingWrapper,
+    user_msg: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if user_msg.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null user message".to_string(),
+            );
+        }
+
+        let wrapper = unsafe { &*wrapper };
+        let encoding = &wrapper.encoding;
+        let user_msg = match utf8_c_str(user_msg, "User message") {
+            Ok(user_msg) => user_msg,
+            Err(e) => return e,
+        };
+
+        let cached_prefix = wrapper
+            .cached_prefix
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(prefix) = cached_prefix.as_ref() else {
+            return HarmonyResult::err(
+                HarmonyErrorCode::InvalidArgument,
+                "No cached prefix; call harmony_encoding_set_cached_prefix first".to_string(),
+            );
+        };
+
+        let user = Message::from_role_and_content(Role::User, user_msg);
+        let conversation = prefix.messages.iter().chain(std::iter::once(&user));
+        let rendered = encoding
+            .render_conversation_suffix(conversation, prefix.messages.len(), None)
+            .and_then(|suffix| {
+                let start = encoding.render_formatting_token(FormattingToken::Start)?;
+                let mut tokens = prefix.tokens.clone();
+                tokens.extend(suffix);
+                tokens.push(start);
+                tokens.extend(encoding.tokenizer.encode_ordinary(Role::Assistant.as_str()));
+                Ok(tokens)
+            });
+
+        match rendered {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Harmony prompt rendering
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_prompt(
+    wrapper: *const HarmonyEncodingWrapper,
+    system_msg: *const c_char,
+    user_msg: *const c_char,
+    assistant_prefix: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        harmony_encoding_render_prompt_v2(
+            wrapper,
+            system_msg,
+            -1,
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+            user_msg,
+            assistant_prefix,
+            ptr::null(),
+            ptr::null(),
+            tokens_out,
+            tokens_len,
+        )
+    })
+}
+
+// Harmony prompt rendering with developer instructions and system settings
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_prompt_v2(
+    wrapper: *const HarmonyEncodingWrapper,
+    system_msg: *const c_char,
+    reasoning_effort: i32,
+    knowledge_cutoff: *const c_char,
+    conversation_start_date: *const c_char,
+    developer_msg: *const c_char,
+    user_msg: *const c_char,
+    assistant_prefix: *const c_char,
+    assistant_channel: *const c_char,
+    assistant_recipient: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let reasoning_effort = match reasoning_effort_from_c(reasoning_effort) {
+            Ok(effort) => effort,
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        let mut messages = Vec::new();
+
+        // Add system message if any system setting was provided
+        let system_text = match optional_c_str(system_msg, "System message") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        let knowledge_cutoff = match optional_c_str(knowledge_cutoff, "Knowledge cutoff") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        let conversation_start_date =
+            match optional_c_str(conversation_start_date, "Conversation start date") {
+                Ok(text) => text,
+                Err(e) => return e,
+            };
+        if system_text.is_some()
+            || reasoning_effort.is_some()
+            || knowledge_cutoff.is_some()
+            || conversation_start_date.is_some()
+        {
+            let mut system_content = SystemContent::new();
+            if let Some(system_text) = system_text {
+                // Use the text as model_identity
+                system_content = system_content.with_model_identity(system_text);
+            }
+            if let Some(effort) = reasoning_effort {
+                system_content = system_content.with_reasoning_effort(effort);
+            }
+            if let Some(cutoff) = knowledge_cutoff {
+                system_content = system_content.with_knowledge_cutoff(cutoff);
+            }
+            if let Some(date) = conversation_start_date {
+                system_content = system_content.with_conversation_start_date(date);
+            }
+            let message = Message::from_role_and_content(Role::System, system_content);
+            messages.push(message);
+        }
+
+        // Add developer message if provided
+        let developer_text = match optional_c_str(developer_msg, "Developer message") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        if let Some(developer_text) = developer_text {
+            let developer_content = DeveloperContent::new().with_instructions(developer_text);
+            let message = Message::from_role_and_content(Role::Developer, developer_content);
+            messages.push(message);
+        }
+
+        // Add user message
+        if user_msg.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null user message".to_string(),
+            );
+        }
+
+        let user_text = match utf8_c_str(user_msg, "User message") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let user_message = Message::from_role_and_content(Role::User, user_text.to_string());
+        messages.push(user_message);
+
+        // Add assistant prefix if any part of it was provided
+        let assistant_text = match optional_c_str(assistant_prefix, "Assistant prefix") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        let assistant_channel = match optional_c_str(assistant_channel, "Assistant channel") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        let assistant_recipient = match optional_c_str(assistant_recipient, "Assistant recipient") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        if assistant_text.is_some() || assistant_channel.is_some() || assistant_recipient.is_some()
+        {
+            let mut assistant_message = Message::from_role_and_content(
+                Role::Assistant,
+                assistant_text.unwrap_or_default().to_string(),
+            );
+            if let Some(channel) = assistant_channel {
+                assistant_message = assistant_message.with_channel(channel);
+            }
+            if let Some(recipient) = assistant_recipient {
+                assistant_message = assistant_message.with_recipient(recipient);
+            }
+            messages.push(assistant_message);
+        }
+
+        // Create conversation and render it
+        let conversation = Conversation::from_messages(messages);
+        match encoding.render_conversation(&conversation, None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Render a conversation of plain-text turns given as parallel arrays of roles
+// and contents, for multi-turn prompts that need neither JSON nor a builder
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_turns(
+    wrapper: *const HarmonyEncodingWrapper,
+    roles: *const i32,
+    texts: *const *const c_char,
+    count: usize,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if (roles.is_null() || texts.is_null()) && count != 0 {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null roles or texts".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let roles = unsafe { c_slice(roles, count) };
+        let texts = unsafe { c_slice(texts, count) };
+
+        let mut messages = Vec::with_capacity(count);
+        for (i, (&role, &text)) in roles.iter().zip(texts).enumerate() {
+            let role = match role_from_c(role) {
+                Ok(Some(role)) => role,
+                Ok(None) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::InvalidArgument,
+                        format!("Turn {} needs a role", i),
+                    )
+                }
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::InvalidArgument,
+                        format!("Turn {}: {}", i, e),
+                    )
+                }
+            };
+            if text.is_null() {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::NullPointer,
+                    format!("Null text at index {}", i),
+                );
+            }
+            let text = match utf8_c_str(text, &format!("Text at index {}", i)) {
+                Ok(text) => text,
+                Err(e) => return e,
+            };
+            messages.push(Message::from_role_and_content(role, text.to_string()));
+        }
+
+        match encoding.render_conversation(&Conversation::from_messages(messages), None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Render developer instructions with function tools, followed by a user message
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_with_tools(
+    wrapper: *const HarmonyEncodingWrapper,
+    developer_instructions: *const c_char,
+    tools_json: *const c_char,
+    user_msg: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if user_msg.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null user message".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let tools = match parse_function_tools_json(tools_json) {
+            Ok(tools) => tools,
+            Err(e) => return e,
+        };
+
+        let mut developer_content = DeveloperContent::new();
+        match optional_c_str(developer_instructions, "Developer instructions") {
+            Ok(Some(instructions)) => {
+                developer_content = developer_content.with_instructions(instructions);
+            }
+            Ok(None) => {}
+            Err(e) => return e,
+        }
+        developer_content = developer_content.with_function_tools(tools);
+
+        let user_text = match utf8_c_str(user_msg, "User message") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let conversation = Conversation::from_messages([
+            Message::from_role_and_content(Role::Developer, developer_content),
+            Message::from_role_and_content(Role::User, user_text.to_string()),
+        ]);
+        match encoding.render_conversation(&conversation, None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Render a full conversation supplied as JSON
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_conversation_json(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    auto_drop_analysis: *const bool,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        match encoding.render_conversation(&conversation, config.as_ref()) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Render a JSON conversation to its harmony text, with the special tokens
+// spelled out, for logging and previews
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_conversation_text(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    next_role: i32,
+    auto_drop_analysis: *const bool,
+    text_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if text_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null text output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let next_role = match role_from_c(next_role) {
+            Ok(role) => role,
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        let rendered = match next_role {
+            Some(role) => {
+                encoding.render_conversation_for_completion(&conversation, role, config.as_ref())
+            }
+            None => encoding.render_conversation(&conversation, config.as_ref()),
+        };
+        let tokens = match rendered {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::RenderFailed,
+                    format!("Failed to render conversation: {}", e),
+                )
+            }
+        };
+
+        match encoding.tokenizer.decode_bytes(&tokens) {
+            Ok(bytes) => write_c_string(String::from_utf8_lossy(&bytes).into_owned(), text_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to decode rendered tokens: {}", e),
+            ),
+        }
+    })
+}
+
+// Render the messages of a JSON conversation that follow an already rendered prefix
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_suffix(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    prefix_messages: usize,
+    auto_drop_analysis: *const bool,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        match encoding.render_conversation_suffix(&conversation, prefix_messages, config.as_ref()) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation suffix: {}", e),
+            ),
+        }
+    })
+}
+
+// Render two JSON conversations and return the tokens the second one appends
+// to the first, failing if the first render is not a prefix of the second
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_delta(
+    wrapper: *const HarmonyEncodingWrapper,
+    prev_conversation_json: *const c_char,
+    new_conversation_json: *const c_char,
+    delta_tokens_out: *mut *mut u32,
+    delta_len_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let prev_conversation = match parse_conversation_json(prev_conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+        let new_conversation = match parse_conversation_json(new_conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let mut renders = Vec::with_capacity(2);
+        for conversation in [&prev_conversation, &new_conversation] {
+            match encoding.render_conversation(conversation, None) {
+                Ok(tokens) => renders.push(tokens),
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::RenderFailed,
+                        format!("Failed to render conversation: {}", e),
+                    )
+                }
+            }
+        }
+        let new_tokens = renders.pop().unwrap();
+        let prev_tokens = renders.pop().unwrap();
+
+        if !new_tokens.starts_with(&prev_tokens) {
+            let index = prev_tokens
+                .iter()
+                .zip(&new_tokens)
+                .position(|(a, b)| a != b)
+                .unwrap_or(new_tokens.len());
+            return HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!(
+                    "Previous render is not a prefix of the new render, they differ at token {}",
+                    index
+                ),
+            );
+        }
+
+        write_tokens(
+            new_tokens[prev_tokens.len()..].to_vec(),
+            delta_tokens_out,
+            delta_len_out,
+        )
+    })
+}
+
+// Render a JSON conversation followed by the header of the next turn
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_for_completion(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    next_role: i32,
+    auto_drop_analysis: *const bool,
+    omit_final_terminator: bool,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let next_role = match role_from_c(next_role) {
+            Ok(Some(role)) => role,
+            Ok(None) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    "Next turn role is required".to_string(),
+                )
+            }
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        let rendered = if omit_final_terminator {
+            encoding.render_conversation_for_continuation(&conversation, next_role, config.as_ref())
+        } else {
+            encoding.render_conversation_for_completion(&conversation, next_role, config.as_ref())
+        };
+        match rendered {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Render a conversation ending in a tool call plus the tool's output, primed for the assistant
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_tool_response(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    tool_output: *const c_char,
+    auto_drop_analysis: *const bool,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tool_output.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null tool output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+        let tool_output = match utf8_c_str(tool_output, "Tool output") {
+            Ok(tool_output) => tool_output,
+            Err(e) => return e,
+        };
+        if HarmonyNextTurn::after(&conversation.messages) != HarmonyNextTurn::Tool {
+            return HarmonyResult::err(
+                HarmonyErrorCode::InvalidArgument,
+                "Conversation does not end in an assistant tool call".to_string(),
+            );
+        }
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        match encoding.render_tool_response_for_completion(
+            &conversation,
+            tool_output,
+            config.as_ref(),
+        ) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Render a JSON conversation for training with a per-token loss mask
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_for_training(
+    wrapper: *const HarmonyEncodingWrapper,
+    conversation_json: *const c_char,
+    auto_drop_analysis: *const bool,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+    mask_out: *mut *mut u8,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if mask_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null mask output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let conversation = match parse_conversation_json(conversation_json) {
+            Ok(conversation) => conversation,
+            Err(e) => return e,
+        };
+
+        let config = render_config_from_c(auto_drop_analysis);
+
+        let (tokens, mask) = match encoding
+            .render_conversation_for_training_with_mask(&conversation, config.as_ref())
+        {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::RenderFailed,
+                    format!("Failed to render conversation: {}", e),
+                )
+            }
+        };
+
+        let result = write_tokens(tokens, tokens_out, tokens_len);
+        if result.success {
+            let mask: Vec<u8> = mask.into_iter().map(u8::from).collect();
+            let (ptr, _) = into_raw_buffer(mask);
+            unsafe {
+                *mask_out = ptr;
+            }
+        }
+        result
+    })
+}
+
+// Decode tokens to text
+#[no_mangle]
+pub extern "C" fn harmony_encoding_decode(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+) -> *mut c_char {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return null_with_error("Null tokens");
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        match encoding.tokenizer.decode_bytes(tokens_slice) {
+            Ok(bytes) => match String::from_utf8(bytes) {
+                Ok(text) => match CString::new(text) {
+                    Ok(c_str) => into_raw_c_string(c_str),
+                    Err(_) => null_with_error("Decoded text contains a NUL byte"),
+                },
+                Err(e) => null_with_error(format!("Decoded bytes are not valid UTF-8: {}", e)),
+            },
+            Err(e) => null_with_error(format!("Failed to decode tokens: {}", e)),
+        }
+    })
+}
+
+// Decode tokens to text, replacing invalid UTF-8 with U+FFFD instead of failing
+#[no_mangle]
+pub extern "C" fn harmony_encoding_decode_lossy(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+) -> *mut c_char {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return null_with_error("Null tokens");
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        match encoding.tokenizer.decode_bytes(tokens_slice) {
+            Ok(bytes) => match CString::new(String::from_utf8_lossy(&bytes).into_owned()) {
+                Ok(c_str) => into_raw_c_string(c_str),
+                Err(_) => null_with_error("Decoded text contains a NUL byte"),
+            },
+            Err(e) => null_with_error(format!("Failed to decode tokens: {}", e)),
+        }
+    })
+}
+
+// Decode tokens to text, skipping ids that are not in the vocabulary
+#[no_mangle]
+pub extern "C" fn harmony_encoding_decode_skip_invalid(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    text_out: *mut *mut c_char,
+    skipped_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if text_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null text output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let mut bytes = Vec::new();
+        let mut skipped = 0;
+        for &token in tokens_slice {
+            match encoding.tokenizer.token_bytes(token) {
+                Some(token_bytes) => bytes.extend_from_slice(token_bytes),
+                None => skipped += 1,
+            }
+        }
+
+        let result = write_c_string(String::from_utf8_lossy(&bytes).into_owned(), text_out);
+        if result.success && !skipped_out.is_null() {
+            unsafe { *skipped_out = skipped };
+        }
+        result
+    })
+}
+
+// Whether decoding tokens and encoding the text again, with special tokens
+// allowed, gives back the same tokens
+#[no_mangle]
+pub extern "C" fn harmony_encoding_stable_roundtrip(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    stable_out: *mut bool,
+    mismatch_index_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if stable_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null stable output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let bytes = match encoding.tokenizer.decode_bytes(tokens_slice) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!("Failed to decode tokens: {}", e),
+                )
+            }
+        };
+        // Invalid UTF-8 cannot survive the round trip; decoding it lossily makes
+        // the re-encoded tokens differ where it starts.
+        let reencoded = encoding
+            .tokenizer
+            .encode_with_special_tokens(&String::from_utf8_lossy(&bytes));
+
+        let common = tokens_slice
+            .iter()
+            .zip(&reencoded)
+            .take_while(|(a, b)| a == b)
+            .count();
+        unsafe {
+            *stable_out = reencoded == tokens_slice;
+            if !mismatch_index_out.is_null() {
+                *mismatch_index_out = common;
+            }
+        }
+        HarmonyResult::ok()
+    })
+}
+
+// Check that the concatenation of `chunks` is valid UTF-8 without copying it,
+// carrying a character split across chunks over to the next one. On failure
+// returns the byte offset where the invalid or incomplete sequence starts.
+fn validate_utf8_chunks<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Result<(), usize> {
+    let mut pending = [0u8; 4];
+    let mut pending_len = 0;
+    let mut offset = 0;
+    for chunk in chunks {
+        let mut data = chunk;
+        while pending_len > 0 && !data.is_empty() {
+            pending[pending_len] = data[0];
+            pending_len += 1;
+            data = &data[1..];
+            match std::str::from_utf8(&pending[..pending_len]) {
+                Ok(_) => {
+                    offset += pending_len;
+                    pending_len = 0;
+                }
+                Err(e) if e.error_len().is_some() || pending_len == pending.len() => {
+                    return Err(offset)
+                }
+                Err(_) => {}
+            }
+        }
+        match std::str::from_utf8(data) {
+            Ok(_) => offset += data.len(),
+            Err(e) if e.error_len().is_some() => return Err(offset + e.valid_up_to()),
+            Err(e) => {
+                let rest = &data[e.valid_up_to()..];
+                offset += e.valid_up_to();
+                pending[..rest.len()].copy_from_slice(rest);
+                pending_len = rest.len();
+            }
+        }
+    }
+    if pending_len > 0 {
+        return Err(offset);
+    }
+    Ok(())
+}
+
+// Decode tokens into a caller-provided buffer without allocating a C string
+#[no_mangle]
+pub extern "C" fn harmony_encoding_decode_into(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    buf: *mut u8,
+    buf_cap: usize,
+    written_out: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if written_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null written output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        // Size the output first so nothing is allocated and the buffer is only
+        // written once the text is known to fit.
+        let mut required = 0;
+        for &token in tokens_slice {
+            match encoding.tokenizer.token_bytes(token) {
+                Some(bytes) => required += bytes.len(),
+                None => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::InvalidArgument,
+                        format!(
+                            "Failed to decode tokens: Invalid token for decoding: {}",
+                            token
+                        ),
+                    )
+                }
+            }
+        }
+
+        unsafe {
+            *written_out = required;
+        }
+        if required > buf_cap {
+            return HarmonyResult::err(
+                HarmonyErrorCode::BufferTooSmall,
+                format!(
+                    "Buffer too small: {} bytes required, {} available",
+                    required, buf_cap
+                ),
+            );
+        }
+        if required == 0 {
+            return HarmonyResult::ok();
+        }
+        if buf.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null buffer".to_string());
+        }
+
+        let token_bytes = || {
+            tokens_slice
+                .iter()
+                .filter_map(|&token| encoding.tokenizer.token_bytes(token))
+        };
+        if let Err(offset) = validate_utf8_chunks(token_bytes()) {
+            unsafe {
+                *written_out = 0;
+            }
+            return HarmonyResult::err(
+                HarmonyErrorCode::InvalidUtf8,
+                format!(
+                    "Decoded bytes are not valid UTF-8: invalid sequence at byte {}",
+                    offset
+                ),
+            );
+        }
+
+        let out = unsafe { std::slice::from_raw_parts_mut(buf, required) };
+        let mut offset = 0;
+        for bytes in token_bytes() {
+            out[offset..offset + bytes.len()].copy_from_slice(bytes);
+            offset += bytes.len();
+        }
+        HarmonyResult::ok()
+    })
+}
+
+// Decode tokens to raw bytes without requiring valid UTF-8
+#[no_mangle]
+pub extern "C" fn harmony_encoding_decode_bytes(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    bytes_out: *mut *mut u8,
+    bytes_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if bytes_out.is_null() || bytes_len.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null bytes output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        match encoding.tokenizer.decode_bytes(tokens_slice) {
+            Ok(bytes) => {
+                let (ptr, len) = into_raw_buffer(bytes);
+                unsafe {
+                    *bytes_len = len;
+                    *bytes_out = ptr;
+                }
+                HarmonyResult::ok()
+            }
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::InvalidArgument,
+                format!("Failed to decode tokens: {}", e),
+            ),
+        }
+    })
+}
+
+// Whether the bytes of `prior_tokens` followed by `token` end on a character
+// boundary, i.e. not inside a multi-byte UTF-8 character
+#[no_mangle]
+pub extern "C" fn harmony_encoding_token_completes_utf8(
+    wrapper: *const HarmonyEncodingWrapper,
+    prior_tokens: *const u32,
+    prior_len: usize,
+    token: u32,
+) -> bool {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if prior_tokens.is_null() && prior_len != 0 {
+            set_last_error("Null tokens".to_string());
+            return false;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let prior = unsafe { c_slice(prior_tokens, prior_len) };
+
+        // A character is at most 4 bytes, so only the last few bytes matter;
+        // collect them walking back from the new token.
+        let mut tail: Vec<u8> = Vec::new();
+        for index in (0..=prior.len()).rev() {
+            let t = prior.get(index).copied().unwrap_or(token);
+            let Some(bytes) = encoding.tokenizer.token_bytes(t) else {
+                let position = if index == prior.len() {
+                    "as the new token".to_string()
+                } else {
+                    format!("at index {}", index)
+                };
+                set_last_error(format!("Unknown token {} {}", t, position));
+                return false;
+            };
+            tail.splice(0..0, bytes.iter().copied());
+            if tail.len() >= 4 {
+                break;
+            }
+        }
+
+        // Find the start of the last character and check that it has all of
+        // its bytes. Bytes that cannot form a character never complete, so
+        // they count as a boundary.
+        let Some(start) = (0..tail.len())
+            .rev()
+            .take(4)
+            .find(|&i| tail[i] & 0xC0 != 0x80)
+        else {
+            return true;
+        };
+        let needed = match tail[start] {
+            b if b < 0x80 => 1,
+            b if b & 0xE0 == 0xC0 => 2,
+            b if b & 0xF0 == 0xE0 => 3,
+            b if b & 0xF8 == 0xF0 => 4,
+            _ => return true,
+        };
+        tail.len() - start >= needed
+    })
+}
+
+// Get stop tokens
+#[no_mangle]
+pub extern "C" fn harmony_encoding_stop_tokens(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        // Get stop tokens from the encoding
+        let stop_tokens = match encoding.stop_tokens() {
+            Ok(tokens) => tokens.into_iter().collect::<Vec<_>>(),
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::RenderFailed,
+                    format!("Failed to get stop tokens: {}", e),
+                )
+            }
+        };
+
+        write_tokens(stop_tokens, tokens_out, tokens_len)
+    })
+}
+
+// The <|end|> token that closes a finished assistant turn in the history
+#[no_mangle]
+pub extern "C" fn harmony_encoding_eot_token(
+    wrapper: *const HarmonyEncodingWrapper,
+    token_out: *mut u32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if token_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null token output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        match encoding.render_formatting_token(FormattingToken::EndMessage) {
+            Ok(token) => {
+                unsafe { *token_out = token };
+                HarmonyResult::ok()
+            }
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to get end-of-turn token: {}", e),
+            ),
+        }
+    })
+}
+
+// Get stop tokens for assistant actions
+#[no_mangle]
+pub extern "C" fn harmony_encoding_stop_tokens_for_assistant_actions(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let stop_tokens = match encoding.stop_tokens_for_assistant_actions() {
+            Ok(tokens) => tokens.into_iter().collect::<Vec<_>>(),
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::RenderFailed,
+                    format!("Failed to get stop tokens: {}", e),
+                )
+            }
+        };
+
+        write_tokens(stop_tokens, tokens_out, tokens_len)
+    })
+}
+
+// Both stop token sets as JSON, each token with its symbolic text
+#[no_mangle]
+pub extern "C" fn harmony_encoding_stop_tokens_json(
+    wrapper: *const HarmonyEncodingWrapper,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let (stop_tokens, action_stop_tokens) = match (
+            encoding.stop_tokens(),
+            encoding.stop_tokens_for_assistant_actions(),
+        ) {
+            (Ok(general), Ok(actions)) => (general, actions),
+            (Err(e), _) | (_, Err(e)) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::RenderFailed,
+                    format!("Failed to get stop tokens: {}", e),
+                )
+            }
+        };
+
+        let describe = |tokens: HashSet<u32>| {
+            let mut tokens: Vec<u32> = tokens.into_iter().collect();
+            tokens.sort_unstable();
+            tokens
+                .into_iter()
+                .map(|token| {
+                    let text = encoding
+                        .tokenizer
+                        .token_bytes(token)
+                        .map(String::from_utf8_lossy)
+                        .unwrap_or_default();
+                    serde_json::json!({"id": token, "text": text})
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let json = serde_json::json!({
+            "stop_tokens": describe(stop_tokens),
+            "stop_tokens_for_assistant_actions": describe(action_stop_tokens),
+        });
+        write_c_string(json.to_string(), json_out)
+    })
+}
+
+// Tokens that begin an assistant message on a channel, up to and including <|message|>
+#[no_mangle]
+pub extern "C" fn harmony_encoding_assistant_header_tokens(
+    wrapper: *const HarmonyEncodingWrapper,
+    channel: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let mut message = Message::from_role_and_content(Role::Assistant, "");
+        match optional_c_str(channel, "Channel") {
+            Ok(Some(channel)) => message = message.with_channel(channel),
+            Ok(None) => {}
+            Err(e) => return e,
+        }
+        match header_tokens(encoding, &message) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render assistant header: {}", e),
+            ),
+        }
+    })
+}
+
+// Render an empty message and drop its <|end|> so the header is exactly what a
+// full render of a message with the same author and channel produces.
+fn header_tokens(encoding: &HarmonyEncoding, message: &Message) -> anyhow::Result<Vec<u32>> {
+    let mut tokens = encoding.render(message, None)?;
+    if let Some(&last) = tokens.last() {
+        if formatting_token_for_rank(encoding, last) == Some(FormattingToken::EndMessage) {
+            tokens.pop();
+        }
+    }
+    Ok(tokens)
+}
+
+// Special-token vocabulary as JSON
+#[no_mangle]
+pub extern "C" fn harmony_encoding_special_tokens_json(
+    wrapper: *const HarmonyEncodingWrapper,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let mut specials: Vec<(&str, u32)> = encoding.tokenizer.special_token_ranks().collect();
+        specials.sort_by_key(|&(_, rank)| rank);
+
+        // Reserved placeholders are reported as inclusive id ranges rather than by name
+        let mut special_tokens = serde_json::Map::new();
+        let mut reserved_ranges: Vec<(u32, u32)> = Vec::new();
+        for (text, rank) in specials {
+            if text.starts_with("<|reserved_") {
+                match reserved_ranges.last_mut() {
+                    Some((_, end)) if *end + 1 == rank => *end = rank,
+                    _ => reserved_ranges.push((rank, rank)),
+                }
+            } else {
+                special_tokens.insert(text.to_string(), rank.into());
+            }
+        }
+
+        let json = serde_json::json!({
+            "special_tokens": special_tokens,
+            "reserved_ranges": reserved_ranges,
+        });
+        write_c_string(json.to_string(), json_out)
+    })
+}
+
+// Each token as `{"id", "text"}` for inspecting how text was chunked. Special
+// tokens show their symbolic form and ordinary tokens their bytes, decoded
+// lossily since a token may hold part of a multi-byte character.
+#[no_mangle]
+pub extern "C" fn harmony_encoding_explain_tokens(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let mut explained = Vec::with_capacity(tokens_slice.len());
+        for (index, &token) in tokens_slice.iter().enumerate() {
+            let Some(bytes) = encoding.tokenizer.token_bytes(token) else {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!("Unknown token {} at index {}", token, index),
+                );
+            };
+            explained.push(serde_json::json!({
+                "id": token,
+                "text": String::from_utf8_lossy(bytes),
+            }));
+        }
+
+        match serde_json::to_string(&explained) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize tokens to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Channels a role's messages may use, as a JSON array of names
+#[no_mangle]
+pub extern "C" fn harmony_encoding_valid_channels(
+    wrapper: *const HarmonyEncodingWrapper,
+    role: i32,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let role = match role_from_c(role) {
+            Ok(Some(role)) => role,
+            Ok(None) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    "Role is required".to_string(),
+                )
+            }
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        // Only assistant messages carry channels; the valid ones are those the
+        // default system message advertises to the model.
+        let channels = match role {
+            Role::Assistant => SystemContent::default()
+                .channel_config
+                .map(|config| config.valid_channels)
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+
+        match serde_json::to_string(&channels) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize channels to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Reasoning effort set by the first system message in the tokens, or -1
+#[no_mangle]
+pub extern "C" fn harmony_encoding_parse_reasoning_effort(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    effort_out: *mut i32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if effort_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null effort output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+        let messages = match parse_completion(encoding, tokens_slice, None) {
+            Ok(messages) => messages,
+            Err((index, e)) => return parse_failed(index, e, ptr::null_mut()),
+        };
+
+        let effort = messages
+            .iter()
+            .find(|message| message.author.role == Role::System)
+            .and_then(system_reasoning_effort);
+        unsafe { *effort_out = reasoning_effort_to_c(effort) };
+        HarmonyResult::ok()
+    })
+}
+
+// Parse completion tokens into messages, returned as a JSON array
+#[no_mangle]
+pub extern "C" fn harmony_encoding_parse_messages(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    role: i32,
+    json_out: *mut *mut c_char,
+    error_out: *mut HarmonyParseError,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        clear_parse_error(error_out);
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let role = match role_from_c(role) {
+            Ok(role) => role,
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        let messages = match parse_completion(encoding, tokens_slice, role) {
+            Ok(messages) => messages,
+            Err((index, e)) => return parse_failed(index, e, error_out),
+        };
+
+        match serde_json::to_string(&messages) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize messages to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Text content of a parsed message
+fn message_text(message: &Message) -> String {
+    message
+        .content
+        .iter()
+        .filter_map(|content| match content {
+            Content::Text(text) => Some(text.text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Parse completion tokens into messages annotated with their byte ranges in the decoded text
+#[no_mangle]
+pub extern "C" fn harmony_encoding_parse_with_spans(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    role: i32,
+    json_out: *mut *mut c_char,
+    error_out: *mut HarmonyParseError,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        clear_parse_error(error_out);
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let role = match role_from_c(role) {
+            Ok(role) => role,
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        let parsed = match parse_completion_with_token_counts(encoding, tokens_slice, role) {
+            Ok(parsed) => parsed,
+            Err((index, e)) => return parse_failed(index, e, error_out),
+        };
+
+        // Byte offset of every token boundary in the decoded text
+        let mut offsets = Vec::with_capacity(tokens_slice.len() + 1);
+        offsets.push(0);
+        for &token in tokens_slice {
+            let len = encoding.tokenizer.token_bytes(token).map_or(0, <[u8]>::len);
+            offsets.push(offsets.last().copied().unwrap_or(0) + len);
+        }
+
+        let mut spans = Vec::with_capacity(parsed.len());
+        let mut start = 0;
+        for (message, count) in &parsed {
+            let end = start + count;
+            let message_tokens = &tokens_slice[start..end];
+            let content_start = message_tokens
+                .iter()
+                .position(|&t| {
+                    formatting_token_for_rank(encoding, t) == Some(FormattingToken::Message)
+                })
+                .map_or(start, |index| start + index + 1);
+            // A message cut off by the end of the tokens has no terminator
+            let terminated = message_tokens.last().is_some_and(|&last| {
+                matches!(
+                    formatting_token_for_rank(encoding, last),
+                    Some(
+                        FormattingToken::EndMessage
+                            | FormattingToken::EndMessageDoneSampling
+                            | FormattingToken::EndMessageAssistantToTool
+                    )
+                )
+            });
+            let content_end = (if terminated { end - 1 } else { end }).max(content_start);
+
+            let mut value = match serde_json::to_value(message) {
+                Ok(value) => value,
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::SerializeFailed,
+                        format!("Failed to serialize messages to JSON: {}", e),
+                    )
+                }
+            };
+            if let Some(object) = value.as_object_mut() {
+                object.insert("text_start".to_string(), offsets[start].into());
+                object.insert("text_end".to_string(), offsets[end].into());
+                object.insert("content_start".to_string(), offsets[content_start].into());
+                object.insert("content_end".to_string(), offsets[content_end].into());
+            }
+            spans.push(value);
+            start = end;
+        }
+
+        match serde_json::to_string(&spans) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize messages to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Parse tokens and read the channel config back from the first system message
+#[no_mangle]
+pub extern "C" fn harmony_encoding_parse_channel_config(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+        unsafe { *json_out = ptr::null_mut() };
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let messages = match parse_completion(encoding, tokens_slice, None) {
+            Ok(messages) => messages,
+            Err((index, e)) => return parse_failed(index, e, ptr::null_mut()),
+        };
+        let config = messages
+            .iter()
+            .find(|message| message.author.role == Role::System)
+            .and_then(|message| ChannelConfig::from_rendered_text(&message_text(message)));
+        let Some(config) = config else {
+            return HarmonyResult::ok();
+        };
+
+        match serde_json::to_string(&config) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize channel config to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Parse completion tokens and count messages and tokens per role and channel
+#[no_mangle]
+pub extern "C" fn harmony_encoding_conversation_stats(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    role: i32,
+    json_out: *mut *mut c_char,
+    error_out: *mut HarmonyParseError,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        clear_parse_error(error_out);
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let role = match role_from_c(role) {
+            Ok(role) => role,
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        let parsed = match parse_completion_with_token_counts(encoding, tokens_slice, role) {
+            Ok(parsed) => parsed,
+            Err((index, e)) => return parse_failed(index, e, error_out),
+        };
+
+        // Groups are listed in the order their first message appears
+        let mut groups: Vec<(&Role, Option<&str>, usize, usize, usize)> = Vec::new();
+        for (message, token_count) in &parsed {
+            let key = (&message.author.role, message.channel.as_deref());
+            let index = match groups.iter().position(|g| (g.0, g.1) == key) {
+                Some(index) => index,
+                None => {
+                    groups.push((key.0, key.1, 0, 0, 0));
+                    groups.len() - 1
+                }
+            };
+            let group = &mut groups[index];
+            group.2 += 1;
+            group.3 +=
+                (message.author.role == Role::Assistant && message.recipient.is_some()) as usize;
+            group.4 += token_count;
+        }
+
+        let stats = serde_json::json!({
+            "messages": parsed.len(),
+            "tokens": parsed.iter().map(|(_, count)| count).sum::<usize>(),
+            "groups": groups
+                .iter()
+                .map(|(role, channel, messages, tool_calls, tokens)| {
+                    serde_json::json!({
+                        "role": role,
+                        "channel": channel,
+                        "messages": messages,
+                        "tool_calls": tool_calls,
+                        "tokens": tokens,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        });
+
+        match serde_json::to_string(&stats) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize conversation stats to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Parse completion tokens and return only the tool calls
+#[no_mangle]
+pub extern "C" fn harmony_encoding_parse_tool_calls(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    json_out: *mut *mut c_char,
+    error_out: *mut HarmonyParseError,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        clear_parse_error(error_out);
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let messages = match parse_completion(encoding, tokens_slice, Some(Role::Assistant)) {
+            Ok(messages) => messages,
+            Err((index, e)) => return parse_failed(index, e, error_out),
+        };
+
+        let tool_calls: Vec<serde_json::Value> = messages
+            .iter()
+            .filter_map(|message| {
+                let recipient = message.recipient.as_ref()?;
+                Some(serde_json::json!({
+                    "recipient": recipient,
+                    "channel": message.channel,
+                    "content": message_text(message),
+                }))
+            })
+            .collect();
+
+        match serde_json::to_string(&tool_calls) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize tool calls to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Parse completion tokens and concatenate the text of every message on a channel
+#[no_mangle]
+pub extern "C" fn harmony_encoding_extract_channel_text(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    channel: *const c_char,
+    text_out: *mut *mut c_char,
+    error_out: *mut HarmonyParseError,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        clear_parse_error(error_out);
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+        if channel.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null channel".to_string());
+        }
+        if text_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null text output".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+        let channel = match utf8_c_str(channel, "Channel") {
+            Ok(channel) => channel,
+            Err(e) => return e,
+        };
+
+        let messages = match parse_completion(encoding, tokens_slice, Some(Role::Assistant)) {
+            Ok(messages) => messages,
+            Err((index, e)) => return parse_failed(index, e, error_out),
+        };
+
+        let text: String = messages
+            .iter()
+            .filter(|message| message.channel.as_deref() == Some(channel))
+            .map(message_text)
+            .collect();
+        write_c_string(text, text_out)
+    })
+}
+
+// Check that completion tokens form well-formed harmony messages
+#[no_mangle]
+pub extern "C" fn harmony_encoding_validate_tokens(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    role: i32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        let role = match role_from_c(role) {
+            Ok(role) => role,
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+        if tokens_slice.is_empty() {
+            return HarmonyResult::ok();
+        }
+
+        let mut parser = match StreamableParser::new(encoding.clone(), role) {
+            Ok(parser) => parser,
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::ParseFailed,
+                    format!("Failed to create parser: {}", e),
+                )
+            }
+        };
+
+        for (index, &token) in tokens_slice.iter().enumerate() {
+            if let Err(e) = parser.process(token) {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::ParseFailed,
+                    format!("Invalid token at index {}: {}", index, e),
+                );
+            }
+        }
+
+        match parser.state() {
+            StreamState::ExpectStart => HarmonyResult::ok(),
+            StreamState::Header { .. } => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Unterminated message header at index {}", tokens_len),
+            ),
+            StreamState::Content { .. } => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Unterminated message at index {}", tokens_len),
+            ),
+        }
+    })
+}
+
+// Remove analysis-channel messages from a token stream, copying the tokens of
+// every other message unchanged so nothing is re-tokenized
+#[no_mangle]
+pub extern "C" fn harmony_encoding_drop_analysis(
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    tokens_out: *mut *mut u32,
+    out_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        match drop_analysis_messages(encoding, tokens_slice) {
+            Ok(kept) => write_tokens(kept, tokens_out, out_len),
+            Err((index, e)) => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Failed to parse messages at token {}: {}", index, e),
+            ),
+        }
+    })
+}
+
+// Each message starts at the token where the parser was expecting <|start|> and
+// ends at the token that completes it, so the messages partition the stream.
+fn drop_analysis_messages(
+    encoding: &HarmonyEncoding,
+    tokens: &[u32],
+) -> Result<Vec<u32>, (usize, anyhow::Error)> {
+    let is_kept = |parser: &StreamableParser, done: usize| {
+        parser.messages().len() > done
+            && parser.messages().last().and_then(|m| m.channel.as_deref()) != Some("analysis")
+    };
+
+    let mut parser = StreamableParser::new(encoding.clone(), None).map_err(|e| (0, e))?;
+    let mut kept = Vec::with_capacity(tokens.len());
+    let mut start = 0;
+    for (index, &token) in tokens.iter().enumerate() {
+        if matches!(parser.state(), StreamState::ExpectStart) {
+            start = index;
+        }
+        let done = parser.messages().len();
+        parser.process(token).map_err(|e| (index, e))?;
+        if is_kept(&parser, done) {
+            kept.extend_from_slice(&tokens[start..=index]);
+        }
+    }
+
+    let done = parser.messages().len();
+    parser.process_eos().map_err(|e| (tokens.len(), e))?;
+    if is_kept(&parser, done) {
+        kept.extend_from_slice(&tokens[start..]);
+    }
+    Ok(kept)
+}
+
+// Streaming parser functions
+#[no_mangle]
+pub extern "C" fn harmony_parser_new(
+    wrapper: *const HarmonyEncodingWrapper,
+    role: i32,
+) -> *mut StreamableParserWrapper {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let role = match role_from_c(role) {
+            Ok(role) => role,
+            Err(e) => return null_with_error(e),
+        };
+
+        match StreamableParser::new(encoding.clone(), role) {
+            Ok(parser) => Box::into_raw(Box::new(StreamableParserWrapper::new(parser))),
+            Err(e) => null_with_error(format!("Failed to create parser: {}", e)),
+        }
+    })
+}
+
+// Create a parser that has already consumed the given tokens
+#[no_mangle]
+pub extern "C" fn harmony_parser_new_with_tokens(
+    wrapper: *const HarmonyEncodingWrapper,
+    role: i32,
+    tokens: *const u32,
+    tokens_len: usize,
+) -> *mut StreamableParserWrapper {
+    ffi_boundary(|| {
+        if tokens.is_null() && tokens_len != 0 {
+            return null_with_error("Null tokens");
+        }
+
+        let wrapper = harmony_parser_new(wrapper, role);
+        if wrapper.is_null() {
+            return wrapper;
+        }
+
+        let parser = unsafe { &mut (*wrapper).parser };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+        for (index, &token) in tokens_slice.iter().enumerate() {
+            if let Err(e) = parser.process(token) {
+                harmony_parser_free(wrapper);
+                return null_with_error(format!("Invalid token at index {}: {}", index, e));
+            }
+        }
+        wrapper
+    })
+}
+
+// Serialize a parser's full state so it can be restored elsewhere
+#[no_mangle]
+pub extern "C" fn harmony_parser_serialize_state(
+    parser: *const StreamableParserWrapper,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+        match parser.snapshot_json() {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize parser state: {}", e),
+            ),
+        }
+    })
+}
+
+// Create a parser from the output of harmony_parser_serialize_state
+#[no_mangle]
+pub extern "C" fn harmony_parser_deserialize_state(
+    wrapper: *const HarmonyEncodingWrapper,
+    json: *const c_char,
+) -> *mut StreamableParserWrapper {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if json.is_null() {
+            return null_with_error("Null parser state JSON");
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let json = match unsafe { CStr::from_ptr(json) }.to_str() {
+            Ok(json) => json,
+            Err(e) => {
+                return null_with_error(format!("Parser state JSON is not valid UTF-8: {}", e))
+            }
+        };
+
+        match StreamableParser::from_snapshot_json(encoding.clone(), json) {
+            Ok(parser) => Box::into_raw(Box::new(StreamableParserWrapper::new(parser))),
+            Err(e) => null_with_error(format!("Invalid parser state: {}", e)),
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_parser_free(parser: *mut StreamableParserWrapper) {
+    ffi_boundary(|| free_handle(parser))
+}
+
+// Reset a parser to the state of a fresh one for the given role
+#[no_mangle]
+pub extern "C" fn harmony_parser_reset(
+    parser: *mut StreamableParserWrapper,
+    role: i32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        let role = match role_from_c(role) {
+            Ok(role) => role,
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+
+        let parser = unsafe { &mut (*parser).parser };
+        parser.reset(role);
+        HarmonyResult::ok()
+    })
+}
+
+// Feed a single token into the parser
+#[no_mangle]
+pub extern "C" fn harmony_parser_process(
+    parser: *mut StreamableParserWrapper,
+    token: u32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        let parser = unsafe { &mut (*parser).parser };
+
+        match parser.process(token) {
+            Ok(_) => HarmonyResult::ok(),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Failed to process token: {}", e),
+            ),
+        }
+    })
+}
+
+// Feed a single token into the parser, treating tokens that break the message
+// structure as content and recording a warning instead of failing
+#[no_mangle]
+pub extern "C" fn harmony_parser_process_lenient(
+    parser: *mut StreamableParserWrapper,
+    token: u32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        let parser = unsafe { &mut (*parser).parser };
+
+        match parser.process_lenient(token) {
+            Ok(_) => HarmonyResult::ok(),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Failed to process token: {}", e),
+            ),
+        }
+    })
+}
+
+// Warnings recorded by harmony_parser_process_lenient as a JSON array of strings
+#[no_mangle]
+pub extern "C" fn harmony_parser_warnings_json(
+    parser: *const StreamableParserWrapper,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+
+        match serde_json::to_string(parser.warnings()) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize warnings to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Receives each content delta from harmony_parser_process_batch. The strings
+// are only valid for the duration of the call.
+pub type HarmonyDeltaCallback =
+    unsafe extern "C" fn(channel: *const c_char, delta: *const c_char, user_data: *mut c_void);
+
+// Feed a batch of tokens into the parser, reporting each content delta
+// through the callback instead of one FFI call per token
+#[no_mangle]
+pub extern "C" fn harmony_parser_process_batch(
+    parser: *mut StreamableParserWrapper,
+    tokens: *const u32,
+    tokens_len: usize,
+    delta_callback: Option<HarmonyDeltaCallback>,
+    user_data: *mut c_void,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if tokens.is_null() && tokens_len != 0 {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null tokens".to_string());
+        }
+
+        let parser = unsafe { &mut (*parser).parser };
+        let tokens_slice = unsafe { c_slice(tokens, tokens_len) };
+
+        for (index, &token) in tokens_slice.iter().enumerate() {
+            if let Err(e) = parser.process(token) {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::ParseFailed,
+                    format!("Failed to process token at index {}: {}", index, e),
+                );
+            }
+
+            let Some(callback) = delta_callback else {
+                continue;
+            };
+            let delta = match parser.last_content_delta() {
+                Ok(Some(delta)) if !delta.is_empty() => delta,
+                _ => continue,
+            };
+            let channel = parser.current_channel().map(CString::new).transpose();
+            let (Ok(channel), Ok(delta)) = (channel, CString::new(delta)) else {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::SerializeFailed,
+                    format!("Content delta at index {} contains a NUL byte", index),
+                );
+            };
+            let channel_ptr = channel
+                .as_ref()
+                .map_or(ptr::null(), |channel| channel.as_ptr());
+            unsafe { callback(channel_ptr, delta.as_ptr(), user_data) };
+        }
+
+        HarmonyResult::ok()
+    })
+}
+
+// What a single processed token did, see harmony_parser_process_event
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HarmonyParserEventKind {
+    None = 0,
+    ContentDelta = 1,
+    ChannelStart = 2,
+    ToolCallStart = 3,
+    MessageEnd = 4,
+    Stop = 5,
+}
+
+// Event reported by harmony_parser_process_event; the strings are owned by the
+// caller and null when not applicable
+#[repr(C)]
+pub struct HarmonyParserEvent {
+    kind: i32,
+    channel: *mut c_char,
+    recipient: *mut c_char,
+    delta: *mut c_char,
+}
+
+impl HarmonyParserEvent {
+    fn none() -> Self {
+        HarmonyParserEvent {
+            kind: HarmonyParserEventKind::None as i32,
+            channel: ptr::null_mut(),
+            recipient: ptr::null_mut(),
+            delta: ptr::null_mut(),
+        }
+    }
+}
+
+// Feed one token into the parser and report what it did as a single event
+#[no_mangle]
+pub extern "C" fn harmony_parser_process_event(
+    parser: *mut StreamableParserWrapper,
+    token: u32,
+    event_out: *mut HarmonyParserEvent,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if event_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null event output".to_string(),
+            );
+        }
+        unsafe { event_out.write(HarmonyParserEvent::none()) };
+
+        let parser = unsafe { &mut (*parser).parser };
+        let messages_before = parser.messages().len();
+        let in_header = matches!(parser.state(), StreamState::Header { .. });
+
+        if let Err(e) = parser.process(token) {
+            return HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Failed to process token: {}", e),
+            );
+        }
+
+        let (kind, channel, recipient, delta) = if parser.messages().len() > messages_before {
+            let message = &parser.messages()[messages_before];
+            let kind = match formatting_token_for_rank(parser.encoding(), token) {
+                Some(
+                    FormattingToken::EndMessageDoneSampling
+                    | FormattingToken::EndMessageAssistantToTool,
+                ) => HarmonyParserEventKind::Stop,
+                _ => HarmonyParserEventKind::MessageEnd,
+            };
+            (
+                kind,
+                message.channel.clone(),
+                message.recipient.clone(),
+                None,
+            )
+        } else if in_header && matches!(parser.state(), StreamState::Content { .. }) {
+            let recipient = parser.current_recipient();
+            let kind = if recipient.is_some() {
+                HarmonyParserEventKind::ToolCallStart
+            } else {
+                HarmonyParserEventKind::ChannelStart
+            };
+            (kind, parser.current_channel(), recipient, None)
+        } else {
+            match parser.last_content_delta() {
+                Ok(Some(delta)) if !delta.is_empty() => (
+                    HarmonyParserEventKind::ContentDelta,
+                    parser.current_channel(),
+                    parser.current_recipient(),
+                    Some(delta),
+                ),
+                _ => return HarmonyResult::ok(),
+            }
+        };
+
+        let strings = (
+            channel.map(CString::new).transpose(),
+            recipient.map(CString::new).transpose(),
+            delta.map(CString::new).transpose(),
+        );
+        let (Ok(channel), Ok(recipient), Ok(delta)) = strings else {
+            return HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                "Event text contains a NUL byte".to_string(),
+            );
+        };
+        let into_raw = |s: Option<CString>| s.map_or(ptr::null_mut(), into_raw_c_string);
+        unsafe {
+            event_out.write(HarmonyParserEvent {
+                kind: kind as i32,
+                channel: into_raw(channel),
+                recipient: into_raw(recipient),
+                delta: into_raw(delta),
+            })
+        };
+        HarmonyResult::ok()
+    })
+}
+
+// Free the strings of an event and reset them to null
+#[no_mangle]
+pub extern "C" fn harmony_parser_event_free(event: *mut HarmonyParserEvent) {
+    ffi_boundary(|| {
+        if event.is_null() {
+            return;
+        }
+        let event = unsafe { &mut *event };
+        for s in [&mut event.channel, &mut event.recipient, &mut event.delta] {
+            harmony_free_string(*s);
+            *s = ptr::null_mut();
+        }
+    })
+}
+
+// Signal end of stream, finalizing any in-progress message
+#[no_mangle]
+pub extern "C" fn harmony_parser_process_eos(
+    parser: *mut StreamableParserWrapper,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        let parser = unsafe { &mut (*parser).parser };
+
+        match parser.process_eos() {
+            Ok(_) => HarmonyResult::ok(),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Failed to process end of stream: {}", e),
+            ),
+        }
+    })
+}
+
+// Text decoded by the most recent process call; empty when the token produced no
+// visible text, null while waiting for the rest of a multi-byte character
+#[no_mangle]
+pub extern "C" fn harmony_parser_last_content_delta(
+    parser: *const StreamableParserWrapper,
+    text_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if text_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null text output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+
+        match parser.last_content_delta() {
+            Ok(Some(delta)) => write_c_string(delta, text_out),
+            Ok(None) if parser.has_undecoded_tokens() => {
+                unsafe {
+                    *text_out = ptr::null_mut();
+                }
+                HarmonyResult::ok()
+            }
+            Ok(None) => write_c_string(String::new(), text_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Failed to get content delta: {}", e),
+            ),
+        }
+    })
+}
+
+// Text of the message being parsed so far; empty when no message is in progress
+#[no_mangle]
+pub extern "C" fn harmony_parser_current_content(
+    parser: *const StreamableParserWrapper,
+    text_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if text_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null text output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+
+        match parser.current_content() {
+            Ok(content) => write_c_string(content, text_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::ParseFailed,
+                format!("Failed to decode current content: {}", e),
+            ),
+        }
+    })
+}
+
+// Copy of every token the parser has consumed
+#[no_mangle]
+pub extern "C" fn harmony_parser_tokens(
+    parser: *const StreamableParserWrapper,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        let parser = unsafe { &(*parser).parser };
+        write_tokens(parser.tokens().to_vec(), tokens_out, tokens_len)
+    })
+}
+
+// Channel of the message being parsed; null until its header has been read
+#[no_mangle]
+pub extern "C" fn harmony_parser_current_channel(
+    parser: *const StreamableParserWrapper,
+    channel_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if channel_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null channel output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+
+        match parser.current_channel() {
+            Some(channel) => write_c_string(channel, channel_out),
+            None => {
+                unsafe {
+                    *channel_out = ptr::null_mut();
+                }
+                HarmonyResult::ok()
+            }
+        }
+    })
+}
+
+// Recipient of the message being parsed; null when unknown or absent
+#[no_mangle]
+pub extern "C" fn harmony_parser_current_recipient(
+    parser: *const StreamableParserWrapper,
+    recipient_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if recipient_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null recipient output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+
+        match parser.current_recipient() {
+            Some(recipient) => write_c_string(recipient, recipient_out),
+            None => {
+                unsafe {
+                    *recipient_out = ptr::null_mut();
+                }
+                HarmonyResult::ok()
+            }
+        }
+    })
+}
+
+// Whether the tool call being parsed already holds a complete JSON object
+#[no_mangle]
+pub extern "C" fn harmony_parser_arguments_complete(
+    parser: *const StreamableParserWrapper,
+) -> bool {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        let parser = unsafe { &(*parser).parser };
+        if parser.current_recipient().is_none() {
+            return false;
+        }
+        // Content ending in the middle of a character does not decode yet.
+        parser.current_content().is_ok_and(|content| {
+            matches!(
+                serde_json::from_str::<serde_json::Value>(&content),
+                Ok(serde_json::Value::Object(_))
+            )
+        })
+    })
+}
+
+// Role of the message being parsed; -1 when it is not known yet
+#[no_mangle]
+pub extern "C" fn harmony_parser_current_role(
+    parser: *const StreamableParserWrapper,
+    role_out: *mut i32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if role_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null role output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+
+        unsafe {
+            *role_out = role_to_c(parser.current_role().as_ref());
+        }
+
+        HarmonyResult::ok()
+    })
+}
+
+// Whether a stop token has been consumed, so nothing further should stream
+#[no_mangle]
+pub extern "C" fn harmony_parser_stopped(parser: *const StreamableParserWrapper) -> bool {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        unsafe { &(*parser).parser }.stopped()
+    })
+}
+
+// Whether the last consumed token completed a message and no new one has started
+#[no_mangle]
+pub extern "C" fn harmony_parser_current_message_complete(
+    parser: *const StreamableParserWrapper,
+) -> bool {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        let parser = unsafe { &(*parser).parser };
+        matches!(parser.state(), StreamState::ExpectStart) && !parser.messages().is_empty()
+    })
+}
+
+// Fully parsed messages as a JSON array
+#[no_mangle]
+pub extern "C" fn harmony_parser_messages_json(
+    parser: *const StreamableParserWrapper,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let parser = unsafe { &(*parser).parser };
+
+        match serde_json::to_string(parser.messages()) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize messages to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Number of fully parsed messages, not counting the one in progress
+#[no_mangle]
+pub extern "C" fn harmony_parser_message_count(parser: *const StreamableParserWrapper) -> usize {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+
+        unsafe { &(*parser).parser }.messages().len()
+    })
+}
+
+// A single fully parsed message as JSON
+#[no_mangle]
+pub extern "C" fn harmony_parser_message_at(
+    parser: *const StreamableParserWrapper,
+    index: usize,
+    json_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(parser) {
+            return e;
+        }
+        if json_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null JSON output".to_string(),
+            );
+        }
+
+        let messages = unsafe { &(*parser).parser }.messages();
+        let Some(message) = messages.get(index) else {
+            return HarmonyResult::err(
+                HarmonyErrorCode::InvalidArgument,
+                format!(
+                    "Message index {} is out of range for {} messages",
+                    index,
+                    messages.len()
+                ),
+            );
+        };
+
+        match serde_json::to_string(message) {
+            Ok(json) => write_c_string(json, json_out),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::SerializeFailed,
+                format!("Failed to serialize message to JSON: {}", e),
+            ),
+        }
+    })
+}
+
+// Incremental decoder functions
+#[no_mangle]
+pub extern "C" fn harmony_decoder_new(
+    wrapper: *const HarmonyEncodingWrapper,
+) -> *mut HarmonyDecoderWrapper {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        Box::into_raw(Box::new(HarmonyDecoderWrapper {
+            tokenizer: Arc::clone(&encoding.tokenizer),
+            pending: Vec::new(),
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_decoder_free(decoder: *mut HarmonyDecoderWrapper) {
+    ffi_boundary(|| {
+        if !decoder.is_null() {
+            unsafe {
+                let _ = Box::from_raw(decoder);
+            }
+        }
+    })
+}
+
+// Decode one token, emitting only the characters it completes
+#[no_mangle]
+pub extern "C" fn harmony_decoder_push(
+    decoder: *mut HarmonyDecoderWrapper,
+    token: u32,
+    text_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if decoder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null decoder".to_string());
+        }
+        if text_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null text output".to_string(),
+            );
+        }
+
+        let decoder = unsafe { &mut *decoder };
+        match decoder.tokenizer.decode_bytes([token]) {
+            Ok(bytes) => decoder.pending.extend(bytes),
+            Err(e) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!("Failed to decode token: {}", e),
+                )
+            }
+        }
+
+        write_c_string(take_complete_utf8(&mut decoder.pending), text_out)
+    })
+}
+
+// System content builder functions
+#[no_mangle]
+pub extern "C" fn harmony_system_content_new() -> *mut SystemContentBuilder {
+    ffi_boundary(|| {
+        Box::into_raw(Box::new(SystemContentBuilder {
+            content: SystemContent::new(),
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_system_content_free(builder: *mut SystemContentBuilder) {
+    ffi_boundary(|| {
+        if !builder.is_null() {
+            unsafe {
+                let _ = Box::from_raw(builder);
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_system_content_set_model_identity(
+    builder: *mut SystemContentBuilder,
+    model_identity: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+
+        let model_identity = match optional_c_str(model_identity, "Model identity") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        let builder = unsafe { &mut *builder };
+        builder.content.model_identity = model_identity.map(str::to_string);
+        HarmonyResult::ok()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_system_content_set_reasoning_effort(
+    builder: *mut SystemContentBuilder,
+    reasoning_effort: i32,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+
+        let builder = unsafe { &mut *builder };
+        match reasoning_effort_from_c(reasoning_effort) {
+            Ok(effort) => {
+                builder.content.reasoning_effort = effort;
+                HarmonyResult::ok()
+            }
+            Err(e) => HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_system_content_set_knowledge_cutoff(
+    builder: *mut SystemContentBuilder,
+    knowledge_cutoff: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+
+        let knowledge_cutoff = match optional_c_str(knowledge_cutoff, "Knowledge cutoff") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        let builder = unsafe { &mut *builder };
+        builder.content.knowledge_cutoff = knowledge_cutoff.map(str::to_string);
+        HarmonyResult::ok()
+    })
+}
+
+// Developer content builder functions
+#[no_mangle]
+pub extern "C" fn harmony_developer_content_new() -> *mut DeveloperContentBuilder {
+    ffi_boundary(|| {
+        Box::into_raw(Box::new(DeveloperContentBuilder {
+            content: DeveloperContent::new(),
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_developer_content_free(builder: *mut DeveloperContentBuilder) {
+    ffi_boundary(|| {
+        if !builder.is_null() {
+            unsafe {
+                let _ = Box::from_raw(builder);
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_developer_content_set_instructions(
+    builder: *mut DeveloperContentBuilder,
+    instructions: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+
+        let instructions = match optional_c_str(instructions, "Instructions") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+        let builder = unsafe { &mut *builder };
+        builder.content.instructions = instructions.map(str::to_string);
+        HarmonyResult::ok()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_developer_content_add_function_tool(
+    builder: *mut DeveloperContentBuilder,
+    name: *const c_char,
+    description: *const c_char,
+    parameters_json: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        harmony_developer_content_add_namespace_tool(
+            builder,
+            c"functions".as_ptr(),
+            name,
+            description,
+            parameters_json,
+        )
+    })
+}
+
+// Add a tool to a named namespace, creating the namespace on first use
+#[no_mangle]
+pub extern "C" fn harmony_developer_content_add_namespace_tool(
+    builder: *mut DeveloperContentBuilder,
+    namespace: *const c_char,
+    name: *const c_char,
+    description: *const c_char,
+    parameters_json: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+        let namespace = match optional_c_str(namespace, "Tool namespace") {
+            Ok(Some(namespace)) => namespace,
+            Ok(None) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    "Tool namespace must not be empty".to_string(),
+                )
+            }
+            Err(e) => return e,
+        };
+        let name = match optional_c_str(name, "Tool name") {
+            Ok(Some(name)) => name,
+            Ok(None) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    "Tool name must not be empty".to_string(),
+                )
+            }
+            Err(e) => return e,
+        };
+
+        let parameters = match optional_c_str(parameters_json, "Parameters JSON") {
+            Ok(Some(json)) => match serde_json::from_str::<serde_json::Value>(json) {
+                Ok(parameters @ serde_json::Value::Object(_)) => Some(parameters),
+                Ok(_) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::InvalidArgument,
+                        format!("Parameters of tool {} must be a JSON object", name),
+                    )
+                }
+                Err(e) => {
+                    return HarmonyResult::err(
+                        HarmonyErrorCode::InvalidArgument,
+                        format!("Invalid parameters JSON for tool {}: {}", name, e),
+                    )
+                }
+            },
+            Ok(None) => None,
+            Err(e) => return e,
+        };
+
+        let description = match optional_c_str(description, "Tool description") {
+            Ok(description) => description.unwrap_or_default(),
+            Err(e) => return e,
+        };
+
+        let builder = unsafe { &mut *builder };
+        let tool = ToolDescription::new(name, description, parameters);
+        builder
+            .content
+            .tools
+            .get_or_insert_with(BTreeMap::new)
+            .entry(namespace.to_string())
+            .or_insert_with(|| ToolNamespaceConfig::new(namespace, None, Vec::new()))
+            .tools
+            .push(tool);
+        HarmonyResult::ok()
+    })
+}
+
+// Name of the builder's tool namespace a recipient such as
+// "functions.get_weather" belongs to; null when no namespace declares it
+#[no_mangle]
+pub extern "C" fn harmony_developer_content_recipient_namespace(
+    builder: *const DeveloperContentBuilder,
+    recipient: *const c_char,
+    namespace_out: *mut *mut c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+        if recipient.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null recipient".to_string());
+        }
+        if namespace_out.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null namespace output".to_string(),
+            );
+        }
+
+        let content = unsafe { &(*builder).content };
+        let recipient = match utf8_c_str(recipient, "Recipient") {
+            Ok(recipient) => recipient,
+            Err(e) => return e,
+        };
+        match content.namespace_for_recipient(recipient) {
+            Some(namespace) => write_c_string(namespace.name.clone(), namespace_out),
+            None => {
+                unsafe {
+                    *namespace_out = ptr::null_mut();
+                }
+                HarmonyResult::ok()
+            }
+        }
+    })
+}
+
+// Check tool call arguments against a tool's parameters schema
+#[no_mangle]
+pub extern "C" fn harmony_validate_tool_arguments(
+    tool_parameters_json: *const c_char,
+    arguments_json: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if tool_parameters_json.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null tool parameters JSON".to_string(),
+            );
+        }
+        if arguments_json.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null tool arguments JSON".to_string(),
+            );
+        }
+
+        let parse = |json: *const c_char, what: &str| -> Result<serde_json::Value, HarmonyResult> {
+            let json = utf8_c_str(json, what)?;
+            serde_json::from_str(json).map_err(|e| {
+                HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    format!("Invalid {}: {}", what.to_lowercase(), e),
+                )
+            })
+        };
+        let parameters = match parse(tool_parameters_json, "Tool parameters JSON") {
+            Ok(parameters) => parameters,
+            Err(e) => return e,
+        };
+        let arguments = match parse(arguments_json, "Tool arguments JSON") {
+            Ok(arguments) => arguments,
+            Err(e) => return e,
+        };
+
+        match validate_tool_arguments(&parameters, &arguments) {
+            Ok(()) => HarmonyResult::ok(),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::InvalidArgument,
+                format!("Invalid tool arguments: {}", e),
+            ),
+        }
+    })
+}
+
+// Render the system message described by a builder
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_system_content(
+    wrapper: *const HarmonyEncodingWrapper,
+    builder: *const SystemContentBuilder,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let content = unsafe { (*builder).content.clone() };
+        let message = Message::from_role_and_content(Role::System, content);
+        match encoding.render(&message, None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render system message: {}", e),
+            ),
+        }
+    })
+}
+
+// Render the developer message described by a builder on its own
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_developer(
+    wrapper: *const HarmonyEncodingWrapper,
+    builder: *const DeveloperContentBuilder,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if builder.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null builder".to_string());
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let content = unsafe { (*builder).content.clone() };
+        let message = Message::from_role_and_content(Role::Developer, content);
+        match encoding.render(&message, None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render developer message: {}", e),
+            ),
+        }
+    })
+}
+
+// Render a prompt from optional system and developer builders and a user message
+#[no_mangle]
+pub extern "C" fn harmony_encoding_render_prompt_with_builders(
+    wrapper: *const HarmonyEncodingWrapper,
+    system: *const SystemContentBuilder,
+    developer: *const DeveloperContentBuilder,
+    user_msg: *const c_char,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+        if user_msg.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null user message".to_string(),
+            );
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let user_text = match utf8_c_str(user_msg, "User message") {
+            Ok(text) => text,
+            Err(e) => return e,
+        };
+
+        let mut messages = Vec::new();
+        if !system.is_null() {
+            let content = unsafe { (*system).content.clone() };
+            messages.push(Message::from_role_and_content(Role::System, content));
+        }
+        if !developer.is_null() {
+            let content = unsafe { (*developer).content.clone() };
+            messages.push(Message::from_role_and_content(Role::Developer, content));
+        }
+        messages.push(Message::from_role_and_content(Role::User, user_text));
+
+        let conversation = Conversation::from_messages(messages);
+        match encoding.render_conversation(&conversation, None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+// Conversation builder functions
+#[no_mangle]
+pub extern "C" fn harmony_conversation_new() -> *mut ConversationBuilder {
+    ffi_boundary(|| {
+        Box::into_raw(Box::new(ConversationBuilder {
+            messages: Vec::new(),
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn harmony_conversation_free(conversation: *mut ConversationBuilder) {
+    ffi_boundary(|| {
+        if !conversation.is_null() {
+            unsafe {
+                let _ = Box::from_raw(conversation);
+            }
+        }
+    })
+}
+
+// Append a text message to a conversation
+#[no_mangle]
+pub extern "C" fn harmony_conversation_add_message(
+    conversation: *mut ConversationBuilder,
+    role: i32,
+    channel: *const c_char,
+    recipient: *const c_char,
+    content: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        harmony_conversation_add_message_with_author(
+            conversation,
+            role,
+            ptr::null(),
+            channel,
+            recipient,
+            content,
+        )
+    })
+}
+
+// Append a text message with an author name, e.g. the tool a result comes from
+#[no_mangle]
+pub extern "C" fn harmony_conversation_add_message_with_author(
+    conversation: *mut ConversationBuilder,
+    role: i32,
+    name: *const c_char,
+    channel: *const c_char,
+    recipient: *const c_char,
+    content: *const c_char,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if conversation.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null conversation".to_string(),
+            );
+        }
+        if content.is_null() {
+            return HarmonyResult::err(HarmonyErrorCode::NullPointer, "Null content".to_string());
+        }
+
+        let role = match role_from_c(role) {
+            Ok(Some(role)) => role,
+            Ok(None) => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    "A message needs a role".to_string(),
+                )
+            }
+            Err(e) => return HarmonyResult::err(HarmonyErrorCode::InvalidArgument, e),
+        };
+        let content = match utf8_c_str(content, "Content") {
+            Ok(content) => content,
+            Err(e) => return e,
+        };
+
+        let author = match optional_c_str(name, "Author name") {
+            Ok(Some(name)) => Author::new(role, name),
+            Ok(None) if role == Role::Tool => {
+                return HarmonyResult::err(
+                    HarmonyErrorCode::InvalidArgument,
+                    "A tool message needs the tool's name as author".to_string(),
+                )
+            }
+            Ok(None) => Author::from(role),
+            Err(e) => return e,
+        };
+        let mut message = Message::from_author_and_content(author, content);
+        match optional_c_str(channel, "Channel") {
+            Ok(Some(channel)) => message = message.with_channel(channel),
+            Ok(None) => {}
+            Err(e) => return e,
+        }
+        match optional_c_str(recipient, "Recipient") {
+            Ok(Some(recipient)) => message = message.with_recipient(recipient),
+            Ok(None) => {}
+            Err(e) => return e,
+        }
+        let conversation = unsafe { &mut *conversation };
+        conversation.messages.push(message);
+        HarmonyResult::ok()
+    })
+}
+
+// Render the messages added to a conversation builder
+#[no_mangle]
+pub extern "C" fn harmony_conversation_render(
+    conversation: *const ConversationBuilder,
+    wrapper: *const HarmonyEncodingWrapper,
+    tokens_out: *mut *mut u32,
+    tokens_len: *mut usize,
+) -> HarmonyResult {
+    ffi_boundary(|| {
+        if conversation.is_null() {
+            return HarmonyResult::err(
+                HarmonyErrorCode::NullPointer,
+                "Null conversation".to_string(),
+            );
+        }
+        if let Err(e) = check_handle(wrapper) {
+            return e;
+        }
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let messages = unsafe { &(*conversation).messages };
+        match encoding.render_conversation(messages.iter(), None) {
+            Ok(tokens) => write_tokens(tokens, tokens_out, tokens_len),
+            Err(e) => HarmonyResult::err(
+                HarmonyErrorCode::RenderFailed,
+                format!("Failed to render conversation: {}", e),
+            ),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn new_encoding() -> *mut HarmonyEncodingWrapper {
+        let wrapper = harmony_encoding_new();
+        assert!(!wrapper.is_null());
+        wrapper
+    }
+
+    fn encode_with_special(wrapper: *const HarmonyEncodingWrapper, text: &str) -> Vec<u32> {
+        let encoding = unsafe { &(*wrapper).encoding };
+        encoding.tokenizer().encode_with_special_tokens(text)
+    }
+
+    #[test]
+    fn test_parser_process() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Thinking.<|end|><|start|>assistant<|channel|>final<|message|>Done.<|return|>",
+        );
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        assert!(!parser.is_null());
+        for token in tokens {
+            assert!(harmony_parser_process(parser, token).success);
+        }
+        let mut json = ptr::null_mut();
+        assert!(harmony_parser_messages_json(parser, &mut json).success);
+        let messages: Vec<Message> = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(
+            messages,
+            [
+                Message::from_role_and_content(Role::Assistant, "Thinking.")
+                    .with_channel("analysis"),
+                Message::from_role_and_content(Role::Assistant, "Done.").with_channel("final"),
+            ]
+        );
+
+        // between messages only a start token is valid
+        let end = encode_with_special(wrapper, "<|end|>")[0];
+        let result = harmony_parser_process(parser, end);
+        assert!(!result.success);
+        assert_eq!(result.error_code, HarmonyErrorCode::ParseFailed as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    fn take_c_string(s: *mut c_char) -> Option<String> {
+        if s.is_null() {
+            return None;
+        }
+        let text = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        harmony_free_string(s);
+        Some(text)
+    }
+
+    #[test]
+    fn test_parser_tracks_channel_and_role() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|start|>assistant<|channel|>analysis<|message|>Hmm<|end|><|start|>assistant<|channel|>final<|message|>Hi",
+        );
+        let parser = harmony_parser_new(wrapper, -1);
+        let mut channels = Vec::new();
+        for token in tokens {
+            assert!(harmony_parser_process(parser, token).success);
+            let mut channel = ptr::null_mut();
+            assert!(harmony_parser_current_channel(parser, &mut channel).success);
+            let channel = take_c_string(channel);
+            if channels.last() != Some(&channel) {
+                channels.push(channel);
+            }
+        }
+        assert_eq!(
+            channels,
+            [
+                None,
+                Some("analysis".to_string()),
+                None,
+                Some("final".to_string())
+            ]
+        );
+
+        let mut role = -1;
+        assert!(harmony_parser_current_role(parser, &mut role).success);
+        assert_eq!(role, HarmonyRole::Assistant as i32);
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    fn take_tokens(tokens: *mut u32, len: usize) -> Vec<u32> {
+        let out = unsafe { std::slice::from_raw_parts(tokens, len) }.to_vec();
+        harmony_free_tokens(tokens, len);
+        out
+    }
+
+    #[test]
+    fn test_render_prompt_with_developer_message() {
+        let wrapper = new_encoding();
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_prompt_v2(
+            wrapper,
+            c"You are a robot.".as_ptr(),
+            -1,
+            ptr::null(),
+            ptr::null(),
+            c"Answer in binary.".as_ptr(),
+            c"What is 2 + 2?".as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let expected = encoding
+            .render_conversation(
+                &Conversation::from_messages([
+                    Message::from_role_and_content(
+                        Role::System,
+                        SystemContent::new().with_model_identity("You are a robot."),
+                    ),
+                    Message::from_role_and_content(
+                        Role::Developer,
+                        DeveloperContent::new().with_instructions("Answer in binary."),
+                    ),
+                    Message::from_role_and_content(Role::User, "What is 2 + 2?"),
+                ]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(take_tokens(tokens, len), expected);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_prompt_reasoning_effort() {
+        let wrapper = new_encoding();
+        let render = |effort: i32| {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result = harmony_encoding_render_prompt_v2(
+                wrapper,
+                ptr::null(),
+                effort,
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                c"Hi".as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                &mut tokens,
+                &mut len,
+            );
+            if result.success {
+                Some(take_tokens(tokens, len))
+            } else {
+                assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+                harmony_free_string(result.error_message);
+                None
+            }
+        };
+
+        let low = render(0).unwrap();
+        let high = render(2).unwrap();
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert!(encoding
+            .tokenizer()
+            .decode_utf8(&low)
+            .unwrap()
+            .contains("Reasoning: low"));
+        assert!(encoding
+            .tokenizer()
+            .decode_utf8(&high)
+            .unwrap()
+            .contains("Reasoning: high"));
+        assert_eq!(render(3), None);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    fn render_json(wrapper: *const HarmonyEncodingWrapper, json: &str) -> Result<Vec<u32>, String> {
+        let json = CString::new(json).unwrap();
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_conversation_json(
+            wrapper,
+            json.as_ptr(),
+            ptr::null(),
+            &mut tokens,
+            &mut len,
+        );
+        if result.success {
+            Ok(take_tokens(tokens, len))
+        } else {
+            Err(take_c_string(result.error_message).unwrap())
+        }
+    }
+
+    #[test]
+    fn test_render_conversation_json() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+
+        let tokens = render_json(
+            wrapper,
+            r#"{"messages": [
+                {"role": "user", "content": "What is 2 + 2?"},
+                {"role": "assistant", "channel": "final", "content": "4"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            encoding.tokenizer().decode_utf8(&tokens).unwrap(),
+            "<|start|>user<|message|>What is 2 + 2?<|end|><|start|>assistant<|channel|>final<|message|>4<|end|>"
+        );
+
+        let json = CString::new(
+            r#"{"messages": [{"role": "user", "content": "What is 2 + 2?"}, {"role": "assistant", "channel": "final", "content": "4"}]}"#,
+        )
+        .unwrap();
+        let mut count = 0;
+        assert!(
+            harmony_encoding_count_conversation_tokens(
+                wrapper,
+                json.as_ptr(),
+                ptr::null(),
+                &mut count
+            )
+            .success
+        );
+        assert_eq!(count, tokens.len());
+
+        let err = render_json(wrapper, r#"{"messages": [{"role": "narrator"}]}"#).unwrap_err();
+        assert!(err.starts_with("Invalid conversation JSON"), "{err}");
+
+        harmony_encoding_free(wrapper);
+    }
+
+    fn load_test_data(name: &str) -> String {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join(name);
+        std::fs::read_to_string(path)
+            .unwrap()
+            .replace("\r\n", "\n")
+            .trim_end()
+            .to_string()
+    }
+
+    #[test]
+    fn test_render_for_completion_drops_analysis() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let json = CString::new(
+            serde_json::json!({"messages": [
+                {"role": "user", "content": "What is 2 + 2?"},
+                {"role": "assistant", "channel": "analysis", "content": "User asks: “What is 2 + 2?” Simple arithmetic. Provide answer."},
+                {"role": "assistant", "channel": "final", "content": "2 + 2 equals 4."},
+                {"role": "user", "content": "What about 9 / 2?"}
+            ]})
+            .to_string(),
+        )
+        .unwrap();
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_for_completion(
+            wrapper,
+            json.as_ptr(),
+            HarmonyRole::Assistant as i32,
+            &true,
+            false,
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        assert_eq!(
+            encoding
+                .tokenizer()
+                .decode_utf8(take_tokens(tokens, len))
+                .unwrap(),
+            load_test_data("test_dropping_cot_by_default.txt")
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_concurrent_encode_plain() {
+        let wrapper = new_encoding();
+        let texts = [
+            "The quick brown fox jumps over the lazy dog.",
+            "Größenwahn ist eine Krankheit 🦀",
+            "fn main() { println!(\"hello\"); }",
+        ];
+        let encode = |wrapper: &HarmonyEncodingWrapper, text: &CStr| {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            assert!(
+                harmony_encoding_encode_plain(wrapper, text.as_ptr(), &mut tokens, &mut len)
+                    .success
+            );
+            take_tokens(tokens, len)
+        };
+        let texts: Vec<CString> = texts.iter().map(|t| CString::new(*t).unwrap()).collect();
+        let shared = unsafe { &*wrapper };
+        let expected: Vec<Vec<u32>> = texts.iter().map(|t| encode(shared, t)).collect();
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        for (text, expected) in texts.iter().zip(&expected) {
+                            assert_eq!(&encode(shared, text), expected);
+                        }
+                    }
+                });
+            }
+        });
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_plain_batch() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let texts: Vec<CString> = (0..100)
+            .map(|i| CString::new(format!("document number {i}")).unwrap())
+            .chain([CString::new("").unwrap()])
+            .collect();
+        let text_ptrs: Vec<*const c_char> = texts.iter().map(|t| t.as_ptr()).collect();
+
+        let mut tokens = ptr::null_mut();
+        let mut offsets = ptr::null_mut();
+        let mut total_len = 0;
+        let result = harmony_encoding_encode_plain_batch(
+            wrapper,
+            text_ptrs.as_ptr(),
+            text_ptrs.len(),
+            0,
+            &mut tokens,
+            &mut offsets,
+            &mut total_len,
+        );
+        assert!(result.success);
+
+        let offsets_slice = unsafe { std::slice::from_raw_parts(offsets, texts.len() + 1) };
+        let tokens_slice = unsafe { std::slice::from_raw_parts(tokens, total_len) };
+        assert_eq!(offsets_slice[texts.len()], total_len);
+        for (i, text) in texts.iter().enumerate() {
+            assert_eq!(
+                &tokens_slice[offsets_slice[i]..offsets_slice[i + 1]],
+                encoding
+                    .tokenizer()
+                    .encode_ordinary(text.to_str().unwrap())
+                    .as_slice()
+            );
+        }
+
+        harmony_free_tokens(tokens, total_len);
+        harmony_free_offsets(offsets, texts.len() + 1);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_special_tokens_json() {
+        let wrapper = new_encoding();
+        let mut json = ptr::null_mut();
+        assert!(harmony_encoding_special_tokens_json(wrapper, &mut json).success);
+        let json: serde_json::Value = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(json["special_tokens"]["<|start|>"], 200006);
+        assert_eq!(json["special_tokens"]["<|call|>"], 200012);
+        assert_eq!(
+            json["reserved_ranges"],
+            serde_json::json!([
+                [200000, 200001],
+                [200004, 200004],
+                [200009, 200011],
+                [200013, 201088]
+            ])
+        );
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_with_special_allowed_set() {
+        let wrapper = new_encoding();
+        let text = CString::new("<|start|>user<|message|>Hi<|end|>").unwrap();
+        let encode = |allowed: Option<&str>| {
+            let allowed = allowed.map(|json| CString::new(json).unwrap());
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result = harmony_encoding_encode_with_special(
+                wrapper,
+                text.as_ptr(),
+                allowed.as_ref().map_or(ptr::null(), |json| json.as_ptr()),
+                0,
+                &mut tokens,
+                &mut len,
+            );
+            assert!(result.success, "error code {}", result.error_code);
+            take_tokens(tokens, len)
+        };
+
+        let all = encode(None);
+        assert_eq!(
+            all,
+            encode_with_special(wrapper, "<|start|>user<|message|>Hi<|end|>")
+        );
+
+        let start = encode_with_special(wrapper, "<|start|>")[0];
+        let end = encode_with_special(wrapper, "<|end|>")[0];
+        let partial = encode(Some(r#"["<|start|>"]"#));
+        assert_eq!(partial[0], start);
+        assert!(!partial.contains(&end));
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let unknown = CString::new(r#"["<|bogus|>"]"#).unwrap();
+        let result = harmony_encoding_encode_with_special(
+            wrapper,
+            text.as_ptr(),
+            unknown.as_ptr(),
+            0,
+            &mut tokens,
+            &mut len,
+        );
+        assert!(!result.success);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        take_c_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_reset_between_generations() {
+        let wrapper = new_encoding();
+        let first = encode_with_special(wrapper, "<|channel|>final<|message|>First.<|return|>");
+        let second = encode_with_special(wrapper, "<|channel|>analysis<|message|>Second.<|end|>");
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        assert!(!parser.is_null());
+
+        let messages_json = |parser| {
+            let mut json = ptr::null_mut();
+            assert!(harmony_parser_messages_json(parser, &mut json).success);
+            take_c_string(json).unwrap()
+        };
+
+        for &token in &first[..3] {
+            assert!(harmony_parser_process(parser, token).success);
+        }
+        assert!(harmony_parser_reset(parser, HarmonyRole::Assistant as i32).success);
+        for &token in &second {
+            assert!(harmony_parser_process(parser, token).success);
+        }
+        let after_partial = messages_json(parser);
+
+        assert!(harmony_parser_reset(parser, HarmonyRole::Assistant as i32).success);
+        for &token in &first {
+            assert!(harmony_parser_process(parser, token).success);
+        }
+        assert!(harmony_parser_reset(parser, HarmonyRole::Assistant as i32).success);
+        for &token in &second {
+            assert!(harmony_parser_process(parser, token).success);
+        }
+        assert_eq!(messages_json(parser), after_partial);
+
+        let fresh = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        for &token in &second {
+            assert!(harmony_parser_process(fresh, token).success);
+        }
+        assert_eq!(messages_json(fresh), after_partial);
+        assert!(after_partial.contains("Second."));
+        assert!(!after_partial.contains("First."));
+
+        assert!(harmony_parser_reset(parser, -1).success);
+        assert_eq!(messages_json(parser), "[]");
+        let result = harmony_parser_reset(parser, 42);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        take_c_string(result.error_message);
+
+        harmony_parser_free(fresh);
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_with_tools_matches_developer_content() {
+        let wrapper = new_encoding();
+        let instructions = CString::new("Use the tools.").unwrap();
+        let tools = CString::new(
+            r#"[{"name": "get_weather", "description": "Gets the weather.",
+                 "parameters": {"type": "object",
+                                "properties": {"city": {"type": "string"}},
+                                "required": ["city"]}},
+                {"name": "ping"}]"#,
+        )
+        .unwrap();
+        let user = CString::new("Weather in Paris?").unwrap();
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_with_tools(
+            wrapper,
+            instructions.as_ptr(),
+            tools.as_ptr(),
+            user.as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        let tokens = take_tokens(tokens, len);
+
+        let developer = DeveloperContent::new()
+            .with_instructions("Use the tools.")
+            .with_function_tools(vec![
+                ToolDescription::new(
+                    "get_weather",
+                    "Gets the weather.",
+                    Some(serde_json::json!({
+                        "type": "object",
+                        "properties": {"city": {"type": "string"}},
+                        "required": ["city"],
+                    })),
+                ),
+                ToolDescription::new("ping", "", None),
+            ]);
+        let conversation = Conversation::from_messages([
+            Message::from_role_and_content(Role::Developer, developer),
+            Message::from_role_and_content(Role::User, "Weather in Paris?"),
+        ]);
+        let encoding = unsafe { &(*wrapper).encoding };
+        let expected = encoding.render_conversation(&conversation, None).unwrap();
+        assert_eq!(tokens, expected);
+        let text = encoding.tokenizer().decode_utf8(&tokens).unwrap();
+        assert!(text.contains("namespace functions {"));
+        assert!(text.contains("type get_weather = (_: {"));
+
+        let nameless = CString::new(r#"[{"description": "No name"}]"#).unwrap();
+        let mut tokens = ptr::null_mut();
+        let result = harmony_encoding_render_with_tools(
+            wrapper,
+            ptr::null(),
+            nameless.as_ptr(),
+            user.as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(!result.success);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        assert_eq!(
+            take_c_string(result.error_message).as_deref(),
+            Some("Tool 0 is missing a name")
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parse_tool_calls() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Need the weather.<|end|>\
+             <|start|>assistant<|channel|>commentary to=functions.get_weather <|constrain|>json\
+             <|message|>{\"city\": \"<|Paris|>\"}<|call|>",
+        );
+
+        let mut json = ptr::null_mut();
+        let result = harmony_encoding_parse_tool_calls(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            &mut json,
+            ptr::null_mut(),
+        );
+        assert!(result.success);
+        let calls: serde_json::Value = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(
+            calls,
+            serde_json::json!([{
+                "recipient": "functions.get_weather",
+                "channel": "commentary",
+                "content": "{\"city\": \"<|Paris|>\"}",
+            }])
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_prompt_knowledge_cutoff_and_date() {
+        let wrapper = new_encoding();
+        let render = |cutoff: *const c_char, date: *const c_char| {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result = harmony_encoding_render_prompt_v2(
+                wrapper,
+                ptr::null(),
+                -1,
+                cutoff,
+                date,
+                ptr::null(),
+                c"Hi".as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                &mut tokens,
+                &mut len,
+            );
+            assert!(result.success);
+            let tokens = take_tokens(tokens, len);
+            let encoding = unsafe { &(*wrapper).encoding };
+            encoding.tokenizer().decode_utf8(&tokens).unwrap()
+        };
+
+        let text = render(c"2025-01".as_ptr(), c"2025-08-05".as_ptr());
+        assert!(text.contains("Knowledge cutoff: 2025-01"));
+        assert!(text.contains("Current date: 2025-08-05"));
+
+        let default_cutoff = render(c"".as_ptr(), c"2025-08-05".as_ptr());
+        assert!(default_cutoff.contains("Knowledge cutoff: 2024-06"));
+        assert!(default_cutoff.contains("Current date: 2025-08-05"));
+
+        let no_system = render(c"".as_ptr(), ptr::null());
+        assert!(!no_system.contains("<|start|>system"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_decoder_buffers_partial_codepoints() {
+        let wrapper = new_encoding();
+        let text = "naïve 日本語 🎉!";
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens = encoding.tokenizer().encode_ordinary(text);
+        assert!(tokens
+            .iter()
+            .any(|&token| encoding.tokenizer().decode_utf8([token]).is_err()));
+
+        let decoder = harmony_decoder_new(wrapper);
+        assert!(!decoder.is_null());
+        let mut decoded = String::new();
+        for token in tokens {
+            let mut out = ptr::null_mut();
+            assert!(harmony_decoder_push(decoder, token, &mut out).success);
+            decoded.push_str(&take_c_string(out).unwrap());
+        }
+        assert_eq!(decoded, text);
+
+        harmony_decoder_free(decoder);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_take_complete_utf8_replaces_invalid_bytes() {
+        let mut pending = vec![b'a', 0xFF, b'b', 0xE6, 0x97];
+        assert_eq!(take_complete_utf8(&mut pending), "a\u{FFFD}b");
+        assert_eq!(pending, [0xE6, 0x97]);
+        pending.push(0xA5);
+        assert_eq!(take_complete_utf8(&mut pending), "日");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_render_for_training_mask() {
+        let wrapper = new_encoding();
+        let json = CString::new(
+            r#"{"messages": [
+                {"role": "user", "content": "What is 2 + 2?"},
+                {"role": "assistant", "channel": "analysis", "content": "Simple."},
+                {"role": "assistant", "channel": "final", "content": "4"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let mut mask = ptr::null_mut();
+        let result = harmony_encoding_render_for_training(
+            wrapper,
+            json.as_ptr(),
+            ptr::null(),
+            &mut tokens,
+            &mut len,
+            &mut mask,
+        );
+        assert!(result.success);
+        let mask_vec = unsafe { std::slice::from_raw_parts(mask, len) }.to_vec();
+        harmony_free_mask(mask, len);
+        let tokens = take_tokens(tokens, len);
+
+        let encoding = unsafe { &(*wrapper).encoding };
+        let conversation: Conversation = serde_json::from_str(json.to_str().unwrap()).unwrap();
+        assert_eq!(
+            tokens,
+            encoding
+                .render_conversation_for_training(&conversation, None)
+                .unwrap()
+        );
+
+        let trained: Vec<u32> = tokens
+            .iter()
+            .zip(&mask_vec)
+            .filter(|(_, &m)| m == 1)
+            .map(|(&t, _)| t)
+            .collect();
+        assert_eq!(
+            encoding.tokenizer().decode_utf8(&trained).unwrap(),
+            "<|channel|>analysis<|message|>Simple.<|end|><|channel|>final<|message|>4<|return|>"
+        );
+        assert!(mask_vec.iter().all(|&m| m <= 1));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_validate_tokens_reports_index() {
+        let wrapper = new_encoding();
+        let validate = |text: &str| {
+            let tokens = encode_with_special(wrapper, text);
+            let result = harmony_encoding_validate_tokens(
+                wrapper,
+                tokens.as_ptr(),
+                tokens.len(),
+                HarmonyRole::Assistant as i32,
+            );
+            (result.error_code, take_c_string(result.error_message))
+        };
+
+        assert_eq!(
+            validate("<|channel|>final<|message|>Hi.<|end|><|start|>user<|message|>Ok<|end|>"),
+            (HarmonyErrorCode::Ok as i32, None)
+        );
+
+        let complete = "<|channel|>final<|message|>Hi.<|end|>";
+        let complete_len = encode_with_special(wrapper, complete).len();
+        let (code, message) = validate(&format!("{complete}oops"));
+        assert_eq!(code, HarmonyErrorCode::ParseFailed as i32);
+        let expected = format!("Invalid token at index {complete_len}:");
+        assert!(message.unwrap().starts_with(&expected));
+
+        let unterminated = "<|channel|>final<|message|>Hi.";
+        let unterminated_len = encode_with_special(wrapper, unterminated).len();
+        let (code, message) = validate(unterminated);
+        assert_eq!(code, HarmonyErrorCode::ParseFailed as i32);
+        assert_eq!(
+            message,
+            Some(format!("Unterminated message at index {unterminated_len}"))
+        );
+
+        let (code, message) = validate("<|channel|><|message|>Hi.<|end|>");
+        assert_eq!(code, HarmonyErrorCode::ParseFailed as i32);
+        assert!(message.unwrap().starts_with("Invalid token at index 1:"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encoding_new_by_name() {
+        let wrapper = harmony_encoding_new_by_name(c"HarmonyGptOss".as_ptr());
+        assert!(!wrapper.is_null());
+        assert_eq!(unsafe { &(*wrapper).encoding }.name(), "HarmonyGptOss");
+        harmony_encoding_free(wrapper);
+
+        assert!(harmony_encoding_new_by_name(c"NoSuchEncoding".as_ptr()).is_null());
+        assert!(harmony_encoding_new_by_name(ptr::null()).is_null());
+    }
+
+    #[test]
+    fn test_last_error_message() {
+        harmony_clear_last_error();
+        assert!(harmony_last_error_message().is_null());
+
+        let wrapper = new_encoding();
+        assert!(harmony_parser_new(wrapper, 42).is_null());
+        let message = unsafe { CStr::from_ptr(harmony_last_error_message()) };
+        assert_eq!(message.to_str().unwrap(), "Invalid role: 42");
+
+        let result =
+            harmony_encoding_encode_plain(wrapper, ptr::null(), ptr::null_mut(), ptr::null_mut());
+        let returned = take_c_string(result.error_message).unwrap();
+        let message = unsafe { CStr::from_ptr(harmony_last_error_message()) };
+        assert_eq!(message.to_str().unwrap(), returned);
+
+        let other_thread = std::thread::spawn(|| harmony_last_error_message().is_null());
+        assert!(other_thread.join().unwrap());
+
+        harmony_clear_last_error();
+        assert!(harmony_last_error_message().is_null());
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_prompt_assistant_channel_and_recipient() {
+        let wrapper = new_encoding();
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_prompt_v2(
+            wrapper,
+            ptr::null(),
+            -1,
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+            c"Weather in Paris?".as_ptr(),
+            c"{\"city\": \"Paris\"}".as_ptr(),
+            c"commentary".as_ptr(),
+            c"functions.get_weather".as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        let tokens = take_tokens(tokens, len);
+
+        let conversation = Conversation::from_messages([
+            Message::from_role_and_content(Role::User, "Weather in Paris?"),
+            Message::from_role_and_content(Role::Assistant, "{\"city\": \"Paris\"}")
+                .with_channel("commentary")
+                .with_recipient("functions.get_weather"),
+        ]);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(
+            tokens,
+            encoding.render_conversation(&conversation, None).unwrap()
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_decode_bytes_keeps_partial_utf8() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens = encoding.tokenizer().encode_ordinary("🎉");
+        let partial = &tokens[..1];
+        assert!(harmony_encoding_decode(wrapper, partial.as_ptr(), partial.len()).is_null());
+
+        let mut bytes = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_decode_bytes(
+            wrapper,
+            partial.as_ptr(),
+            partial.len(),
+            &mut bytes,
+            &mut len,
+        );
+        assert!(result.success);
+        let partial_bytes = unsafe { std::slice::from_raw_parts(bytes, len) }.to_vec();
+        harmony_free_bytes(bytes, len);
+        assert!(!partial_bytes.is_empty());
+        assert!("🎉".as_bytes().starts_with(&partial_bytes));
+
+        let result =
+            harmony_encoding_decode_bytes(wrapper, [u32::MAX].as_ptr(), 1, &mut bytes, &mut len);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        take_c_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encoding_clone_shares_tokenizer() {
+        let wrapper = new_encoding();
+        let clone = harmony_encoding_clone(wrapper);
+        assert!(!clone.is_null());
+        let (original, cloned) = unsafe { (&(*wrapper).encoding, &(*clone).encoding) };
+        assert!(Arc::ptr_eq(&original.tokenizer, &cloned.tokenizer));
+
+        harmony_encoding_free(wrapper);
+        let tokens = encode_with_special(clone, "<|start|>user<|message|>Hi<|end|>");
+        assert_eq!(tokens.len(), 5);
+        harmony_encoding_free(clone);
+
+        assert!(harmony_encoding_clone(ptr::null()).is_null());
+    }
+
+    #[test]
+    fn test_versions() {
+        let version = unsafe { CStr::from_ptr(harmony_version()) };
+        assert_eq!(version.to_str().unwrap(), env!("CARGO_PKG_VERSION"));
+
+        let wrapper = new_encoding();
+        assert_eq!(
+            take_c_string(harmony_encoding_version(wrapper)).as_deref(),
+            Some("HarmonyGptOss/o200k_harmony")
+        );
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_commentary_tool_call_turn() {
+        let wrapper = new_encoding();
+        let json = CString::new(
+            serde_json::json!({"messages": [
+                {"role": "user", "content": "What is the weather in SF?"},
+                {"role": "assistant", "channel": "analysis", "content": "User asks: “What is the weather in SF?” We need to use lookup_weather tool."},
+                {"role": "assistant", "channel": "commentary", "recipient": "functions.lookup_weather",
+                 "content_type": "<|constrain|>json", "content": "{\"location\": \"San Francisco\"}"},
+                {"role": "tool", "name": "functions.lookup_weather",
+                 "content": "{\"temperature\": 20, \"description\": \"sunny\"}"}
+            ]})
+            .to_string(),
+        )
+        .unwrap();
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_for_completion(
+            wrapper,
+            json.as_ptr(),
+            HarmonyRole::Assistant as i32,
+            &true,
+            false,
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(
+            encoding
+                .tokenizer()
+                .decode_utf8(take_tokens(tokens, len))
+                .unwrap(),
+            load_test_data("test_does_not_drop_if_ongoing_analysis.txt")
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_conversation_json_auto_drop_analysis() {
+        let wrapper = new_encoding();
+        let json = CString::new(
+            serde_json::json!({"messages": [
+                {"role": "user", "content": "What is 2 + 2?"},
+                {"role": "assistant", "channel": "analysis", "content": "Simple arithmetic."},
+                {"role": "assistant", "channel": "final", "content": "4"}
+            ]})
+            .to_string(),
+        )
+        .unwrap();
+        let render = |auto_drop_analysis: *const bool| {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result = harmony_encoding_render_conversation_json(
+                wrapper,
+                json.as_ptr(),
+                auto_drop_analysis,
+                &mut tokens,
+                &mut len,
+            );
+            assert!(result.success);
+            let mut count = 0;
+            let result = harmony_encoding_count_conversation_tokens(
+                wrapper,
+                json.as_ptr(),
+                auto_drop_analysis,
+                &mut count,
+            );
+            assert!(result.success);
+            assert_eq!(count, len);
+            let encoding = unsafe { &(*wrapper).encoding };
+            encoding
+                .tokenizer()
+                .decode_utf8(take_tokens(tokens, len))
+                .unwrap()
+        };
+
+        assert!(render(ptr::null()).contains("Simple arithmetic."));
+        assert!(render(&false).contains("Simple arithmetic."));
+        let dropped = render(&true);
+        assert!(!dropped.contains("Simple arithmetic."));
+        assert!(dropped.ends_with("<|start|>assistant<|channel|>final<|message|>4<|end|>"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_content_type_round_trips_through_json() {
+        let wrapper = new_encoding();
+        let tokens = render_json(
+            wrapper,
+            r#"{"messages": [{"role": "assistant", "channel": "commentary",
+                              "recipient": "functions.get_weather",
+                              "content_type": "<|constrain|>json",
+                              "content": "{\"city\": \"Paris\"}"}]}"#,
+        )
+        .unwrap();
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(
+            encoding.tokenizer().decode_utf8(&tokens).unwrap(),
+            "<|start|>assistant to=functions.get_weather<|channel|>commentary <|constrain|>json<|message|>{\"city\": \"Paris\"}<|call|>"
+        );
+
+        let mut json = ptr::null_mut();
+        let result = harmony_encoding_parse_messages(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            -1,
+            &mut json,
+            ptr::null_mut(),
+        );
+        assert!(result.success);
+        let messages: Vec<Message> = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(
+            messages[0].content_type.as_deref(),
+            Some("<|constrain|>json")
+        );
+        assert_eq!(
+            messages[0].recipient.as_deref(),
+            Some("functions.get_weather")
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_plain_counted() {
+        let wrapper = new_encoding();
+        let text = "Größenwahn 🦀";
+        let c_text = CString::new(text).unwrap();
+        let encoded = harmony_encoding_encode_plain_counted(wrapper, c_text.as_ptr(), 0);
+        assert!(encoded.result.success);
+        assert_eq!(encoded.text_byte_len, text.len());
+        let tokens = take_tokens(encoded.tokens, encoded.tokens_len);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(tokens, encoding.tokenizer().encode_ordinary(text));
+
+        let encoded = harmony_encoding_encode_plain_counted(wrapper, ptr::null(), 0);
+        assert_eq!(
+            encoded.result.error_code,
+            HarmonyErrorCode::NullPointer as i32
+        );
+        assert!(encoded.tokens.is_null());
+        take_c_string(encoded.result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_current_content_accumulates() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Let me think.<|end|><|start|>assistant<|channel|>final<|message|>Done<|return|>",
+        );
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        let current_content = || {
+            let mut text = ptr::null_mut();
+            assert!(harmony_parser_current_content(parser, &mut text).success);
+            take_c_string(text).unwrap()
+        };
+
+        assert_eq!(current_content(), "");
+        let mut seen = Vec::new();
+        for &token in &tokens {
+            assert!(harmony_parser_process(parser, token).success);
+            seen.push(current_content());
+        }
+        assert!(seen.contains(&"Let me think.".to_string()));
+        assert!(seen.contains(&"Let me".to_string()));
+        assert!(seen.contains(&"Done".to_string()));
+        assert_eq!(seen.last().map(String::as_str), Some(""));
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_tokens_returns_copy() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(wrapper, "<|channel|>final<|message|>Hi<|end|>");
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        for &token in &tokens[..3] {
+            assert!(harmony_parser_process(parser, token).success);
+        }
+
+        let mut out = ptr::null_mut();
+        let mut len = 0;
+        assert!(harmony_parser_tokens(parser, &mut out, &mut len).success);
+        assert_eq!(take_tokens(out, len), &tokens[..3]);
+
+        for &token in &tokens[3..] {
+            assert!(harmony_parser_process(parser, token).success);
+        }
+        assert!(harmony_parser_tokens(parser, &mut out, &mut len).success);
+        assert_eq!(take_tokens(out, len), tokens);
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_result_free() {
+        let wrapper = new_encoding();
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let mut result =
+            harmony_encoding_encode_plain(wrapper, c"Hi".as_ptr(), &mut tokens, &mut len);
+        assert!(result.success);
+        assert!(result.error_message.is_null());
+        harmony_result_free(&mut result);
+        take_tokens(tokens, len);
+
+        let mut result = harmony_encoding_encode_plain(wrapper, ptr::null(), &mut tokens, &mut len);
+        assert!(!result.error_message.is_null());
+        harmony_result_free(&mut result);
+        assert!(result.error_message.is_null());
+        harmony_result_free(&mut result);
+        harmony_result_free(ptr::null_mut());
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encoding_new_from_data() {
+        let malformed = b"not a vocab file\n";
+        let wrapper =
+            harmony_encoding_new_from_data(malformed.as_ptr(), malformed.len(), ptr::null());
+        assert!(wrapper.is_null());
+        let message = unsafe { CStr::from_ptr(harmony_last_error_message()) };
+        assert!(message
+            .to_str()
+            .unwrap()
+            .starts_with("Failed to load encoding from data"));
+
+        // The vocab file is only available locally when it is read from
+        // TIKTOKEN_ENCODINGS_BASE rather than downloaded.
+        let Some(base_dir) = std::env::var_os("TIKTOKEN_ENCODINGS_BASE") else {
+            return;
+        };
+        let data =
+            std::fs::read(std::path::Path::new(&base_dir).join("o200k_base.tiktoken")).unwrap();
+        let wrapper =
+            harmony_encoding_new_from_data(data.as_ptr(), data.len(), c"HarmonyGptOss".as_ptr());
+        assert!(!wrapper.is_null());
+        let reference = new_encoding();
+        let text = "<|start|>user<|message|>Größenwahn 🦀<|end|>";
+        assert_eq!(
+            encode_with_special(wrapper, text),
+            encode_with_special(reference, text)
+        );
+        harmony_encoding_free(reference);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_roundtrip_ok() {
+        let wrapper = new_encoding();
+        for text in [
+            "",
+            "hello world",
+            "Größenwahn",
+            "日本語のテキスト",
+            "🦀🎉👩‍👩‍👧",
+            "<|start|>",
+        ] {
+            let c_text = CString::new(text).unwrap();
+            assert!(
+                harmony_encoding_roundtrip_ok(wrapper, c_text.as_ptr()),
+                "{text}"
+            );
+        }
+        assert!(!harmony_encoding_roundtrip_ok(wrapper, ptr::null()));
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_harmony_role_conversions() {
+        for role in [
+            Role::System,
+            Role::Developer,
+            Role::User,
+            Role::Assistant,
+            Role::Tool,
+        ] {
+            let c_role = role_to_c(Some(&role));
+            assert_eq!(role_from_c(c_role), Ok(Some(role)));
+        }
+        assert_eq!(HarmonyRole::System as i32, 0);
+        assert_eq!(HarmonyRole::Tool as i32, 4);
+        assert_eq!(role_to_c(None), -1);
+        assert_eq!(role_from_c(-1), Ok(None));
+        assert_eq!(role_from_c(5), Err("Invalid role: 5".to_string()));
+    }
+
+    #[test]
+    fn test_valid_channels() {
+        let wrapper = new_encoding();
+        let channels = |role: HarmonyRole| {
+            let mut json = ptr::null_mut();
+            assert!(harmony_encoding_valid_channels(wrapper, role as i32, &mut json).success);
+            take_c_string(json).unwrap()
+        };
+        assert_eq!(
+            channels(HarmonyRole::Assistant),
+            r#"["analysis","commentary","final"]"#
+        );
+        assert_eq!(channels(HarmonyRole::User), "[]");
+
+        let mut json = ptr::null_mut();
+        let result = harmony_encoding_valid_channels(wrapper, -1, &mut json);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        take_c_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_tool_result_cycle() {
+        let wrapper = new_encoding();
+        let json = serde_json::json!({"messages": [
+            {"role": "user", "content": "What is the weather in Tokyo?"},
+            {"role": "assistant", "channel": "commentary", "recipient": "functions.get_weather",
+             "content_type": "<|constrain|>json", "content": "{\"location\":\"Tokyo\"}"},
+            {"role": "tool", "author": "functions.get_weather", "recipient": "assistant",
+             "channel": "commentary", "content": "{\"temperature\": 20}"},
+            {"role": "assistant", "channel": "final", "content": "It is 20 degrees in Tokyo."}
+        ]});
+        let tokens = render_json(wrapper, &json.to_string()).unwrap();
+
+        let conversation = Conversation::from_messages([
+            Message::from_role_and_content(Role::User, "What is the weather in Tokyo?"),
+            Message::from_role_and_content(Role::Assistant, "{\"location\":\"Tokyo\"}")
+                .with_channel("commentary")
+                .with_recipient("functions.get_weather")
+                .with_content_type("<|constrain|>json"),
+            Message::from_author_and_content(
+                Author::new(Role::Tool, "functions.get_weather"),
+                "{\"temperature\": 20}",
+            )
+            .with_recipient("assistant")
+            .with_channel("commentary"),
+            Message::from_role_and_content(Role::Assistant, "It is 20 degrees in Tokyo.")
+                .with_channel("final"),
+        ]);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(
+            tokens,
+            encoding.render_conversation(&conversation, None).unwrap()
+        );
+        assert!(encoding
+            .tokenizer()
+            .decode_utf8(&tokens)
+            .unwrap()
+            .contains("<|start|>functions.get_weather to=assistant<|channel|>commentary<|message|>{\"temperature\": 20}<|end|>"));
+
+        let nameless = r#"{"messages": [{"role": "tool", "content": "{}"}]}"#;
+        assert!(render_json(wrapper, nameless)
+            .unwrap_err()
+            .contains("Tools should have a name"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_decode_into_buffer() {
+        let wrapper = new_encoding();
+        let text = "Größenwahn 🦀";
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens = encoding.tokenizer().encode_ordinary(text);
+
+        let mut buf = [0u8; 64];
+        let mut written = 0;
+        let result = harmony_encoding_decode_into(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut written,
+        );
+        assert!(result.success);
+        assert_eq!(&buf[..written], text.as_bytes());
+
+        let mut small = [0u8; 4];
+        let result = harmony_encoding_decode_into(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            small.as_mut_ptr(),
+            small.len(),
+            &mut written,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::BufferTooSmall as i32);
+        assert_eq!(written, text.len());
+        assert_eq!(small, [0u8; 4]);
+        take_c_string(result.error_message);
+
+        // Stopping inside the crab leaves an incomplete character: the buffer
+        // must not be touched.
+        let split = tokens
+            .iter()
+            .rposition(|&t| {
+                std::str::from_utf8(encoding.tokenizer.token_bytes(t).unwrap()).is_err()
+            })
+            .expect("emoji should be split across byte-level tokens");
+        let mut buf = [0xAAu8; 64];
+        let result = harmony_encoding_decode_into(
+            wrapper,
+            tokens.as_ptr(),
+            split,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut written,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidUtf8 as i32);
+        assert_eq!(written, 0);
+        assert_eq!(buf, [0xAAu8; 64]);
+        take_c_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_new_with_tokens_resumes() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Thinking hard.<|end|><|start|>assistant<|channel|>final<|message|>The answer is 4.<|return|>",
+        );
+        let split = tokens.len() - 4;
+        let assistant = HarmonyRole::Assistant as i32;
+
+        let resumed = harmony_parser_new_with_tokens(wrapper, assistant, tokens.as_ptr(), split);
+        assert!(!resumed.is_null());
+        let stepped = harmony_parser_new(wrapper, assistant);
+        for &token in &tokens[..split] {
+            assert!(harmony_parser_process(stepped, token).success);
+        }
+        let (resumed_parser, stepped_parser) = unsafe { (&(*resumed).parser, &(*stepped).parser) };
+        assert_eq!(
+            resumed_parser.state_json().unwrap(),
+            stepped_parser.state_json().unwrap()
+        );
+        assert_eq!(resumed_parser.tokens(), stepped_parser.tokens());
+
+        for &token in &tokens[split..] {
+            assert!(harmony_parser_process(resumed, token).success);
+        }
+        let mut json = ptr::null_mut();
+        assert!(harmony_parser_messages_json(resumed, &mut json).success);
+        assert!(take_c_string(json).unwrap().contains("The answer is 4."));
+
+        let bad = encode_with_special(wrapper, "<|channel|>final<|message|>Hi<|end|>oops");
+        assert!(
+            harmony_parser_new_with_tokens(wrapper, assistant, bad.as_ptr(), bad.len()).is_null()
+        );
+        let message = unsafe { CStr::from_ptr(harmony_last_error_message()) };
+        assert!(message
+            .to_str()
+            .unwrap()
+            .starts_with("Invalid token at index 5:"));
+
+        harmony_parser_free(stepped);
+        harmony_parser_free(resumed);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_empty_token_arrays_accept_null() {
+        let wrapper = new_encoding();
+        let empty: *const u32 = ptr::null();
+
+        let text = harmony_encoding_decode(wrapper, empty, 0);
+        assert_eq!(take_c_string(text).unwrap(), "");
+
+        let mut bytes = ptr::null_mut();
+        let mut bytes_len = 1;
+        assert!(
+            harmony_encoding_decode_bytes(wrapper, empty, 0, &mut bytes, &mut bytes_len).success
+        );
+        assert_eq!(bytes_len, 0);
+        harmony_free_bytes(bytes, bytes_len);
+
+        let mut written = 1;
+        let result =
+            harmony_encoding_decode_into(wrapper, empty, 0, ptr::null_mut(), 0, &mut written);
+        assert!(result.success);
+        assert_eq!(written, 0);
+
+        let assistant = HarmonyRole::Assistant as i32;
+        let mut json = ptr::null_mut();
+        assert!(
+            harmony_encoding_parse_messages(
+                wrapper,
+                empty,
+                0,
+                assistant,
+                &mut json,
+                ptr::null_mut()
+            )
+            .success
+        );
+        assert_eq!(take_c_string(json).unwrap(), "[]");
+        assert!(
+            harmony_encoding_parse_tool_calls(wrapper, empty, 0, &mut json, ptr::null_mut())
+                .success
+        );
+        assert_eq!(take_c_string(json).unwrap(), "[]");
+        assert!(harmony_encoding_validate_tokens(wrapper, empty, 0, assistant).success);
+
+        let parser = harmony_parser_new_with_tokens(wrapper, assistant, empty, 0);
+        assert!(!parser.is_null());
+        harmony_parser_free(parser);
+
+        let result = harmony_encoding_decode_bytes(wrapper, empty, 1, &mut bytes, &mut bytes_len);
+        assert_eq!(result.error_code, HarmonyErrorCode::NullPointer as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_system_content_builder() {
+        let wrapper = new_encoding();
+        let builder = harmony_system_content_new();
+        let identity = CString::new("You are a helpful assistant.").unwrap();
+        let cutoff = CString::new("2024-06").unwrap();
+        assert!(harmony_system_content_set_model_identity(builder, identity.as_ptr()).success);
+        assert!(harmony_system_content_set_reasoning_effort(builder, 2).success);
+        assert!(harmony_system_content_set_knowledge_cutoff(builder, cutoff.as_ptr()).success);
+
+        let result = harmony_system_content_set_reasoning_effort(builder, 7);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_render_system_content(wrapper, builder, &mut tokens, &mut len).success
+        );
+        let expected = SystemContent::new()
+            .with_model_identity("You are a helpful assistant.")
+            .with_reasoning_effort(ReasoningEffort::High)
+            .with_knowledge_cutoff("2024-06");
+        let encoding = unsafe { &(*wrapper).encoding };
+        let expected = encoding
+            .render(
+                &Message::from_role_and_content(Role::System, expected),
+                None,
+            )
+            .unwrap();
+        assert_eq!(take_tokens(tokens, len), expected);
+
+        // clearing a field drops it from the rendered message
+        assert!(harmony_system_content_set_knowledge_cutoff(builder, ptr::null()).success);
+        assert!(
+            harmony_encoding_render_system_content(wrapper, builder, &mut tokens, &mut len).success
+        );
+        let text = encoding
+            .tokenizer()
+            .decode_utf8(take_tokens(tokens, len))
+            .unwrap();
+        assert!(!text.contains("Knowledge cutoff"));
+
+        harmony_system_content_free(builder);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_developer_content_builder() {
+        let wrapper = new_encoding();
+        let developer = harmony_developer_content_new();
+        let instructions = CString::new("Answer briefly.").unwrap();
+        let name = CString::new("get_weather").unwrap();
+        let description = CString::new("Gets the weather").unwrap();
+        let parameters =
+            CString::new(r#"{"type":"object","properties":{"location":{"type":"string"}}}"#)
+                .unwrap();
+        assert!(
+            harmony_developer_content_set_instructions(developer, instructions.as_ptr()).success
+        );
+        assert!(
+            harmony_developer_content_add_function_tool(
+                developer,
+                name.as_ptr(),
+                description.as_ptr(),
+                parameters.as_ptr()
+            )
+            .success
+        );
+
+        let not_object = CString::new("[]").unwrap();
+        let result = harmony_developer_content_add_function_tool(
+            developer,
+            name.as_ptr(),
+            description.as_ptr(),
+            not_object.as_ptr(),
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        let system = harmony_system_content_new();
+        let user = CString::new("Weather in Paris?").unwrap();
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_render_prompt_with_builders(
+                wrapper,
+                system,
+                developer,
+                user.as_ptr(),
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+
+        let expected = Conversation::from_messages([
+            Message::from_role_and_content(Role::System, SystemContent::new()),
+            Message::from_role_and_content(
+                Role::Developer,
+                DeveloperContent::new()
+                    .with_instructions("Answer briefly.")
+                    .with_function_tools(vec![ToolDescription::new(
+                        "get_weather",
+                        "Gets the weather",
+                        Some(serde_json::from_str(parameters.to_str().unwrap()).unwrap()),
+                    )]),
+            ),
+            Message::from_role_and_content(Role::User, "Weather in Paris?"),
+        ]);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(
+            take_tokens(tokens, len),
+            encoding.render_conversation(&expected, None).unwrap()
+        );
+
+        harmony_system_content_free(system);
+        harmony_developer_content_free(developer);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_special_token_classification() {
+        let wrapper = new_encoding();
+        let marker = |text: &str| {
+            let tokens = encode_with_special(wrapper, text);
+            assert_eq!(tokens.len(), 1);
+            harmony_encoding_token_role_marker(wrapper, tokens[0])
+        };
+        assert_eq!(marker("<|start|>"), HarmonyTokenMarker::Start as i32);
+        assert_eq!(marker("<|message|>"), HarmonyTokenMarker::Message as i32);
+        assert_eq!(marker("<|end|>"), HarmonyTokenMarker::End as i32);
+        assert_eq!(marker("<|return|>"), HarmonyTokenMarker::Return as i32);
+        assert_eq!(marker("<|call|>"), HarmonyTokenMarker::Call as i32);
+        assert_eq!(marker("<|channel|>"), HarmonyTokenMarker::Channel as i32);
+        assert_eq!(
+            marker("<|constrain|>"),
+            HarmonyTokenMarker::Constrain as i32
+        );
+
+        let ordinary = encode_with_special(wrapper, "hello")[0];
+        assert_eq!(
+            harmony_encoding_token_role_marker(wrapper, ordinary),
+            HarmonyTokenMarker::None as i32
+        );
+        assert!(!harmony_encoding_is_special_token(wrapper, ordinary));
+
+        // special tokens without a formatting role are still special
+        let endoftext = encode_with_special(wrapper, "<|endoftext|>")[0];
+        assert!(harmony_encoding_is_special_token(wrapper, endoftext));
+        assert_eq!(
+            harmony_encoding_token_role_marker(wrapper, endoftext),
+            HarmonyTokenMarker::None as i32
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_conversation_builder() {
+        let wrapper = new_encoding();
+        let conversation = harmony_conversation_new();
+        let add =
+            |role: HarmonyRole, channel: Option<&str>, recipient: Option<&str>, text: &str| {
+                let channel = channel.map(|c| CString::new(c).unwrap());
+                let recipient = recipient.map(|r| CString::new(r).unwrap());
+                let text = CString::new(text).unwrap();
+                harmony_conversation_add_message(
+                    conversation,
+                    role as i32,
+                    channel.as_ref().map_or(ptr::null(), |c| c.as_ptr()),
+                    recipient.as_ref().map_or(ptr::null(), |r| r.as_ptr()),
+                    text.as_ptr(),
+                )
+            };
+        assert!(add(HarmonyRole::User, None, None, "What's the weather?").success);
+        assert!(
+            add(
+                HarmonyRole::Assistant,
+                Some("commentary"),
+                Some("functions.get_weather"),
+                "{}"
+            )
+            .success
+        );
+
+        let text = CString::new("Hi").unwrap();
+        let result = harmony_conversation_add_message(
+            conversation,
+            -1,
+            ptr::null(),
+            ptr::null(),
+            text.as_ptr(),
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(harmony_conversation_render(conversation, wrapper, &mut tokens, &mut len).success);
+        let expected = Conversation::from_messages([
+            Message::from_role_and_content(Role::User, "What's the weather?"),
+            Message::from_role_and_content(Role::Assistant, "{}")
+                .with_channel("commentary")
+                .with_recipient("functions.get_weather"),
+        ]);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(
+            take_tokens(tokens, len),
+            encoding.render_conversation(&expected, None).unwrap()
+        );
+
+        harmony_conversation_free(conversation);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|start|>assistant<|channel|>final<|message|>Hi<|end|><|end|>",
+        );
+        let mut json = ptr::null_mut();
+        let mut error = HarmonyParseError::none();
+        let mut result = harmony_encoding_parse_messages(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            -1,
+            &mut json,
+            &mut error,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::ParseFailed as i32);
+        assert_eq!(error.code, HarmonyErrorCode::ParseFailed as i32);
+        assert_eq!(error.token_index, tokens.len() - 1);
+        let message = unsafe { CStr::from_ptr(error.message) }.to_str().unwrap();
+        assert!(message.contains("expecting start token"));
+        harmony_result_free(&mut result);
+        harmony_parse_error_free(&mut error);
+        assert!(error.message.is_null());
+
+        // an unterminated header fails at the end of the tokens
+        let unterminated = encode_with_special(wrapper, "<|channel|>final");
+        let mut result = harmony_encoding_parse_tool_calls(
+            wrapper,
+            unterminated.as_ptr(),
+            unterminated.len(),
+            &mut json,
+            &mut error,
+        );
+        assert!(!result.success);
+        assert_eq!(error.token_index, unterminated.len());
+        harmony_result_free(&mut result);
+        harmony_parse_error_free(&mut error);
+
+        // a successful parse resets the error
+        let complete = &tokens[..tokens.len() - 1];
+        assert!(
+            harmony_encoding_parse_messages(
+                wrapper,
+                complete.as_ptr(),
+                complete.len(),
+                -1,
+                &mut json,
+                &mut error
+            )
+            .success
+        );
+        assert_eq!(error.code, HarmonyErrorCode::Ok as i32);
+        assert!(error.message.is_null());
+        take_c_string(json);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_drop_analysis() {
+        let wrapper = new_encoding();
+        let user = "<|start|>user<|message|>Weather in Paris?<|end|>";
+        let analysis =
+            "<|start|>assistant<|channel|>analysis<|message|>Need the weather tool.<|end|>";
+        let call = "<|start|>assistant to=functions.get_weather<|channel|>commentary <|constrain|>json<|message|>{\"city\": \"Paris\"}<|call|>";
+        let tool = "<|start|>functions.get_weather to=assistant<|channel|>commentary<|message|>{\"temp\": 20}<|end|>";
+        let last = "<|start|>assistant<|channel|>analysis<|message|>It is warm.<|end|><|start|>assistant<|channel|>final<|message|>It is 20°C.";
+        let tokens = encode_with_special(wrapper, &[user, analysis, call, tool, last].concat());
+
+        let mut out = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_drop_analysis(
+                wrapper,
+                tokens.as_ptr(),
+                tokens.len(),
+                &mut out,
+                &mut len
+            )
+            .success
+        );
+        let expected = [
+            user,
+            call,
+            tool,
+            "<|start|>assistant<|channel|>final<|message|>It is 20°C.",
+        ]
+        .iter()
+        .flat_map(|part| encode_with_special(wrapper, part))
+        .collect::<Vec<_>>();
+        assert_eq!(take_tokens(out, len), expected);
+
+        let result = harmony_encoding_drop_analysis(
+            wrapper,
+            tokens[1..].as_ptr(),
+            tokens.len() - 1,
+            &mut out,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::ParseFailed as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_current_message_complete() {
+        let wrapper = new_encoding();
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        let feed = |text: &str| {
+            for token in encode_with_special(wrapper, text) {
+                assert!(harmony_parser_process(parser, token).success);
+            }
+            harmony_parser_current_message_complete(parser)
+        };
+        assert!(!harmony_parser_current_message_complete(parser));
+        assert!(!feed("<|channel|>analysis<|message|>Thinking"));
+        assert!(feed("<|end|>"));
+        // the next message's start token begins a new, incomplete message
+        assert!(!feed("<|start|>"));
+        assert!(!feed("assistant<|channel|>final<|message|>Done."));
+        assert!(feed("<|return|>"));
+
+        assert!(harmony_parser_reset(parser, -1).success);
+        assert!(!harmony_parser_current_message_complete(parser));
+        assert!(!harmony_parser_current_message_complete(ptr::null()));
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_developer_matches_full_render() {
+        let wrapper = new_encoding();
+        let developer = harmony_developer_content_new();
+        assert!(
+            harmony_developer_content_set_instructions(developer, c"Be terse.".as_ptr()).success
+        );
+        assert!(
+            harmony_developer_content_add_function_tool(
+                developer,
+                c"lookup".as_ptr(),
+                c"Looks things up".as_ptr(),
+                cr#"{"type":"object","properties":{"query":{"type":"string"}}}"#.as_ptr()
+            )
+            .success
+        );
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_render_developer(wrapper, developer, &mut tokens, &mut len).success
+        );
+        let alone = take_tokens(tokens, len);
+
+        let system = harmony_system_content_new();
+        assert!(
+            harmony_encoding_render_prompt_with_builders(
+                wrapper,
+                system,
+                developer,
+                c"Hi".as_ptr(),
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        let full = take_tokens(tokens, len);
+        // the system message changes when tools are present, so search for the
+        // developer message rather than assuming its offset
+        assert!(full.windows(alone.len()).any(|window| window == alone));
+
+        harmony_system_content_free(system);
+        harmony_developer_content_free(developer);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_max_tokens() {
+        let wrapper = new_encoding();
+        let text = CString::new("one two three four five").unwrap();
+        let expected = encode_with_special(wrapper, "one two three four five");
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+
+        let exact = expected.len();
+        assert!(
+            harmony_encoding_encode_plain_limited(
+                wrapper,
+                text.as_ptr(),
+                exact,
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        assert_eq!(take_tokens(tokens, len), expected);
+
+        tokens = ptr::null_mut();
+        let result = harmony_encoding_encode_plain_limited(
+            wrapper,
+            text.as_ptr(),
+            exact - 1,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::TooLong as i32);
+        assert!(tokens.is_null());
+        harmony_free_string(result.error_message);
+
+        let result = harmony_encoding_encode_with_special(
+            wrapper,
+            text.as_ptr(),
+            ptr::null(),
+            2,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::TooLong as i32);
+        harmony_free_string(result.error_message);
+
+        let encoded = harmony_encoding_encode_plain_counted(wrapper, text.as_ptr(), 2);
+        assert_eq!(encoded.result.error_code, HarmonyErrorCode::TooLong as i32);
+        assert!(encoded.tokens.is_null());
+        harmony_free_string(encoded.result.error_message);
+
+        let short = CString::new("one").unwrap();
+        let texts = [short.as_ptr(), text.as_ptr()];
+        let mut offsets = ptr::null_mut();
+        let result = harmony_encoding_encode_plain_batch(
+            wrapper,
+            texts.as_ptr(),
+            texts.len(),
+            2,
+            &mut tokens,
+            &mut offsets,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::TooLong as i32);
+        let message = take_c_string(result.error_message).unwrap();
+        assert!(message.starts_with("Text at index 1 is too long"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_for_completion_omit_final_terminator() {
+        let wrapper = new_encoding();
+        let render = |json: &CStr, omit_final_terminator: bool| {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result = harmony_encoding_render_for_completion(
+                wrapper,
+                json.as_ptr(),
+                HarmonyRole::Assistant as i32,
+                ptr::null(),
+                omit_final_terminator,
+                &mut tokens,
+                &mut len,
+            );
+            assert!(result.success);
+            take_tokens(tokens, len)
+        };
+
+        let final_json = cr#"{"messages":[{"role":"user","content":"Count to 5."},{"role":"assistant","channel":"final","content":"1, 2, 3"}]}"#;
+        let prefix = "<|start|>user<|message|>Count to 5.<|end|><|start|>assistant<|channel|>final<|message|>1, 2, 3";
+        assert_eq!(
+            render(final_json, false),
+            encode_with_special(wrapper, &format!("{prefix}<|end|><|start|>assistant"))
+        );
+        assert_eq!(
+            render(final_json, true),
+            encode_with_special(wrapper, prefix)
+        );
+
+        // without a trailing final message the flag changes nothing
+        let user_json = cr#"{"messages":[{"role":"user","content":"Hi"}]}"#;
+        assert_eq!(render(user_json, true), render(user_json, false));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_error_message_with_nul() {
+        let result = HarmonyResult::err(
+            HarmonyErrorCode::ParseFailed,
+            "Unexpected text \"a\0b\"".to_string(),
+        );
+        assert_eq!(
+            take_c_string(result.error_message).unwrap(),
+            r#"Unexpected text "a\x00b""#
+        );
+        let last = unsafe { CStr::from_ptr(harmony_last_error_message()) };
+        assert_eq!(last.to_str().unwrap(), r#"Unexpected text "a\x00b""#);
+    }
+
+    #[test]
+    fn test_assistant_header_tokens() {
+        let wrapper = new_encoding();
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+
+        assert!(
+            harmony_encoding_assistant_header_tokens(
+                wrapper,
+                c"final".as_ptr(),
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        assert_eq!(
+            take_tokens(tokens, len),
+            encode_with_special(wrapper, "<|start|>assistant<|channel|>final<|message|>")
+        );
+
+        assert!(
+            harmony_encoding_assistant_header_tokens(wrapper, ptr::null(), &mut tokens, &mut len)
+                .success
+        );
+        assert_eq!(
+            take_tokens(tokens, len),
+            encode_with_special(wrapper, "<|start|>assistant<|message|>")
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_arguments_complete() {
+        let wrapper = new_encoding();
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        let feed = |text: &str| {
+            for token in encode_with_special(wrapper, text) {
+                assert!(harmony_parser_process(parser, token).success);
+            }
+            harmony_parser_arguments_complete(parser)
+        };
+
+        // content without a recipient is never a tool call
+        assert!(!feed("<|channel|>commentary<|message|>{}"));
+        assert!(!feed("<|end|><|start|>assistant to=functions.get_weather<|channel|>commentary json<|message|>"));
+        let mut recipient = ptr::null_mut();
+        assert!(harmony_parser_current_recipient(parser, &mut recipient).success);
+        assert_eq!(take_c_string(recipient).unwrap(), "functions.get_weather");
+
+        assert!(!feed("{\"city\": "));
+        assert!(!feed("\"Paris\""));
+        assert!(feed("}"));
+        assert!(feed("\n"));
+        assert!(!feed("<|call|>"));
+        assert!(harmony_parser_current_recipient(parser, &mut recipient).success);
+        assert!(recipient.is_null());
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_ffi_boundary_catches_panics() {
+        let result: HarmonyResult = ffi_boundary(|| panic!("boom"));
+        assert_eq!(result.error_code, HarmonyErrorCode::Panic as i32);
+        assert_eq!(
+            take_c_string(result.error_message).unwrap(),
+            "Internal panic: boom"
+        );
+
+        let parser: *mut StreamableParserWrapper =
+            ffi_boundary(|| panic!("index {} out of range", 3));
+        assert!(parser.is_null());
+        let message = unsafe { CStr::from_ptr(harmony_last_error_message()) };
+        assert_eq!(
+            message.to_str().unwrap(),
+            "Internal panic: index 3 out of range"
+        );
+
+        assert!(!ffi_boundary(|| -> bool { panic!("boom") }));
+    }
+
+    #[test]
+    fn test_vocab_bounds() {
+        let wrapper = new_encoding();
+        let vocab_size = harmony_encoding_vocab_size(wrapper);
+        let max_token_id = harmony_encoding_max_token_id(wrapper);
+        // ids are dense, so a logit buffer of vocab_size entries covers them all
+        assert_eq!(vocab_size, max_token_id as usize + 1);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert!(encoding.tokenizer.token_bytes(max_token_id).is_some());
+        assert!(encoding.tokenizer.token_bytes(max_token_id + 1).is_none());
+
+        assert_eq!(harmony_encoding_vocab_size(ptr::null()), 0);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_suffix_concatenates_with_prefix() {
+        let wrapper = new_encoding();
+        let history = r#"{"role":"system","content":"You are helpful."},{"role":"user","content":"Hi"},{"role":"assistant","channel":"final","content":"Hello!"}"#;
+        let turn = r#"{"role":"user","content":"How are you?"}"#;
+        let prefix_json = CString::new(format!(r#"{{"messages":[{history}]}}"#)).unwrap();
+        let full_json = CString::new(format!(r#"{{"messages":[{history},{turn}]}}"#)).unwrap();
+        let render = |json: &CStr| {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result = harmony_encoding_render_conversation_json(
+                wrapper,
+                json.as_ptr(),
+                ptr::null(),
+                &mut tokens,
+                &mut len,
+            );
+            assert!(result.success);
+            take_tokens(tokens, len)
+        };
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_render_suffix(
+                wrapper,
+                full_json.as_ptr(),
+                3,
+                ptr::null(),
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        let mut concatenated = render(&prefix_json);
+        concatenated.extend(take_tokens(tokens, len));
+        assert_eq!(concatenated, render(&full_json));
+
+        // dropping analysis from the cached prefix would invalidate it
+        let with_analysis = CString::new(
+            r#"{"messages":[{"role":"user","content":"Hi"},{"role":"assistant","channel":"analysis","content":"Greet."},{"role":"assistant","channel":"final","content":"Hello!"}]}"#,
+        )
+        .unwrap();
+        let result = harmony_encoding_render_suffix(
+            wrapper,
+            with_analysis.as_ptr(),
+            2,
+            &true,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::RenderFailed as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_decode_lossy() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens = encoding.tokenizer.encode_ordinary("\u{1F980}");
+        let split = tokens
+            .iter()
+            .position(|&t| std::str::from_utf8(encoding.tokenizer.token_bytes(t).unwrap()).is_err())
+            .expect("emoji should be split across byte-level tokens");
+        let partial = &tokens[..=split];
+
+        assert!(harmony_encoding_decode(wrapper, partial.as_ptr(), partial.len()).is_null());
+        let text = harmony_encoding_decode_lossy(wrapper, partial.as_ptr(), partial.len());
+        assert!(take_c_string(text).unwrap().contains('\u{FFFD}'));
+
+        let text = harmony_encoding_decode_lossy(wrapper, tokens.as_ptr(), tokens.len());
+        assert_eq!(take_c_string(text).unwrap(), "\u{1F980}");
+
+        harmony_encoding_free(wrapper);
+    }
+
+    static CUSTOM_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+    static CUSTOM_FREES: AtomicUsize = AtomicUsize::new(0);
+
+    // Counting wrappers around malloc/free, which the global allocator also
+    // uses on this platform, so tests running concurrently stay sound.
+    unsafe extern "C" fn counting_alloc(size: usize) -> *mut c_void {
+        CUSTOM_ALLOCS.fetch_add(1, Ordering::SeqCst);
+        malloc(size)
+    }
+
+    unsafe extern "C" fn counting_free(ptr: *mut c_void) {
+        CUSTOM_FREES.fetch_add(1, Ordering::SeqCst);
+        free(ptr)
+    }
+
+    extern "C" {
+        fn malloc(size: usize) -> *mut c_void;
+        fn free(ptr: *mut c_void);
+    }
+
+    #[test]
+    fn test_custom_allocators() {
+        let wrapper = new_encoding();
+
+        let result = harmony_set_allocators(Some(counting_alloc), None);
+        assert!(!result.success);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        assert!(harmony_set_allocators(Some(counting_alloc), Some(counting_free)).success);
+        let allocs = CUSTOM_ALLOCS.load(Ordering::SeqCst);
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result =
+            harmony_encoding_encode_plain(wrapper, c"hello world".as_ptr(), &mut tokens, &mut len);
+        assert!(result.success);
+        let text = harmony_encoding_decode(wrapper, tokens, len);
+        assert!(CUSTOM_ALLOCS.load(Ordering::SeqCst) >= allocs + 2);
+
+        let frees = CUSTOM_FREES.load(Ordering::SeqCst);
+        assert_eq!(
+            unsafe { CStr::from_ptr(text) }.to_str().unwrap(),
+            "hello world"
+        );
+        harmony_free_string(text);
+        harmony_free_tokens(tokens, len);
+        assert!(CUSTOM_FREES.load(Ordering::SeqCst) >= frees + 2);
+
+        assert!(harmony_set_allocators(None, None).success);
+        harmony_encoding_free(wrapper);
+    }
+
+    static TRACKED_FREES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    unsafe extern "C" fn tracked_free(ptr: *mut c_void) {
+        TRACKED_FREES.lock().unwrap().push(ptr as usize);
+        free(ptr)
+    }
+
+    #[test]
+    fn test_switching_allocators_with_outstanding_buffers() {
+        let wrapper = new_encoding();
+        let encode = || {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result =
+                harmony_encoding_encode_plain(wrapper, c"hello".as_ptr(), &mut tokens, &mut len);
+            assert!(result.success);
+            (tokens, len)
+        };
+
+        assert!(harmony_set_allocators(Some(counting_alloc), Some(tracked_free)).success);
+        let (tracked, tracked_len) = encode();
+        assert!(harmony_set_allocators(Some(counting_alloc), Some(counting_free)).success);
+        let (counted, counted_len) = encode();
+        assert!(harmony_set_allocators(None, None).success);
+        let (global, global_len) = encode();
+
+        // Only look at frees from here on; other tests may share the allocator.
+        let before = TRACKED_FREES.lock().unwrap().len();
+        harmony_free_tokens(counted, counted_len);
+        harmony_free_tokens(global, global_len);
+        let frees = TRACKED_FREES.lock().unwrap()[before..].to_vec();
+        assert!(!frees.contains(&(counted as usize)));
+        assert!(!frees.contains(&(global as usize)));
+        harmony_free_tokens(tracked, tracked_len);
+        assert!(TRACKED_FREES.lock().unwrap()[before..].contains(&(tracked as usize)));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_explain_tokens() {
+        let wrapper = new_encoding();
+        let mut tokens = encode_with_special(wrapper, "<|start|>user<|message|>hi");
+        tokens.extend(
+            unsafe { &(*wrapper).encoding }
+                .tokenizer
+                .encode_ordinary("\u{1F980}"),
+        );
+
+        let mut json = ptr::null_mut();
+        let result =
+            harmony_encoding_explain_tokens(wrapper, tokens.as_ptr(), tokens.len(), &mut json);
+        assert!(result.success);
+        let explained: Vec<serde_json::Value> =
+            serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(explained.len(), tokens.len());
+        assert_eq!(
+            explained[0],
+            serde_json::json!({"id": tokens[0], "text": "<|start|>"})
+        );
+        assert_eq!(explained[1]["text"], "user");
+        assert_eq!(explained[2]["text"], "<|message|>");
+        assert_eq!(explained[3]["text"], "hi");
+        assert!(explained[4]["text"].as_str().unwrap().contains('\u{FFFD}'));
+
+        let bad = [tokens[0], u32::MAX];
+        let result = harmony_encoding_explain_tokens(wrapper, bad.as_ptr(), bad.len(), &mut json);
+        assert!(!result.success);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_turns() {
+        let wrapper = new_encoding();
+        let roles = [
+            HarmonyRole::User as i32,
+            HarmonyRole::Assistant as i32,
+            HarmonyRole::User as i32,
+        ];
+        let texts = [c"Hi".as_ptr(), c"Hello!".as_ptr(), c"How are you?".as_ptr()];
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_turns(
+            wrapper,
+            roles.as_ptr(),
+            texts.as_ptr(),
+            roles.len(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        let encoding = unsafe { &(*wrapper).encoding };
+        assert_eq!(
+            encoding
+                .tokenizer()
+                .decode_utf8(take_tokens(tokens, len))
+                .unwrap(),
+            "<|start|>user<|message|>Hi<|end|><|start|>assistant<|message|>Hello!<|end|>\
+             <|start|>user<|message|>How are you?<|end|>"
+        );
+
+        let roles = [HarmonyRole::User as i32, -1];
+        let result = harmony_encoding_render_turns(
+            wrapper,
+            roles.as_ptr(),
+            texts.as_ptr(),
+            roles.len(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(!result.success);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parse_reasoning_effort() {
+        let wrapper = new_encoding();
+        let builder = harmony_system_content_new();
+        assert!(harmony_system_content_set_reasoning_effort(builder, 2).success);
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_render_system_content(wrapper, builder, &mut tokens, &mut len).success
+        );
+        let mut system = take_tokens(tokens, len);
+        system.extend(encode_with_special(
+            wrapper,
+            "<|start|>user<|message|>Hi<|end|>",
+        ));
+
+        let mut effort = 0;
+        let result = harmony_encoding_parse_reasoning_effort(
+            wrapper,
+            system.as_ptr(),
+            system.len(),
+            &mut effort,
+        );
+        assert!(result.success);
+        assert_eq!(effort, 2);
+
+        // No system message leaves the effort unset
+        let user = encode_with_special(wrapper, "<|start|>user<|message|>Hi<|end|>");
+        let result = harmony_encoding_parse_reasoning_effort(
+            wrapper,
+            user.as_ptr(),
+            user.len(),
+            &mut effort,
+        );
+        assert!(result.success);
+        assert_eq!(effort, -1);
+
+        harmony_system_content_free(builder);
+        harmony_encoding_free(wrapper);
+    }
+
+    unsafe extern "C" fn collect_delta(
+        channel: *const c_char,
+        delta: *const c_char,
+        user_data: *mut c_void,
+    ) {
+        let deltas = &mut *user_data.cast::<Vec<(Option<String>, String)>>();
+        let channel =
+            (!channel.is_null()).then(|| CStr::from_ptr(channel).to_str().unwrap().to_string());
+        deltas.push((channel, CStr::from_ptr(delta).to_str().unwrap().to_string()));
+    }
+
+    #[test]
+    fn test_parser_process_batch() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Think.<|end|><|start|>assistant<|channel|>final<|message|>Hello world<|return|>",
+        );
+
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        let mut deltas: Vec<(Option<String>, String)> = Vec::new();
+        let result = harmony_parser_process_batch(
+            parser,
+            tokens.as_ptr(),
+            tokens.len(),
+            Some(collect_delta),
+            (&mut deltas as *mut Vec<(Option<String>, String)>).cast(),
+        );
+        assert!(result.success);
+
+        let text_on = |channel: &str| -> String {
+            deltas
+                .iter()
+                .filter(|(c, _)| c.as_deref() == Some(channel))
+                .map(|(_, delta)| delta.as_str())
+                .collect()
+        };
+        assert_eq!(text_on("analysis"), "Think.");
+        assert_eq!(text_on("final"), "Hello world");
+        assert!(harmony_parser_current_message_complete(parser));
+        harmony_parser_free(parser);
+
+        // Without a callback the batch only updates the parser state
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        let result = harmony_parser_process_batch(
+            parser,
+            tokens.as_ptr(),
+            tokens.len(),
+            None,
+            ptr::null_mut(),
+        );
+        assert!(result.success);
+        let mut json = ptr::null_mut();
+        assert!(harmony_parser_messages_json(parser, &mut json).success);
+        let messages: serde_json::Value =
+            serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(messages.as_array().unwrap().len(), 2);
+        harmony_parser_free(parser);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_developer_content_recipient_namespace() {
+        let builder = harmony_developer_content_new();
+        assert!(
+            harmony_developer_content_add_function_tool(
+                builder,
+                c"lookup".as_ptr(),
+                ptr::null(),
+                ptr::null()
+            )
+            .success
+        );
+        assert!(
+            harmony_developer_content_add_namespace_tool(
+                builder,
+                c"crm".as_ptr(),
+                c"lookup".as_ptr(),
+                c"Looks up a customer.".as_ptr(),
+                ptr::null()
+            )
+            .success
+        );
+
+        let namespace_of = |recipient: &CStr| {
+            let mut namespace = ptr::null_mut();
+            let result = harmony_developer_content_recipient_namespace(
+                builder,
+                recipient.as_ptr(),
+                &mut namespace,
+            );
+            assert!(result.success);
+            take_c_string(namespace)
+        };
+        assert_eq!(
+            namespace_of(c"functions.lookup").as_deref(),
+            Some("functions")
+        );
+        assert_eq!(namespace_of(c"crm.lookup").as_deref(), Some("crm"));
+        assert_eq!(namespace_of(c"crm.delete"), None);
+
+        let result = harmony_developer_content_add_namespace_tool(
+            builder,
+            c"".as_ptr(),
+            c"lookup".as_ptr(),
+            ptr::null(),
+            ptr::null(),
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_developer_content_free(builder);
+    }
+
+    #[test]
+    fn test_message_token_lengths() {
+        let wrapper = new_encoding();
+        let json = serde_json::json!({"messages": [
+            {"role": "user", "content": "What is 2 + 2?"},
+            {"role": "assistant", "channel": "analysis", "content": "Simple arithmetic."},
+            {"role": "assistant", "channel": "final", "content": "4"},
+        ]})
+        .to_string();
+        let json = CString::new(json).unwrap();
+
+        for auto_drop in [false, true] {
+            let mut lengths = ptr::null_mut();
+            let mut count = 0;
+            let result = harmony_encoding_message_token_lengths(
+                wrapper,
+                json.as_ptr(),
+                &auto_drop,
+                &mut lengths,
+                &mut count,
+            );
+            assert!(result.success);
+            let per_message = unsafe { std::slice::from_raw_parts(lengths, count) }.to_vec();
+            harmony_free_lengths(lengths, count);
+
+            let mut total = 0;
+            assert!(
+                harmony_encoding_count_conversation_tokens(
+                    wrapper,
+                    json.as_ptr(),
+                    &auto_drop,
+                    &mut total
+                )
+                .success
+            );
+            assert_eq!(per_message.len(), 3);
+            assert_eq!(per_message.iter().sum::<usize>(), total);
+            assert_eq!(per_message[1] == 0, auto_drop);
+        }
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_conversation_text() {
+        let wrapper = new_encoding();
+        let json = cr#"{"messages": [{"role": "user", "content": "Hi"}]}"#;
+
+        let mut text = ptr::null_mut();
+        let result = harmony_encoding_render_conversation_text(
+            wrapper,
+            json.as_ptr(),
+            -1,
+            ptr::null(),
+            &mut text,
+        );
+        assert!(result.success);
+        assert_eq!(
+            take_c_string(text).unwrap(),
+            "<|start|>user<|message|>Hi<|end|>"
+        );
+
+        let result = harmony_encoding_render_conversation_text(
+            wrapper,
+            json.as_ptr(),
+            HarmonyRole::Assistant as i32,
+            ptr::null(),
+            &mut text,
+        );
+        assert!(result.success);
+        assert_eq!(
+            take_c_string(text).unwrap(),
+            "<|start|>user<|message|>Hi<|end|><|start|>assistant"
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_rejects_invalid_utf8() {
+        let wrapper = new_encoding();
+        let invalid = c"caf\xe9";
+        let invalid_utf8 = HarmonyErrorCode::InvalidUtf8 as i32;
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        for (system_msg, user_msg) in [
+            (c"".as_ptr(), invalid.as_ptr()),
+            (invalid.as_ptr(), c"Hi".as_ptr()),
+        ] {
+            let result = harmony_encoding_render_prompt(
+                wrapper,
+                system_msg,
+                user_msg,
+                ptr::null(),
+                &mut tokens,
+                &mut len,
+            );
+            assert_eq!(result.error_code, invalid_utf8);
+            harmony_free_string(result.error_message);
+        }
+
+        let roles = [HarmonyRole::User as i32];
+        let texts = [invalid.as_ptr()];
+        let result = harmony_encoding_render_turns(
+            wrapper,
+            roles.as_ptr(),
+            texts.as_ptr(),
+            1,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, invalid_utf8);
+        harmony_free_string(result.error_message);
+
+        let result = harmony_encoding_render_with_tools(
+            wrapper,
+            ptr::null(),
+            c"[]".as_ptr(),
+            invalid.as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, invalid_utf8);
+        harmony_free_string(result.error_message);
+
+        let builder = harmony_system_content_new();
+        let result = harmony_system_content_set_model_identity(builder, invalid.as_ptr());
+        assert_eq!(result.error_code, invalid_utf8);
+        harmony_free_string(result.error_message);
+        harmony_system_content_free(builder);
+
+        let builder = harmony_developer_content_new();
+        let mut namespace = ptr::null_mut();
+        let result = harmony_developer_content_recipient_namespace(
+            builder,
+            invalid.as_ptr(),
+            &mut namespace,
+        );
+        assert_eq!(result.error_code, invalid_utf8);
+        harmony_free_string(result.error_message);
+        harmony_developer_content_free(builder);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_rejects_invalid_utf8() {
+        let wrapper = new_encoding();
+        let invalid = c"caf\xe9";
+        let invalid_utf8 = HarmonyErrorCode::InvalidUtf8 as i32;
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result =
+            harmony_encoding_encode_plain(wrapper, invalid.as_ptr(), &mut tokens, &mut len);
+        assert_eq!(result.error_code, invalid_utf8);
+        harmony_free_string(result.error_message);
+
+        let result = harmony_encoding_encode_with_special(
+            wrapper,
+            invalid.as_ptr(),
+            ptr::null(),
+            0,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, invalid_utf8);
+        harmony_free_string(result.error_message);
+
+        let counted = harmony_encoding_encode_plain_counted(wrapper, invalid.as_ptr(), 0);
+        assert_eq!(counted.result.error_code, invalid_utf8);
+        assert!(counted.tokens.is_null());
+        harmony_free_string(counted.result.error_message);
+
+        let mut count = 0;
+        let result = harmony_encoding_count_tokens(wrapper, invalid.as_ptr(), &mut count);
+        assert_eq!(result.error_code, invalid_utf8);
+        harmony_free_string(result.error_message);
+
+        let texts = [c"fine".as_ptr(), invalid.as_ptr()];
+        let mut offsets = ptr::null_mut();
+        let result = harmony_encoding_encode_plain_batch(
+            wrapper,
+            texts.as_ptr(),
+            texts.len(),
+            0,
+            &mut tokens,
+            &mut offsets,
+            &mut len,
+        );
+        assert_eq!(result.error_code, invalid_utf8);
+        assert!(take_c_string(result.error_message)
+            .unwrap()
+            .starts_with("Text at index 1 is not valid UTF-8"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_invalid_handles_are_rejected() {
+        let wrapper = new_encoding();
+
+        // A wrapper whose tag was cleared, as it is by harmony_encoding_free
+        let mut stale = HarmonyEncodingWrapper::new(unsafe { &(*wrapper).encoding }.clone());
+        stale.tag = 0;
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_encode_plain(&stale, c"hi".as_ptr(), &mut tokens, &mut len);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidHandle as i32);
+        harmony_free_string(result.error_message);
+        assert_eq!(harmony_encoding_vocab_size(&stale), 0);
+        assert!(harmony_parser_new(&stale, -1).is_null());
+
+        let result =
+            harmony_encoding_encode_plain(ptr::null(), c"hi".as_ptr(), &mut tokens, &mut len);
+        assert_eq!(result.error_code, HarmonyErrorCode::NullPointer as i32);
+        harmony_free_string(result.error_message);
+
+        // Freeing a parser twice is refused instead of double-freeing
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+        harmony_parser_free(parser);
+        harmony_clear_last_error();
+        harmony_parser_free(parser);
+        let message = unsafe { CStr::from_ptr(harmony_last_error_message()) };
+        assert!(message
+            .to_str()
+            .unwrap()
+            .starts_with("Invalid parser handle"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_stop_tokens_json() {
+        let wrapper = new_encoding();
+        let mut json = ptr::null_mut();
+        assert!(harmony_encoding_stop_tokens_json(wrapper, &mut json).success);
+        let json: serde_json::Value = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+
+        let texts = |key: &str| -> Vec<String> {
+            json[key]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|token| token["text"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let mut general = texts("stop_tokens");
+        general.sort();
+        assert_eq!(general, ["<|call|>", "<|end|>", "<|return|>"]);
+        let mut actions = texts("stop_tokens_for_assistant_actions");
+        actions.sort();
+        assert_eq!(actions, ["<|call|>", "<|return|>"]);
+
+        let end = &json["stop_tokens"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|token| token["text"] == "<|end|>")
+            .unwrap()["id"];
+        assert_eq!(
+            end.as_u64(),
+            Some(encode_with_special(wrapper, "<|end|>")[0] as u64)
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_structured_conversation_json() {
+        let wrapper = new_encoding();
+        let json = serde_json::json!({"messages": [
+            {"role": "system", "content": [{
+                "type": "system_content",
+                "model_identity": "You are a test model.",
+                "reasoning_effort": "high",
+                "knowledge_cutoff": "2024-06",
+                "conversation_start_date": "2025-08-05",
+                "channel_config": {
+                    "valid_channels": ["analysis", "final"],
+                    "channel_required": true
+                }
+            }]},
+            {"role": "developer", "content": [{
+                "type": "developer_content",
+                "instructions": "Be brief.",
+                "tools": {"functions": {"name": "functions", "tools": [{
+                    "name": "get_weather",
+                    "description": "Gets the weather.",
+                    "parameters": {"type": "object", "properties": {}}
+                }]}}
+            }]},
+            {"role": "user", "content": "Weather?"}
+        ]});
+
+        let system = SystemContent::new()
+            .with_model_identity("You are a test model.")
+            .with_reasoning_effort(ReasoningEffort::High)
+            .with_knowledge_cutoff("2024-06")
+            .with_conversation_start_date("2025-08-05")
+            .with_required_channels(["analysis", "final"]);
+        let developer = DeveloperContent::new()
+            .with_instructions("Be brief.")
+            .with_function_tools(vec![ToolDescription::new(
+                "get_weather",
+                "Gets the weather.",
+                Some(serde_json::json!({"type": "object", "properties": {}})),
+            )]);
+        let encoding = unsafe { &(*wrapper).encoding };
+        let expected = encoding
+            .render_conversation(
+                &Conversation::from_messages([
+                    Message::from_role_and_content(Role::System, system),
+                    Message::from_role_and_content(Role::Developer, developer),
+                    Message::from_role_and_content(Role::User, "Weather?"),
+                ]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(render_json(wrapper, &json.to_string()).unwrap(), expected);
+
+        let invalid = [
+            (
+                r#"{"messages": [{"role": "system", "content": [{"type": "system_content", "reasoning": "high"}]}]}"#,
+                "messages[0].content[0]: unknown field `reasoning`",
+            ),
+            (
+                r#"{"messages": [{"role": "user", "content": [{"type": "developer_content"}]}]}"#,
+                "messages[0].content[0]: developer content belongs in a developer message",
+            ),
+            (
+                r#"{"messages": [{"role": "developer", "content": [{"type": "developer_content", "tools": {"functions": {"name": "functions", "tools": [{"name": "f", "description": "", "parameters": []}]}}}]}]}"#,
+                "messages[0].content[0].tools.functions.tools[0].parameters: must be a JSON object",
+            ),
+        ];
+        for (json, message) in invalid {
+            let err = render_json(wrapper, json).unwrap_err();
+            assert!(err.ends_with(message), "{err}");
+        }
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_stable_roundtrip() {
+        let wrapper = new_encoding();
+        let roundtrip = |tokens: &[u32]| {
+            let mut stable = false;
+            let mut index = usize::MAX;
+            let result = harmony_encoding_stable_roundtrip(
+                wrapper,
+                tokens.as_ptr(),
+                tokens.len(),
+                &mut stable,
+                &mut index,
+            );
+            assert!(result.success);
+            (stable, index)
+        };
+
+        let tokens = encode_with_special(
+            wrapper,
+            "<|start|>assistant<|channel|>final<|message|>Hello, world!<|return|>",
+        );
+        assert_eq!(roundtrip(&tokens), (true, tokens.len()));
+
+        // "Hello" spelled as single-character tokens is valid but not canonical
+        let encoding = unsafe { &(*wrapper).encoding };
+        let mut split = tokens[..5].to_vec();
+        for c in ["H", "e", "l", "l", "o"] {
+            split.extend(encoding.tokenizer.encode_ordinary(c));
+        }
+        assert_eq!(roundtrip(&split), (false, 5));
+
+        let mut stable = true;
+        let bad = [u32::MAX];
+        let result = harmony_encoding_stable_roundtrip(
+            wrapper,
+            bad.as_ptr(),
+            1,
+            &mut stable,
+            ptr::null_mut(),
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_extract_channel_text() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>First, <|end|>\
+             <|start|>assistant<|channel|>analysis<|message|>then.<|end|>\
+             <|start|>assistant<|channel|>final<|message|>The answer.<|return|>",
+        );
+        let extract = |channel: &CStr| {
+            let mut text = ptr::null_mut();
+            let result = harmony_encoding_extract_channel_text(
+                wrapper,
+                tokens.as_ptr(),
+                tokens.len(),
+                channel.as_ptr(),
+                &mut text,
+                ptr::null_mut(),
+            );
+            assert!(result.success);
+            take_c_string(text).unwrap()
+        };
+        assert_eq!(extract(c"final"), "The answer.");
+        assert_eq!(extract(c"analysis"), "First, then.");
+        assert_eq!(extract(c"commentary"), "");
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_stopped() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Think.<|end|><|start|>assistant<|channel|>final<|message|>Done.<|return|>",
+        );
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+
+        for &token in &tokens[..tokens.len() - 1] {
+            assert!(harmony_parser_process(parser, token).success);
+            assert!(!harmony_parser_stopped(parser));
+        }
+        assert!(harmony_parser_process(parser, tokens[tokens.len() - 1]).success);
+        assert!(harmony_parser_stopped(parser));
+
+        assert!(harmony_parser_reset(parser, HarmonyRole::Assistant as i32).success);
+        assert!(!harmony_parser_stopped(parser));
+        assert!(!harmony_parser_stopped(ptr::null()));
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_truncate_to_budget() {
+        let wrapper = new_encoding();
+        let json = serde_json::json!({"messages": [
+            {"role": "system", "content": "You are terse."},
+            {"role": "user", "content": "Tell me about the history of the Roman Empire."},
+            {"role": "assistant", "channel": "final", "content": "It lasted a long time."},
+            {"role": "user", "content": "And Byzantium?"},
+        ]});
+        let json = CString::new(json.to_string()).unwrap();
+        let roles = |out: *mut c_char| -> Vec<String> {
+            let out: serde_json::Value =
+                serde_json::from_str(&take_c_string(out).unwrap()).unwrap();
+            out["messages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|m| m["role"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let render_len = |messages: &[&str]| {
+            let encoding = unsafe { &(*wrapper).encoding };
+            let all: Conversation = serde_json::from_str(json.to_str().unwrap()).unwrap();
+            let kept: Vec<&Message> = all
+                .messages
+                .iter()
+                .filter(|m| messages.contains(&message_text(m).as_str()))
+                .collect();
+            encoding.render_conversation(kept, None).unwrap().len()
+        };
+
+        // Just enough room for the system message and the last user turn
+        let budget = render_len(&["You are terse.", "And Byzantium?"]);
+        let mut out = ptr::null_mut();
+        let result = harmony_encoding_truncate_to_budget(
+            wrapper,
+            json.as_ptr(),
+            ptr::null(),
+            budget,
+            false,
+            &mut out,
+        );
+        assert!(result.success);
+        assert_eq!(roles(out), ["system", "user"]);
+
+        // The system message alone is over a tiny budget unless the flag keeps the user turn
+        let result = harmony_encoding_truncate_to_budget(
+            wrapper,
+            json.as_ptr(),
+            ptr::null(),
+            3,
+            false,
+            &mut out,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::TooLong as i32);
+        harmony_free_string(result.error_message);
+        let result = harmony_encoding_truncate_to_budget(
+            wrapper,
+            json.as_ptr(),
+            ptr::null(),
+            3,
+            true,
+            &mut out,
+        );
+        assert!(result.success);
+        assert_eq!(roles(out), ["system", "user"]);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_tokens_for_marker() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let marker_tokens = |marker: &CStr| {
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result =
+                harmony_encoding_tokens_for_marker(wrapper, marker.as_ptr(), &mut tokens, &mut len);
+            assert!(result.success);
+            take_tokens(tokens, len)
+        };
+
+        let channel = marker_tokens(c"channel:analysis");
+        let recipient = marker_tokens(c"recipient:functions.get_weather");
+        assert_eq!(
+            encoding.tokenizer().decode_utf8(&channel).unwrap(),
+            "<|channel|>analysis"
+        );
+        assert_eq!(
+            encoding.tokenizer().decode_utf8(&recipient).unwrap(),
+            " to=functions.get_weather"
+        );
+
+        // The marker tokens appear verbatim in a rendered header
+        let message = Message::from_role_and_content(Role::Assistant, "{}")
+            .with_channel("commentary")
+            .with_recipient("functions.get_weather");
+        let rendered = encoding.render(&message, None).unwrap();
+        assert!(rendered
+            .windows(recipient.len())
+            .any(|window| window == recipient.as_slice()));
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        for marker in [c"channel:", c"tool:browser", c"analysis"] {
+            let result =
+                harmony_encoding_tokens_for_marker(wrapper, marker.as_ptr(), &mut tokens, &mut len);
+            assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+            harmony_free_string(result.error_message);
+        }
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_serialize_state_roundtrip() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Thinking about 🌍<|end|><|start|>assistant<|channel|>final<|message|>Done.<|return|>",
+        );
+
+        let mut uninterrupted = StreamableParser::new(
+            unsafe { &(*wrapper).encoding }.clone(),
+            Some(Role::Assistant),
+        )
+        .unwrap();
+        for &token in &tokens {
+            uninterrupted.process(token).unwrap();
+        }
+
+        // Checkpoint at every position, including inside the multi-token emoji
+        for split in 0..=tokens.len() {
+            let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+            for &token in &tokens[..split] {
+                assert!(harmony_parser_process(parser, token).success);
+            }
+            let mut json = ptr::null_mut();
+            assert!(harmony_parser_serialize_state(parser, &mut json).success);
+            harmony_parser_free(parser);
+
+            let json = take_c_string(json).unwrap();
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert!(value["state"].is_string());
+            let json = CString::new(json).unwrap();
+            let restored = harmony_parser_deserialize_state(wrapper, json.as_ptr());
+            assert!(!restored.is_null());
+            for &token in &tokens[split..] {
+                assert!(harmony_parser_process(restored, token).success);
+            }
+            let restored_parser = unsafe { &(*restored).parser };
+            assert_eq!(restored_parser.messages(), uninterrupted.messages());
+            assert_eq!(restored_parser.tokens(), uninterrupted.tokens());
+            assert_eq!(
+                restored_parser.state_json().unwrap(),
+                uninterrupted.state_json().unwrap()
+            );
+            assert_eq!(restored_parser.stopped(), uninterrupted.stopped());
+            harmony_parser_free(restored);
+        }
+
+        assert!(harmony_parser_deserialize_state(wrapper, c"{\"state\":1}".as_ptr()).is_null());
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_conversation_stats() {
+        let wrapper = new_encoding();
+        let analysis = "<|channel|>analysis<|message|>Need the weather.<|end|>";
+        let call = "<|start|>assistant to=functions.get_weather<|channel|>commentary json<|message|>{\"city\":\"Paris\"}<|call|>";
+        let tool = "<|start|>functions.get_weather to=assistant<|channel|>commentary<|message|>{\"temp\":20}<|end|>";
+        let more_analysis = "<|start|>assistant<|channel|>analysis<|message|>Mild.<|end|>";
+        let answer = "<|start|>assistant<|channel|>final<|message|>20 degrees.<|return|>";
+        let tokens = encode_with_special(
+            wrapper,
+            &[analysis, call, tool, more_analysis, answer].concat(),
+        );
+
+        let mut json = ptr::null_mut();
+        let result = harmony_encoding_conversation_stats(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            HarmonyRole::Assistant as i32,
+            &mut json,
+            ptr::null_mut(),
+        );
+        assert!(result.success);
+        let stats: serde_json::Value = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(stats["messages"], 5);
+        assert_eq!(stats["tokens"], tokens.len());
+
+        let len = |text: &str| encode_with_special(wrapper, text).len();
+        let groups = stats["groups"].as_array().unwrap();
+        let summary: Vec<(&str, Option<&str>, u64, u64, u64)> = groups
+            .iter()
+            .map(|g| {
+                (
+                    g["role"].as_str().unwrap(),
+                    g["channel"].as_str(),
+                    g["messages"].as_u64().unwrap(),
+                    g["tool_calls"].as_u64().unwrap(),
+                    g["tokens"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "assistant",
+                    Some("analysis"),
+                    2,
+                    0,
+                    (len(analysis) + len(more_analysis)) as u64
+                ),
+                ("assistant", Some("commentary"), 1, 1, len(call) as u64),
+                ("tool", Some("commentary"), 1, 0, len(tool) as u64),
+                ("assistant", Some("final"), 1, 0, len(answer) as u64),
+            ]
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_plain_len_with_nul() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text = "header\0binary\0tail";
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_encode_plain_len(
+            wrapper,
+            text.as_ptr().cast(),
+            text.len(),
+            0,
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        let tokens = take_tokens(tokens, len);
+        assert_eq!(tokens, encoding.tokenizer().encode_ordinary(text));
+        assert_eq!(encoding.tokenizer().decode_utf8(&tokens).unwrap(), text);
+
+        let invalid = b"ok\0\xff";
+        let mut tokens = ptr::null_mut();
+        let result = harmony_encoding_encode_plain_len(
+            wrapper,
+            invalid.as_ptr().cast(),
+            invalid.len(),
+            0,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidUtf8 as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_validate_tool_arguments() {
+        let parameters = CString::new(
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "location": {"type": "string"},
+                    "unit": {"type": "string", "enum": ["celsius", "fahrenheit"]},
+                    "days": {"type": "integer"},
+                    "hours": {"type": "array", "items": {"type": "integer"}},
+                },
+                "required": ["location"],
+                "additionalProperties": false,
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let check = |arguments: serde_json::Value| {
+            let arguments = CString::new(arguments.to_string()).unwrap();
+            let result = harmony_validate_tool_arguments(parameters.as_ptr(), arguments.as_ptr());
+            let message = take_c_string(result.error_message);
+            (result.error_code, message)
+        };
+
+        assert_eq!(
+            check(
+                serde_json::json!({"location": "Paris", "unit": "celsius", "days": 3, "hours": [9, 12]})
+            ),
+            (HarmonyErrorCode::Ok as i32, None)
+        );
+        for (arguments, expected) in [
+            (
+                serde_json::json!({"unit": "celsius"}),
+                "missing required field 'location'",
+            ),
+            (
+                serde_json::json!({"location": 1}),
+                "arguments.location: expected string, got number",
+            ),
+            (
+                serde_json::json!({"location": "Paris", "unit": "kelvin"}),
+                "is not one of",
+            ),
+            (
+                serde_json::json!({"location": "Paris", "days": 1.5}),
+                "arguments.days: expected integer",
+            ),
+            (
+                serde_json::json!({"location": "Paris", "hours": [9, "noon"]}),
+                "arguments.hours[1]",
+            ),
+            (
+                serde_json::json!({"location": "Paris", "city": "Paris"}),
+                "unexpected field 'city'",
+            ),
+            (
+                serde_json::json!(["Paris"]),
+                "arguments: expected object, got array",
+            ),
+        ] {
+            let (code, message) = check(arguments);
+            assert_eq!(code, HarmonyErrorCode::InvalidArgument as i32);
+            assert!(message.unwrap().contains(expected));
+        }
+
+        let result = harmony_validate_tool_arguments(parameters.as_ptr(), c"{".as_ptr());
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+    }
+
+    #[test]
+    fn test_eot_token() {
+        let wrapper = new_encoding();
+        let mut token = 0;
+        assert!(harmony_encoding_eot_token(wrapper, &mut token).success);
+        assert_eq!(encode_with_special(wrapper, "<|end|>"), [token]);
+
+        // It is how a finished assistant message ends in a rendered conversation
+        let encoding = unsafe { &(*wrapper).encoding };
+        let message = Message::from_role_and_content(Role::Assistant, "Hi").with_channel("final");
+        let rendered = encoding.render_conversation([&message], None).unwrap();
+        assert_eq!(rendered.last(), Some(&token));
+
+        let result = harmony_encoding_eot_token(wrapper, ptr::null_mut());
+        assert_eq!(result.error_code, HarmonyErrorCode::NullPointer as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_message_count_and_at() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Hmm.<|end|><|start|>assistant<|channel|>final<|message|>Hi!<|return|>",
+        );
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+
+        let mut counts = Vec::new();
+        for &token in &tokens {
+            assert!(harmony_parser_process(parser, token).success);
+            counts.push(harmony_parser_message_count(parser));
+        }
+        let end = tokens
+            .iter()
+            .position(|&t| encode_with_special(wrapper, "<|end|>") == [t]);
+        assert_eq!(counts[end.unwrap()], 1);
+        assert_eq!(counts[end.unwrap() - 1], 0);
+        assert_eq!(counts.last(), Some(&2));
+
+        let mut json = ptr::null_mut();
+        assert!(harmony_parser_message_at(parser, 1, &mut json).success);
+        let message: Message = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(&message, &unsafe { &(*parser).parser }.messages()[1]);
+        assert_eq!(message.channel.as_deref(), Some("final"));
+
+        let result = harmony_parser_message_at(parser, 2, &mut json);
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+        assert_eq!(harmony_parser_message_count(ptr::null()), 0);
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_plain_i32() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text = "Signed ids for 🌍";
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_encode_plain_i32(
+            wrapper,
+            c"Signed ids for 🌍".as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        let signed = unsafe { std::slice::from_raw_parts(tokens, len) }.to_vec();
+        harmony_free_tokens_i32(tokens, len);
+
+        let expected: Vec<i32> = encoding
+            .tokenizer()
+            .encode_ordinary(text)
+            .into_iter()
+            .map(|token| token as i32)
+            .collect();
+        assert_eq!(signed, expected);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_conversation_needs_new_turn() {
+        let wrapper = new_encoding();
+        let next_turn = |messages: serde_json::Value| {
+            let json =
+                CString::new(serde_json::json!({ "messages": messages }).to_string()).unwrap();
+            let mut next = -1;
+            let result =
+                harmony_encoding_conversation_needs_new_turn(wrapper, json.as_ptr(), &mut next);
+            assert!(result.success);
+            next
+        };
+        let user = serde_json::json!({"role": "user", "content": "Weather in Paris?"});
+        let analysis = serde_json::json!({"role": "assistant", "channel": "analysis", "content": "Look it up."});
+        let call = serde_json::json!({
+            "role": "assistant", "channel": "commentary",
+            "recipient": "functions.get_weather", "content": "{}",
+        });
+        let output = serde_json::json!({
+            "role": "tool", "name": "functions.get_weather", "channel": "commentary",
+            "content": "{\"temp\": 20}",
+        });
+        let answer =
+            serde_json::json!({"role": "assistant", "channel": "final", "content": "20 degrees."});
+
+        assert_eq!(
+            next_turn(serde_json::json!([])),
+            HarmonyNextTurn::AssistantNew as i32
+        );
+        assert_eq!(
+            next_turn(serde_json::json!([user])),
+            HarmonyNextTurn::AssistantNew as i32
+        );
+        assert_eq!(
+            next_turn(serde_json::json!([user, analysis])),
+            HarmonyNextTurn::AssistantContinue as i32
+        );
+        assert_eq!(
+            next_turn(serde_json::json!([user, analysis, call])),
+            HarmonyNextTurn::Tool as i32
+        );
+        assert_eq!(
+            next_turn(serde_json::json!([user, analysis, call, output])),
+            HarmonyNextTurn::AssistantContinue as i32
+        );
+        assert_eq!(
+            next_turn(serde_json::json!([user, analysis, call, output, answer])),
+            HarmonyNextTurn::User as i32
+        );
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_process_event() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|channel|>analysis<|message|>Check.<|end|><|start|>assistant to=functions.get_weather<|channel|>commentary json<|message|>{}<|call|>",
+        );
+        let parser = harmony_parser_new(wrapper, HarmonyRole::Assistant as i32);
+
+        let mut events = Vec::new();
+        let mut analysis_text = String::new();
+        for &token in &tokens {
+            let mut event = HarmonyParserEvent::none();
+            assert!(harmony_parser_process_event(parser, token, &mut event).success);
+            let text = |s: *mut c_char| {
+                (!s.is_null()).then(|| unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string())
+            };
+            let (channel, recipient) = (text(event.channel), text(event.recipient));
+            if event.kind == HarmonyParserEventKind::ContentDelta as i32 {
+                if channel.as_deref() == Some("analysis") {
+                    analysis_text.push_str(&text(event.delta).unwrap());
+                }
+            } else if event.kind != HarmonyParserEventKind::None as i32 {
+                events.push((event.kind, channel, recipient));
+            }
+            harmony_parser_event_free(&mut event);
+            assert!(event.delta.is_null());
+        }
+
+        let weather = Some("functions.get_weather".to_string());
+        assert_eq!(
+            events,
+            [
+                (
+                    HarmonyParserEventKind::ChannelStart as i32,
+                    Some("analysis".to_string()),
+                    None
+                ),
+                (
+                    HarmonyParserEventKind::MessageEnd as i32,
+                    Some("analysis".to_string()),
+                    None
+                ),
+                (
+                    HarmonyParserEventKind::ToolCallStart as i32,
+                    Some("commentary".to_string()),
+                    weather.clone()
+                ),
+                (
+                    HarmonyParserEventKind::Stop as i32,
+                    Some("commentary".to_string()),
+                    weather
+                ),
+            ]
+        );
+        assert_eq!(analysis_text, "Check.");
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_tool_response() {
+        let wrapper = new_encoding();
+        let call = serde_json::json!({
+            "role": "assistant", "channel": "commentary",
+            "recipient": "functions.get_weather", "content": "{\"city\": \"Paris\"}",
+        });
+        let user = serde_json::json!({"role": "user", "content": "Weather in Paris?"});
+        let render = |messages: serde_json::Value| {
+            let json =
+                CString::new(serde_json::json!({ "messages": messages }).to_string()).unwrap();
+            let mut tokens = ptr::null_mut();
+            let mut len = 0;
+            let result = harmony_encoding_render_tool_response(
+                wrapper,
+                json.as_ptr(),
+                c"{\"temp\": 20}".as_ptr(),
+                ptr::null(),
+                &mut tokens,
+                &mut len,
+            );
+            (result, tokens, len)
+        };
+
+        let (result, tokens, len) = render(serde_json::json!([user, call]));
+        assert!(result.success);
+        let text = unsafe { &(*wrapper).encoding }
+            .tokenizer()
+            .decode_utf8(take_tokens(tokens, len))
+            .unwrap();
+        assert!(text.ends_with(
+            "<|call|><|start|>functions.get_weather to=assistant<|channel|>commentary<|message|>{\"temp\": 20}<|end|><|start|>assistant"
+        ));
+
+        let (result, _, _) = render(serde_json::json!([user]));
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_token_completes_utf8() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let tokens = encoding.tokenizer().encode_ordinary("Hi 🌍🎉 ok");
+
+        // Each prefix completes exactly when its bytes decode as UTF-8
+        for end in 1..=tokens.len() {
+            let prior = &tokens[..end - 1];
+            let expected = encoding.tokenizer().decode_utf8(&tokens[..end]).is_ok();
+            assert_eq!(
+                harmony_encoding_token_completes_utf8(
+                    wrapper,
+                    prior.as_ptr(),
+                    prior.len(),
+                    tokens[end - 1]
+                ),
+                expected,
+                "prefix of {} tokens",
+                end
+            );
+        }
+        // The emoji span several tokens, so some prefixes must wait
+        assert!(
+            (1..tokens.len()).any(|end| encoding.tokenizer().decode_utf8(&tokens[..end]).is_err())
+        );
+
+        assert!(!harmony_encoding_token_completes_utf8(
+            wrapper,
+            ptr::null(),
+            0,
+            u32::MAX
+        ));
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_prefix_is_stable() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let system = serde_json::json!({
+            "role": "system",
+            "content": [{"type": "system_content", "reasoning_effort": "high"}],
+        });
+        let developer = serde_json::json!({
+            "role": "developer",
+            "content": [{
+                "type": "developer_content",
+                "instructions": "Answer briefly.",
+                "tools": {"functions": {"name": "functions", "tools": [
+                    {"name": "get_weather", "description": "Current weather",
+                     "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}},
+                ]}},
+            }],
+        });
+        let system_json = CString::new(system.to_string()).unwrap();
+        let developer_json = CString::new(developer.to_string()).unwrap();
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        let result = harmony_encoding_render_prefix(
+            wrapper,
+            system_json.as_ptr(),
+            developer_json.as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(result.success);
+        let prefix = take_tokens(tokens, len);
+
+        // The prefix starts every render of a conversation beginning with it
+        let conversation: Conversation = serde_json::from_value(serde_json::json!({"messages": [
+            system,
+            developer,
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "channel": "analysis", "content": "Call the tool."},
+            {"role": "assistant", "channel": "final", "content": "Sunny."},
+        ]}))
+        .unwrap();
+        let config = RenderConversationConfig {
+            auto_drop_analysis: true,
+        };
+        let full = encoding
+            .render_conversation(&conversation, Some(&config))
+            .unwrap();
+        assert!(full.starts_with(&prefix));
+        let completion = encoding
+            .render_conversation_for_completion(&conversation, Role::Assistant, None)
+            .unwrap();
+        assert!(completion.starts_with(&prefix));
+
+        // Roles are checked
+        let result = harmony_encoding_render_prefix(
+            wrapper,
+            developer_json.as_ptr(),
+            ptr::null(),
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_with_cached_prefix() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+
+        let result = harmony_encoding_render_with_cached_prefix(
+            wrapper,
+            c"Hi".as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        let system = serde_json::json!({
+            "role": "system",
+            "content": [{"type": "system_content", "model_identity": "You are a weather bot."}],
+        });
+        let developer = serde_json::json!({
+            "role": "developer",
+            "content": [{"type": "developer_content", "instructions": "Answer briefly."}],
+        });
+        let system_json = CString::new(system.to_string()).unwrap();
+        let developer_json = CString::new(developer.to_string()).unwrap();
+        assert!(
+            harmony_encoding_set_cached_prefix(
+                wrapper,
+                system_json.as_ptr(),
+                developer_json.as_ptr()
+            )
+            .success
+        );
+
+        for user_msg in [c"Weather in Paris?", c"And in Rome?"] {
+            let result = harmony_encoding_render_with_cached_prefix(
+                wrapper,
+                user_msg.as_ptr(),
+                &mut tokens,
+                &mut len,
+            );
+            assert!(result.success);
+            let conversation: Conversation =
+                serde_json::from_value(serde_json::json!({"messages": [
+                    system,
+                    developer,
+                    {"role": "user", "content": user_msg.to_str().unwrap()},
+                ]}))
+                .unwrap();
+            let expected = encoding
+                .render_conversation_for_completion(&conversation, Role::Assistant, None)
+                .unwrap();
+            assert_eq!(take_tokens(tokens, len), expected);
+        }
+
+        // Setting neither message clears the cache
+        assert!(harmony_encoding_set_cached_prefix(wrapper, ptr::null(), ptr::null()).success);
+        let result = harmony_encoding_render_with_cached_prefix(
+            wrapper,
+            c"Hi".as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert!(!result.success);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_decode_skip_invalid() {
+        let wrapper = new_encoding();
+        let mut tokens = encode_with_special(wrapper, "Hello<|end|> world");
+        tokens.insert(1, u32::MAX);
+        tokens.push(harmony_encoding_vocab_size(wrapper) as u32);
+
+        assert!(harmony_encoding_decode_lossy(wrapper, tokens.as_ptr(), tokens.len()).is_null());
+
+        let mut text = ptr::null_mut();
+        let mut skipped = 0;
+        let result = harmony_encoding_decode_skip_invalid(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            &mut text,
+            &mut skipped,
+        );
+        assert!(result.success);
+        assert_eq!(take_c_string(text).unwrap(), "Hello<|end|> world");
+        assert_eq!(skipped, 2);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_conversation_builder_tool_authors() {
+        let wrapper = new_encoding();
+        let conversation = harmony_conversation_new();
+        let tool = HarmonyRole::Tool as i32;
+        let assistant = HarmonyRole::Assistant as i32;
+        for (role, name, recipient, content) in [
+            (
+                assistant,
+                None,
+                Some(c"functions.get_weather"),
+                c"{\"city\": \"Paris\"}",
+            ),
+            (
+                tool,
+                Some(c"functions.get_weather"),
+                Some(c"assistant"),
+                c"{\"temp\": 20}",
+            ),
+            (
+                assistant,
+                None,
+                Some(c"functions.get_time"),
+                c"{\"city\": \"Paris\"}",
+            ),
+            (
+                tool,
+                Some(c"functions.get_time"),
+                Some(c"assistant"),
+                c"{\"time\": \"12:00\"}",
+            ),
+        ] {
+            let result = harmony_conversation_add_message_with_author(
+                conversation,
+                role,
+                name.map_or(ptr::null(), CStr::as_ptr),
+                c"commentary".as_ptr(),
+                recipient.map_or(ptr::null(), CStr::as_ptr),
+                content.as_ptr(),
+            );
+            assert!(result.success);
+        }
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(harmony_conversation_render(conversation, wrapper, &mut tokens, &mut len).success);
+        let text = unsafe { &(*wrapper).encoding }
+            .tokenizer()
+            .decode_utf8(take_tokens(tokens, len))
+            .unwrap();
+        assert!(text.contains("<|start|>functions.get_weather to=assistant<|channel|>commentary<|message|>{\"temp\": 20}<|end|>"));
+        assert!(text.contains("<|start|>functions.get_time to=assistant<|channel|>commentary<|message|>{\"time\": \"12:00\"}<|end|>"));
+
+        // Without a name a tool message could not be rendered
+        let result = harmony_conversation_add_message(
+            conversation,
+            tool,
+            ptr::null(),
+            ptr::null(),
+            c"{}".as_ptr(),
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_conversation_free(conversation);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parse_with_spans() {
+        let wrapper = new_encoding();
+        let completion = "<|channel|>analysis<|message|>Look up 🌍.<|end|><|start|>assistant<|channel|>final<|message|>Sunny.<|return|>";
+        let tokens = encode_with_special(wrapper, completion);
+
+        let mut json = ptr::null_mut();
+        let result = harmony_encoding_parse_with_spans(
+            wrapper,
+            tokens.as_ptr(),
+            tokens.len(),
+            HarmonyRole::Assistant as i32,
+            &mut json,
+            ptr::null_mut(),
+        );
+        assert!(result.success);
+        let messages: serde_json::Value =
+            serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        let span = |message: &serde_json::Value, from: &str, to: &str| {
+            let range =
+                message[from].as_u64().unwrap() as usize..message[to].as_u64().unwrap() as usize;
+            completion[range].to_string()
+        };
+
+        assert_eq!(
+            span(&messages[0], "text_start", "text_end"),
+            "<|channel|>analysis<|message|>Look up 🌍.<|end|>"
+        );
+        assert_eq!(
+            span(&messages[0], "content_start", "content_end"),
+            "Look up 🌍."
+        );
+        assert_eq!(
+            span(&messages[1], "text_start", "text_end"),
+            "<|start|>assistant<|channel|>final<|message|>Sunny.<|return|>"
+        );
+        assert_eq!(span(&messages[1], "content_start", "content_end"), "Sunny.");
+        assert_eq!(messages[1]["channel"], "final");
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parser_process_lenient_and_warnings() {
+        let wrapper = new_encoding();
+        let tokens = encode_with_special(
+            wrapper,
+            "<|start|>user<|message|>Hi<|end|><|channel|>stray<|end|>",
+        );
+        let parser = harmony_parser_new(wrapper, -1);
+
+        let mut json = ptr::null_mut();
+        assert!(harmony_parser_warnings_json(parser, &mut json).success);
+        assert_eq!(take_c_string(json).unwrap(), "[]");
+
+        for &token in &tokens {
+            assert!(harmony_parser_process_lenient(parser, token).success);
+        }
+        let messages = unsafe { &(*parser).parser }.messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(message_text(&messages[1]), "<|channel|>stray");
+        assert_eq!(messages[1].author.role, Role::Assistant);
+
+        assert!(harmony_parser_warnings_json(parser, &mut json).success);
+        let warnings: Vec<String> = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("expecting start token"));
+
+        assert!(harmony_parser_reset(parser, -1).success);
+        let first = encode_with_special(wrapper, "<|channel|>");
+        let result = harmony_parser_process(parser, first[0]);
+        assert_eq!(result.error_code, HarmonyErrorCode::ParseFailed as i32);
+        harmony_free_string(result.error_message);
+
+        let result = harmony_parser_warnings_json(parser, ptr::null_mut());
+        assert_eq!(result.error_code, HarmonyErrorCode::NullPointer as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_parser_free(parser);
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_parse_channel_config() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let render = |system: SystemContent| {
+            let conversation = Conversation::from_messages([
+                Message::from_role_and_content(Role::System, system),
+                Message::from_role_and_content(Role::User, "Hi"),
+            ]);
+            encoding.render_conversation(&conversation, None).unwrap()
+        };
+
+        let tokens = render(SystemContent::new());
+        let mut json = ptr::null_mut();
+        assert!(
+            harmony_encoding_parse_channel_config(
+                wrapper,
+                tokens.as_ptr(),
+                tokens.len(),
+                &mut json
+            )
+            .success
+        );
+        let config: ChannelConfig = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(Some(config), SystemContent::new().channel_config);
+
+        let optional = ChannelConfig {
+            valid_channels: vec!["final".to_string()],
+            channel_required: false,
+        };
+        let tokens = render(SystemContent::new().with_channel_config(optional.clone()));
+        assert!(
+            harmony_encoding_parse_channel_config(
+                wrapper,
+                tokens.as_ptr(),
+                tokens.len(),
+                &mut json
+            )
+            .success
+        );
+        let config: ChannelConfig = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(config, optional);
+
+        let tokens = render(SystemContent::new().with_channel_config(ChannelConfig::default()));
+        assert!(
+            harmony_encoding_parse_channel_config(
+                wrapper,
+                tokens.as_ptr(),
+                tokens.len(),
+                &mut json
+            )
+            .success
+        );
+        assert!(json.is_null());
+
+        let result =
+            harmony_encoding_parse_channel_config(wrapper, tokens[1..].as_ptr(), 3, &mut json);
+        assert_eq!(result.error_code, HarmonyErrorCode::ParseFailed as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_render_delta() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let history = r#"{"role":"user","content":"Hi"},{"role":"assistant","channel":"final","content":"Hello!"}"#;
+        let turn = r#"{"role":"user","content":"How are you?"}"#;
+        let prev_json = CString::new(format!(r#"{{"messages":[{history}]}}"#)).unwrap();
+        let new_json = CString::new(format!(r#"{{"messages":[{history},{turn}]}}"#)).unwrap();
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_render_delta(
+                wrapper,
+                prev_json.as_ptr(),
+                new_json.as_ptr(),
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        let user = Message::from_role_and_content(Role::User, "How are you?");
+        assert_eq!(
+            take_tokens(tokens, len),
+            encoding.render(&user, None).unwrap()
+        );
+
+        assert!(
+            harmony_encoding_render_delta(
+                wrapper,
+                new_json.as_ptr(),
+                new_json.as_ptr(),
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        assert!(take_tokens(tokens, len).is_empty());
+
+        let edited = CString::new(format!(
+            r#"{{"messages":[{{"role":"user","content":"Hey"}},{turn}]}}"#
+        ))
+        .unwrap();
+        let result = harmony_encoding_render_delta(
+            wrapper,
+            prev_json.as_ptr(),
+            edited.as_ptr(),
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::RenderFailed as i32);
+        assert!(take_c_string(result.error_message)
+            .unwrap()
+            .contains("differ at token 3"));
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_windows() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text = "one two three four five six seven eight nine ten eleven";
+        let c_text = CString::new(text).unwrap();
+        let encoded = encoding.tokenizer().encode_ordinary(text);
+        assert_eq!(encoded.len(), 11);
+
+        let mut tokens = ptr::null_mut();
+        let mut offsets = ptr::null_mut();
+        let mut count = 0;
+        let result = harmony_encoding_encode_windows(
+            wrapper,
+            c_text.as_ptr(),
+            4,
+            1,
+            &mut tokens,
+            &mut offsets,
+            &mut count,
+        );
+        assert!(result.success);
+        assert_eq!(count, 4);
+        let offsets_slice = unsafe { std::slice::from_raw_parts(offsets, count + 1) };
+        let total_len = offsets_slice[count];
+        let tokens_slice = unsafe { std::slice::from_raw_parts(tokens, total_len) };
+        let windows: Vec<&[u32]> = (0..count)
+            .map(|i| &tokens_slice[offsets_slice[i]..offsets_slice[i + 1]])
+            .collect();
+        assert_eq!(
+            windows,
+            [
+                &encoded[0..4],
+                &encoded[3..7],
+                &encoded[6..10],
+                &encoded[9..11]
+            ]
+        );
+        harmony_free_tokens(tokens, total_len);
+        harmony_free_offsets(offsets, count + 1);
+
+        let result = harmony_encoding_encode_windows(
+            wrapper,
+            c"".as_ptr(),
+            4,
+            1,
+            &mut tokens,
+            &mut offsets,
+            &mut count,
+        );
+        assert!(result.success);
+        assert_eq!(count, 0);
+        harmony_free_offsets(offsets, 1);
+
+        let result = harmony_encoding_encode_windows(
+            wrapper,
+            c_text.as_ptr(),
+            4,
+            4,
+            &mut tokens,
+            &mut offsets,
+            &mut count,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        let result = harmony_encoding_encode_windows(
+            wrapper,
+            ptr::null(),
+            4,
+            1,
+            &mut tokens,
+            &mut offsets,
+            &mut count,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::NullPointer as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+
+    #[test]
+    fn test_encode_plain_with_header() {
+        let wrapper = new_encoding();
+        let encoding = unsafe { &(*wrapper).encoding };
+        let text = c"Once upon a time";
+        let plain = encoding.tokenizer.encode_ordinary("Once upon a time");
+
+        let mut tokens = ptr::null_mut();
+        let mut len = 0;
+        assert!(
+            harmony_encoding_encode_plain_with_header(
+                wrapper,
+                text.as_ptr(),
+                false,
+                HarmonyRole::User as i32,
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        assert_eq!(take_tokens(tokens, len), plain);
+
+        assert!(
+            harmony_encoding_encode_plain_with_header(
+                wrapper,
+                text.as_ptr(),
+                true,
+                -1,
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        let mut expected = encode_with_special(wrapper, "<|start|>assistant<|message|>");
+        expected.extend(&plain);
+        assert_eq!(take_tokens(tokens, len), expected);
+
+        assert!(
+            harmony_encoding_encode_plain_with_header(
+                wrapper,
+                text.as_ptr(),
+                true,
+                HarmonyRole::User as i32,
+                &mut tokens,
+                &mut len
+            )
+            .success
+        );
+        let mut expected = encode_with_special(wrapper, "<|start|>user<|message|>");
+        expected.extend(&plain);
+        assert_eq!(take_tokens(tokens, len), expected);
+
+        let result = harmony_encoding_encode_plain_with_header(
+            wrapper,
+            text.as_ptr(),
+            true,
+            42,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::InvalidArgument as i32);
+        harmony_free_string(result.error_message);
+
+        let result = harmony_encoding_encode_plain_with_header(
+            wrapper,
+            ptr::null(),
+            true,
+            -1,
+            &mut tokens,
+            &mut len,
+        );
+        assert_eq!(result.error_code, HarmonyErrorCode::NullPointer as i32);
+        harmony_free_string(result.error_message);
+
+        harmony_encoding_free(wrapper);
+    }
+}
@@ -10,6 +10,9 @@ use std::{fmt::Display, marker::PhantomData};
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Author {
     pub role: Role,
+    /// Name of the author, e.g. the tool a tool message comes from. Also
+    /// accepted as `author` when deserializing.
+    #[serde(alias = "author")]
     pub name: Option<String>,
 }
 
@@ -207,8 +210,11 @@ pub struct TextContent {
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum ReasoningEffort {
+    #[serde(alias = "low")]
     Low,
+    #[serde(alias = "medium")]
     Medium,
+    #[serde(alias = "high")]
     High,
 }
 
@@ -234,6 +240,24 @@ impl ChannelConfig {
             channel_required: true,
         }
     }
+
+    /// Read the config back from the text of a rendered system message, i.e.
+    /// its `# Valid channels: ...` line. Returns `None` if there is no such line.
+    pub fn from_rendered_text(text: &str) -> Option<Self> {
+        let line = text
+            .lines()
+            .find_map(|line| line.strip_prefix("# Valid channels: "))?;
+        let (channels, channel_required) =
+            match line.strip_suffix(" Channel must be included for every message.") {
+                Some(channels) => (channels, true),
+                None => (line, false),
+            };
+        let channels = channels.strip_suffix('.').unwrap_or(channels);
+        Some(Self {
+            valid_channels: channels.split(", ").map(str::to_string).collect(),
+            channel_required,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -430,6 +454,112 @@ impl ToolDescription {
             parameters,
         }
     }
+
+    /// Check tool call arguments against this tool's `parameters` schema, see
+    /// [`validate_tool_arguments`]. A tool without parameters accepts anything.
+    pub fn validate_arguments(&self, arguments: &serde_json::Value) -> anyhow::Result<()> {
+        match &self.parameters {
+            Some(parameters) => validate_tool_arguments(parameters, arguments),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check tool call arguments against a tool's JSON schema `parameters`.
+///
+/// Only a minimal subset of JSON schema is checked: `type` (a name or a list
+/// of names), `enum`, `required`, `properties`, `additionalProperties: false`
+/// and `items`, recursively. Other keywords are ignored.
+pub fn validate_tool_arguments(
+    parameters: &serde_json::Value,
+    arguments: &serde_json::Value,
+) -> anyhow::Result<()> {
+    validate_schema_value(parameters, arguments, "arguments")
+}
+
+fn validate_schema_value(
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+    path: &str,
+) -> anyhow::Result<()> {
+    use serde_json::Value;
+
+    let Value::Object(schema) = schema else {
+        anyhow::bail!("schema for {path} is not an object");
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let names: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => anyhow::bail!("schema for {path} has an invalid type"),
+        };
+        let matches = |name: &str| match name {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            "number" => value.is_number(),
+            "integer" => {
+                value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+            }
+            _ => false,
+        };
+        if !names.iter().any(|name| matches(name)) {
+            anyhow::bail!(
+                "{path}: expected {}, got {}",
+                names.join(" or "),
+                match value {
+                    Value::Null => "null",
+                    Value::Bool(_) => "boolean",
+                    Value::Number(_) => "number",
+                    Value::String(_) => "string",
+                    Value::Array(_) => "array",
+                    Value::Object(_) => "object",
+                }
+            );
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            anyhow::bail!(
+                "{path}: {value} is not one of {}",
+                Value::Array(allowed.clone())
+            );
+        }
+    }
+
+    if let Value::Object(fields) = value {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !fields.contains_key(name) {
+                    anyhow::bail!("{path}: missing required field '{name}'");
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, field) in fields {
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(field_schema) => {
+                    validate_schema_value(field_schema, field, &format!("{path}.{name}"))?
+                }
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    anyhow::bail!("{path}: unexpected field '{name}'");
+                }
+                None => {}
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            validate_schema_value(item_schema, item, &format!("{path}[{index}]"))?;
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -532,4 +662,17 @@ impl DeveloperContent {
         self = self.with_tools(ToolNamespaceConfig::new("functions", None, tools));
         self
     }
+
+    /// The tool namespace a message recipient such as `functions.get_weather`
+    /// addresses: either a namespace named exactly `recipient`, or the one
+    /// named by its prefix before a `.` that declares the remaining tool name.
+    pub fn namespace_for_recipient(&self, recipient: &str) -> Option<&ToolNamespaceConfig> {
+        self.tools.as_ref()?.values().find(|namespace| {
+            recipient == namespace.name
+                || recipient
+                    .strip_prefix(namespace.name.as_str())
+                    .and_then(|rest| rest.strip_prefix('.'))
+                    .is_some_and(|tool| namespace.tools.iter().any(|t| t.name == tool))
+        })
+    }
 }
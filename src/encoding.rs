@@ -170,6 +170,18 @@ impl HarmonyEncoding {
         B: Extend<Rank>,
     {
         let messages: Vec<_> = conversation.into_iter().collect();
+        let (render_options, messages) = Self::conversation_render_plan(&messages, config);
+        messages
+            .into_iter()
+            .try_for_each(|msg| self.render_into(msg, into, Some(&render_options)))
+    }
+
+    /// Work out the render options for a conversation and which of its messages
+    /// are rendered once `config` has been applied.
+    fn conversation_render_plan<'a>(
+        messages: &[&'a Message],
+        config: Option<&RenderConversationConfig>,
+    ) -> (RenderOptions, Vec<&'a Message>) {
         let has_function_tools = messages.iter().any(|msg| {
             msg.content.iter().any(|c| {
                 if let Content::DeveloperContent(dev) = c {
@@ -206,7 +218,7 @@ impl HarmonyEncoding {
             .iter()
             .position(|msg| msg.channel.as_deref() == Some("final"));
 
-        let result = messages
+        let rendered = messages
             .iter()
             .enumerate()
             .filter(|(idx, msg)| {
@@ -214,9 +226,9 @@ impl HarmonyEncoding {
                     && first_final_idx.is_some_and(|first| *idx < first)
                     && msg.channel.as_deref() == Some("analysis"))
             })
-            .try_for_each(|(_, msg)| self.render_into(msg, into, Some(&render_options)));
-        result?;
-        Ok(())
+            .map(|(_, msg)| *msg)
+            .collect();
+        (render_options, rendered)
     }
 
     /// Renders a conversation into a collection of tokens, adding the next turn role.
@@ -259,6 +271,82 @@ impl HarmonyEncoding {
         Ok(into)
     }
 
+    /// Render a conversation that ends in an assistant tool call, followed by
+    /// the tool's output, for completion of the assistant's next message.
+    ///
+    /// The output is rendered the way the model expects a tool response: from
+    /// the called tool, addressed to the assistant and on the channel of the
+    /// call. Analysis before the call is kept even with `auto_drop_analysis`,
+    /// as the assistant's turn has not reached a final message yet.
+    pub fn render_tool_response_for_completion<'a, I>(
+        &self,
+        conversation: I,
+        tool_output: &str,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let messages: Vec<&Message> = conversation.into_iter().collect();
+        let call = messages
+            .last()
+            .filter(|msg| msg.author.role == Role::Assistant)
+            .and_then(|msg| {
+                msg.recipient
+                    .as_deref()
+                    .filter(|r| *r != "all")
+                    .map(|r| (msg, r))
+            });
+        let Some((call, tool_name)) = call else {
+            anyhow::bail!("conversation must end in an assistant tool call");
+        };
+
+        let mut response =
+            Message::from_author_and_content(Author::new(Role::Tool, tool_name), tool_output)
+                .with_recipient("assistant");
+        if let Some(channel) = &call.channel {
+            response = response.with_channel(channel.clone());
+        }
+
+        self.render_conversation_for_completion(
+            messages.into_iter().chain(std::iter::once(&response)),
+            Role::Assistant,
+            config,
+        )
+    }
+
+    /// Render a conversation so that sampling continues its last message.
+    ///
+    /// If the last message in the conversation is an assistant message to the
+    /// `final` channel, its trailing `<|end|>` token is left off and no new turn
+    /// is started, so the model picks up where that message stopped. Otherwise
+    /// this is the same as [`Self::render_conversation_for_completion`].
+    pub fn render_conversation_for_continuation<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let messages: Vec<&Message> = conversation.into_iter().collect();
+        let continues_final = messages.last().is_some_and(|last| {
+            last.author.role == Role::Assistant && last.channel.as_deref() == Some("final")
+        });
+        if !continues_final {
+            return self.render_conversation_for_completion(messages, next_turn_role, config);
+        }
+
+        let mut out = vec![];
+        self.render_conversation_into(messages, &mut out, config)?;
+        let end = self.render_formatting_token(FormattingToken::EndMessage)?;
+        if out.last() == Some(&end) {
+            out.pop();
+        }
+        Ok(out)
+    }
+
     /// Render a conversation for training.
     ///
     /// If the last message in the conversation is an assistant message to the
@@ -285,6 +373,110 @@ impl HarmonyEncoding {
         Ok(out)
     }
 
+    /// Render a conversation for training together with a loss mask.
+    ///
+    /// The mask has one entry per token and is `true` where the model should
+    /// be trained to produce the token: everything an assistant message renders
+    /// after its `<|start|>assistant` prefix, i.e. the channel and recipient
+    /// header, the content and the closing token. The prefix itself and all
+    /// tokens of other authors are prompt, since at inference time the prompt
+    /// ends with `<|start|>assistant`.
+    pub fn render_conversation_for_training_with_mask<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<bool>)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let messages: Vec<&Message> = conversation.into_iter().collect();
+        let (render_options, rendered) = Self::conversation_render_plan(&messages, config);
+        let mut tokens = vec![];
+        let mut mask = vec![];
+        for msg in rendered {
+            let start = tokens.len();
+            self.render_into(msg, &mut tokens, Some(&render_options))?;
+            let trainable_from = if msg.author.role == Role::Assistant {
+                let prefix_len = 1 + self
+                    .tokenizer
+                    .encode_ordinary(Role::Assistant.as_str())
+                    .len();
+                start + prefix_len
+            } else {
+                tokens.len()
+            };
+            mask.resize(trainable_from, false);
+            mask.resize(tokens.len(), true);
+        }
+        if let Some(last) = messages.last() {
+            if last.author.role == Role::Assistant && last.channel.as_deref() == Some("final") {
+                if let Some(last_token) = tokens.last_mut() {
+                    *last_token =
+                        self.render_formatting_token(FormattingToken::EndMessageDoneSampling)?;
+                }
+            }
+        }
+        Ok((tokens, mask))
+    }
+
+    /// Render only the messages after the first `prefix_len`, such that the
+    /// result appended to `render_conversation` of those first messages (with
+    /// the same `config`) equals `render_conversation` of the whole
+    /// conversation.
+    ///
+    /// Fails when the later messages would change how the prefix renders,
+    /// e.g. because they add function tools or let `auto_drop_analysis` drop an
+    /// analysis message inside the prefix; the prefix then has to be rendered
+    /// again.
+    pub fn render_conversation_suffix<'a, I>(
+        &self,
+        conversation: I,
+        prefix_len: usize,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let messages: Vec<&Message> = conversation.into_iter().collect();
+        if prefix_len > messages.len() {
+            anyhow::bail!(
+                "prefix of {} messages is longer than the conversation of {}",
+                prefix_len,
+                messages.len()
+            );
+        }
+        let (prefix_options, prefix_rendered) =
+            Self::conversation_render_plan(&messages[..prefix_len], config);
+        let (render_options, rendered) = Self::conversation_render_plan(&messages, config);
+
+        // Rendering only ever drops messages, so the prefix renders the same
+        // way within the whole conversation when as many of its messages are
+        // kept and the options agree.
+        let kept_from_prefix = rendered
+            .iter()
+            .filter(|msg| {
+                messages[..prefix_len]
+                    .iter()
+                    .any(|p| std::ptr::eq(*p, **msg))
+            })
+            .count();
+        if kept_from_prefix != prefix_rendered.len()
+            || prefix_options.conversation_has_function_tools
+                != render_options.conversation_has_function_tools
+        {
+            anyhow::bail!(
+                "the new messages change how the first {} messages render",
+                prefix_len
+            );
+        }
+
+        let mut out = vec![];
+        for msg in &rendered[kept_from_prefix..] {
+            self.render_into(msg, &mut out, Some(&render_options))?;
+        }
+        Ok(out)
+    }
+
     /// Render a conversation without appending a new role.
     pub fn render_conversation<'a, I>(
         &self,
@@ -299,6 +491,93 @@ impl HarmonyEncoding {
         Ok(out)
     }
 
+    /// Number of tokens each message contributes to `render_conversation`,
+    /// including its header and closing token, in conversation order.
+    /// Messages that `config` drops count 0, so the lengths add up to the
+    /// length of the whole render.
+    pub fn render_conversation_message_lengths<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<usize>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let messages: Vec<&Message> = conversation.into_iter().collect();
+        let (render_options, rendered) = Self::conversation_render_plan(&messages, config);
+        let mut tokens = vec![];
+        messages
+            .iter()
+            .map(|msg| {
+                if !rendered.iter().any(|r| std::ptr::eq(*r, *msg)) {
+                    return Ok(0);
+                }
+                tokens.clear();
+                self.render_into(msg, &mut tokens, Some(&render_options))?;
+                Ok(tokens.len())
+            })
+            .collect()
+    }
+
+    /// Drop the oldest messages other than system and developer messages until
+    /// the conversation renders to at most `max_tokens`. With `keep_last_user`
+    /// the most recent user message is never dropped. Returns the remaining
+    /// messages and their rendered length, which is still over the budget when
+    /// the messages that are kept do not fit on their own.
+    pub fn truncate_conversation_to_budget<'a, I>(
+        &self,
+        conversation: I,
+        max_tokens: usize,
+        keep_last_user: bool,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Message>, usize)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut messages: Vec<&Message> = conversation.into_iter().collect();
+        let last_user = messages
+            .iter()
+            .rposition(|msg| msg.author.role == Role::User)
+            .filter(|_| keep_last_user);
+        let mut keep: Vec<bool> = messages
+            .iter()
+            .enumerate()
+            .map(|(idx, msg)| {
+                matches!(msg.author.role, Role::System | Role::Developer) || Some(idx) == last_user
+            })
+            .collect();
+
+        loop {
+            let lengths =
+                self.render_conversation_message_lengths(messages.iter().copied(), config)?;
+            let total: usize = lengths.iter().sum();
+            if total <= max_tokens {
+                return Ok((messages.into_iter().cloned().collect(), total));
+            }
+
+            // Message lengths barely depend on each other, so drop as many of
+            // the oldest messages as should cover the excess and measure again.
+            let mut excess = total - max_tokens;
+            let mut dropped = vec![false; messages.len()];
+            for idx in 0..messages.len() {
+                if excess == 0 {
+                    break;
+                }
+                if !keep[idx] {
+                    dropped[idx] = true;
+                    excess = excess.saturating_sub(lengths[idx]);
+                }
+            }
+            if !dropped.contains(&true) {
+                return Ok((messages.into_iter().cloned().collect(), total));
+            }
+            let mut flags = dropped.iter();
+            messages.retain(|_| !flags.next().unwrap());
+            let mut flags = dropped.iter();
+            keep.retain(|_| !flags.next().unwrap());
+        }
+    }
+
     /// Render a single message into tokens.
     pub fn render(
         &self,
@@ -310,6 +589,26 @@ impl HarmonyEncoding {
         Ok(out)
     }
 
+    /// Render the header tokens that put a message on `channel`, exactly as
+    /// they appear in a rendered message header.
+    pub fn render_channel_marker(&self, channel: &str) -> anyhow::Result<Vec<Rank>> {
+        let mut out = vec![];
+        self.render_formatting_token_into(FormattingToken::Channel, &mut out)?;
+        self.render_text_into(channel, &mut out)?;
+        Ok(out)
+    }
+
+    /// Render the header tokens that address a message to `recipient`, exactly
+    /// as they appear in a rendered message header. The recipient `all` is
+    /// implied and renders no tokens.
+    pub fn render_recipient_marker(&self, recipient: &str) -> anyhow::Result<Vec<Rank>> {
+        let mut out = vec![];
+        if recipient != "all" {
+            self.render_text_into(format!(" to={recipient}"), &mut out)?;
+        }
+        Ok(out)
+    }
+
     /// Render a single message into the provided buffer.
     pub fn render_into<B>(
         &self,
@@ -330,7 +629,7 @@ impl HarmonyEncoding {
         self.format_token_mapping.get(&t).map(|s| s.as_str())
     }
 
-    fn render_formatting_token(
+    pub(crate) fn render_formatting_token(
         &self,
         t: FormattingToken,
     ) -> Result<Rank, RenderFormattingTokenError> {
@@ -822,15 +1121,12 @@ impl Render<Message> for HarmonyEncoding {
 
         // next render the header recipient, if there is one
         if let Some(recipient) = &message.recipient {
-            if recipient != "all" {
-                self.render_text_into(format!(" to={recipient}"), into)?;
-            }
+            into.extend(self.render_recipient_marker(recipient)?);
         }
 
         // next header channel
         if let Some(channel) = &message.channel {
-            self.render_formatting_token_into(FormattingToken::Channel, into)?;
-            self.render_text_into(channel, into)?;
+            into.extend(self.render_channel_marker(channel)?);
         }
 
         // finally content type
@@ -1014,11 +1310,15 @@ pub struct StreamableParser {
     messages: Vec<Message>,
     state: StreamState,
     stop_tokens: HashSet<Rank>,
+    stop_tokens_for_assistant_actions: HashSet<Rank>,
+    stopped: bool,
     last_content_delta: Option<String>,
     undecoded_tokens: Vec<Rank>,
+    warnings: Vec<String>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "state")]
 pub enum StreamState {
     ExpectStart,
     Header {
@@ -1030,10 +1330,27 @@ pub enum StreamState {
     },
 }
 
+// Serialized form of a StreamableParser, see StreamableParser::snapshot_json.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ParserSnapshot {
+    encoding: String,
+    #[serde(flatten)]
+    state: StreamState,
+    next_role: Option<Role>,
+    tokens: Vec<Rank>,
+    messages: Vec<Message>,
+    stopped: bool,
+    last_content_delta: Option<String>,
+    undecoded_tokens: Vec<Rank>,
+    #[serde(default)]
+    warnings: Vec<String>,
+}
+
 impl StreamableParser {
     /// Create a new streaming parser starting with the given role.
     pub fn new(encoding: HarmonyEncoding, role: Option<Role>) -> anyhow::Result<Self> {
         let stop_tokens = encoding.stop_tokens()?;
+        let stop_tokens_for_assistant_actions = encoding.stop_tokens_for_assistant_actions()?;
         let (state, next_role) = match role {
             Some(role) => (
                 StreamState::Header {
@@ -1050,16 +1367,39 @@ impl StreamableParser {
             messages: Vec::new(),
             state,
             stop_tokens,
+            stop_tokens_for_assistant_actions,
+            stopped: false,
             last_content_delta: None,
             undecoded_tokens: Vec::new(),
+            warnings: Vec::new(),
         })
     }
 
-    /// Consume a single token and update the internal state.
-    /// Consume a single token and update the internal state.
-    fn process_next(&mut self, token: Option<Rank>) -> anyhow::Result<&mut Self> {
+    /// Return the parser to the state of a freshly constructed one for `role`,
+    /// keeping the encoding and the capacity of the internal buffers.
+    pub fn reset(&mut self, role: Option<Role>) {
+        self.state = match role {
+            Some(_) => StreamState::Header {
+                header_tokens: Vec::new(),
+            },
+            None => StreamState::ExpectStart,
+        };
+        self.next_role = role;
+        self.tokens.clear();
+        self.messages.clear();
+        self.stopped = false;
+        self.last_content_delta = None;
+        self.undecoded_tokens.clear();
+        self.warnings.clear();
+    }
+
+    /// Consume a single token and update the internal state. In lenient mode
+    /// structural errors are recorded as warnings and the offending tokens are
+    /// kept as message content instead.
+    fn process_next(&mut self, token: Option<Rank>, lenient: bool) -> anyhow::Result<&mut Self> {
         if let Some(token) = token {
             self.tokens.push(token);
+            self.stopped |= self.stop_tokens_for_assistant_actions.contains(&token);
         }
         // Clone next_role up front to avoid borrow checker issues
         let next_role_clone = self.next_role.clone();
@@ -1074,6 +1414,14 @@ impl StreamableParser {
                             header_tokens: Vec::new(),
                         };
                     }
+                    Some(token) if lenient => {
+                        self.warnings.push(format!(
+                            "Unexpected token {} while expecting start token {}, treating it as content",
+                            token, start
+                        ));
+                        self.start_recovered_message(next_role_clone, Vec::new());
+                        return self.process_content(Some(token), lenient);
+                    }
                     Some(token) => {
                         anyhow::bail!(
                             "Unexpected token {} while expecting start token {}",
@@ -1099,13 +1447,29 @@ impl StreamableParser {
                         let next_role_cloned = next_role_clone;
                         // Set state to dummy to drop mutable borrow
                         self.state = StreamState::ExpectStart;
-                        let header =
-                            self.parse_header_from_tokens(&header_tokens_cloned, next_role_cloned)?;
-                        self.next_role = None;
-                        self.state = StreamState::Content {
-                            header,
-                            content_tokens: Vec::new(),
-                        };
+                        match self.parse_header_from_tokens(
+                            &header_tokens_cloned,
+                            next_role_cloned.clone(),
+                        ) {
+                            Ok(header) => {
+                                self.next_role = None;
+                                self.state = StreamState::Content {
+                                    header,
+                                    content_tokens: Vec::new(),
+                                };
+                            }
+                            Err(e) if lenient => {
+                                self.warnings.push(format!(
+                                    "Invalid message header ({}), treating it as content",
+                                    e
+                                ));
+                                self.start_recovered_message(
+                                    next_role_cloned,
+                                    header_tokens_cloned,
+                                );
+                            }
+                            Err(e) => return Err(e),
+                        }
                     }
                     Some(token) => {
                         header_tokens.push(token);
@@ -1117,67 +1481,105 @@ impl StreamableParser {
                     }
                 }
             }
-            StreamState::Content {
-                header,
-                content_tokens,
-            } => {
-                let is_eos = if let Some(token) = token {
-                    if self.stop_tokens.contains(&token) {
-                        // this is a stop token, dont parse and mark EOS
-                        true
-                    } else {
-                        self.undecoded_tokens.push(token);
-                        // some tokens might not appropriately decode on their own. If they don't
-                        // we will collect them until they eventually decode
-                        match self
-                            .encoding
-                            .tokenizer()
-                            .decode_utf8(&self.undecoded_tokens)
-                        {
-                            Ok(decoded) => {
-                                content_tokens.extend(self.undecoded_tokens.iter().copied());
-                                self.last_content_delta = Some(decoded);
-                                self.undecoded_tokens.clear();
-                            }
-                            Err(_) => {
-                                self.last_content_delta = None;
-                            }
+            StreamState::Content { .. } => return self.process_content(token, lenient),
+        }
+        Ok(self)
+    }
+
+    // Enter the content state for a message whose header could not be parsed,
+    // attributing it to the expected role and keeping `content_tokens` as text.
+    fn start_recovered_message(&mut self, role: Option<Role>, content_tokens: Vec<Rank>) {
+        let header = ParsedHeader {
+            author: Author::from(role.unwrap_or(Role::Assistant)),
+            recipient: None,
+            channel: None,
+            content_type: None,
+        };
+        self.next_role = None;
+        self.state = StreamState::Content {
+            header,
+            content_tokens,
+        };
+    }
+
+    fn process_content(&mut self, token: Option<Rank>, lenient: bool) -> anyhow::Result<&mut Self> {
+        if let StreamState::Content {
+            header,
+            content_tokens,
+        } = &mut self.state
+        {
+            let is_eos = if let Some(token) = token {
+                if self.stop_tokens.contains(&token) {
+                    // this is a stop token, dont parse and mark EOS
+                    true
+                } else {
+                    self.undecoded_tokens.push(token);
+                    // some tokens might not appropriately decode on their own. If they don't
+                    // we will collect them until they eventually decode
+                    match self
+                        .encoding
+                        .tokenizer()
+                        .decode_utf8(&self.undecoded_tokens)
+                    {
+                        Ok(decoded) => {
+                            content_tokens.extend(self.undecoded_tokens.iter().copied());
+                            self.last_content_delta = Some(decoded);
+                            self.undecoded_tokens.clear();
+                        }
+                        Err(_) => {
+                            self.last_content_delta = None;
                         }
-                        // this was not an EOS
-                        false
                     }
-                } else {
-                    // token = None signals EOS to this function
-                    true
-                };
-                if is_eos {
-                    let text = self.encoding.tokenizer().decode_utf8(content_tokens)?;
-                    let message = Message {
-                        author: header.author.clone(),
-                        recipient: header.recipient.clone(),
-                        channel: header.channel.clone(),
-                        content_type: header.content_type.clone(),
-                        content: vec![Content::Text(TextContent { text })],
-                    };
-                    self.messages.push(message);
-                    self.state = StreamState::ExpectStart;
-                    self.last_content_delta = None;
-                    self.undecoded_tokens.clear();
+                    // this was not an EOS
+                    false
                 }
+            } else {
+                // token = None signals EOS to this function
+                true
+            };
+            if is_eos {
+                let text = match self.encoding.tokenizer().decode_utf8(&*content_tokens) {
+                    Ok(text) => text,
+                    Err(e) if lenient => {
+                        self.warnings
+                            .push(format!("{}, replacing invalid UTF-8 in content", e));
+                        let bytes = self.encoding.tokenizer().decode_bytes(&*content_tokens)?;
+                        String::from_utf8_lossy(&bytes).into_owned()
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let message = Message {
+                    author: header.author.clone(),
+                    recipient: header.recipient.clone(),
+                    channel: header.channel.clone(),
+                    content_type: header.content_type.clone(),
+                    content: vec![Content::Text(TextContent { text })],
+                };
+                self.messages.push(message);
+                self.state = StreamState::ExpectStart;
+                self.last_content_delta = None;
+                self.undecoded_tokens.clear();
             }
         }
         Ok(self)
     }
 
     pub fn process(&mut self, token: Rank) -> anyhow::Result<&mut Self> {
-        self.process_next(Some(token))
+        self.process_next(Some(token), false)
     }
 
     pub fn process_eos(&mut self) -> anyhow::Result<&mut Self> {
-        self.process_next(None)?;
+        self.process_next(None, false)?;
         Ok(self)
     }
 
+    /// Like [`Self::process`], but a token that does not fit the message
+    /// structure is treated as content instead of failing the stream. Each
+    /// recovery is recorded in [`Self::warnings`].
+    pub fn process_lenient(&mut self, token: Rank) -> anyhow::Result<&mut Self> {
+        self.process_next(Some(token), true)
+    }
+
     fn parse_header_from_tokens(
         &self,
         header_tokens: &[Rank],
@@ -1333,6 +1735,11 @@ impl StreamableParser {
         }
     }
 
+    /// The state the parser is currently in.
+    pub fn state(&self) -> &StreamState {
+        &self.state
+    }
+
     /// Current content type if known.
     pub fn current_content_type(&self) -> Option<String> {
         match &self.state {
@@ -1346,6 +1753,24 @@ impl StreamableParser {
         Ok(self.last_content_delta.clone())
     }
 
+    /// Whether `<|return|>` or `<|call|>` has been consumed since the parser
+    /// was created or reset, i.e. the assistant's sampling should have ended.
+    /// An `<|end|>` closing e.g. an analysis message does not count, as the
+    /// final message is still to come.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Whether content tokens are buffered waiting to complete a UTF-8 sequence.
+    pub fn has_undecoded_tokens(&self) -> bool {
+        !self.undecoded_tokens.is_empty()
+    }
+
+    /// Problems recovered from by the lenient processing methods, oldest first.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
     /// Consume the parser and return all parsed messages.
     pub fn into_messages(self) -> Vec<Message> {
         self.messages
@@ -1361,34 +1786,59 @@ impl StreamableParser {
         &self.tokens
     }
 
+    /// The encoding the parser decodes with.
+    pub fn encoding(&self) -> &HarmonyEncoding {
+        &self.encoding
+    }
+
     /// Expose the current state as a JSON string for Python interop.
     pub fn state_json(&self) -> anyhow::Result<String> {
-        #[derive(serde::Serialize)]
-        #[serde(tag = "state")]
-        enum SerializableStreamState<'a> {
-            ExpectStart,
-            Header {
-                header_tokens: &'a [Rank],
-            },
-            Content {
-                header: &'a ParsedHeader,
-                content_tokens: &'a [Rank],
-            },
-        }
-        let serializable = match &self.state {
-            StreamState::ExpectStart => SerializableStreamState::ExpectStart,
-            StreamState::Header { header_tokens } => {
-                SerializableStreamState::Header { header_tokens }
-            }
-            StreamState::Content {
-                header,
-                content_tokens,
-            } => SerializableStreamState::Content {
-                header,
-                content_tokens,
-            },
+        Ok(serde_json::to_string(&self.state)?)
+    }
+
+    /// Serialize everything needed to continue parsing elsewhere: the fields
+    /// of [`Self::state_json`] plus the tokens, messages and buffers seen so
+    /// far. Restore it with [`Self::from_snapshot_json`].
+    pub fn snapshot_json(&self) -> anyhow::Result<String> {
+        let snapshot = ParserSnapshot {
+            encoding: self.encoding.name().to_string(),
+            state: self.state.clone(),
+            next_role: self.next_role.clone(),
+            tokens: self.tokens.clone(),
+            messages: self.messages.clone(),
+            stopped: self.stopped,
+            last_content_delta: self.last_content_delta.clone(),
+            undecoded_tokens: self.undecoded_tokens.clone(),
+            warnings: self.warnings.clone(),
         };
-        Ok(serde_json::to_string(&serializable)?)
+        Ok(serde_json::to_string(&snapshot)?)
+    }
+
+    /// Recreate a parser from [`Self::snapshot_json`] output. Processing
+    /// further tokens gives the same results as the original parser would.
+    pub fn from_snapshot_json(encoding: HarmonyEncoding, json: &str) -> anyhow::Result<Self> {
+        let snapshot: ParserSnapshot = serde_json::from_str(json)?;
+        anyhow::ensure!(
+            snapshot.encoding == encoding.name(),
+            "snapshot was taken with encoding {} but restored with {}",
+            snapshot.encoding,
+            encoding.name()
+        );
+        let stop_tokens = encoding.stop_tokens()?;
+        let stop_tokens_for_assistant_actions = encoding.stop_tokens_for_assistant_actions()?;
+        Ok(Self {
+            encoding,
+            next_role: snapshot.next_role,
+            tokens: snapshot.tokens,
+            messages: snapshot.messages,
+            state: snapshot.state,
+            stop_tokens,
+            stop_tokens_for_assistant_actions,
+            stopped: snapshot.stopped,
+            last_content_delta: snapshot.last_content_delta,
+            undecoded_tokens: snapshot.undecoded_tokens,
+            warnings: snapshot.warnings,
+        })
     }
 
     /// Return the current recipient if known.
@@ -8,6 +8,7 @@ pub mod tiktoken_ext;
 
 pub use encoding::{HarmonyEncoding, StreamableParser};
 pub use registry::load_harmony_encoding;
+pub use registry::load_harmony_encoding_from_data;
 pub use registry::HarmonyEncodingName;
 
 #[cfg(test)]
@@ -5,6 +5,7 @@ use std::{
 
 use crate::{
     encoding::{FormattingToken, HarmonyEncoding},
+    tiktoken::CoreBPE,
     tiktoken_ext,
 };
 
@@ -43,15 +44,36 @@ impl std::fmt::Debug for HarmonyEncodingName {
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<HarmonyEncoding> {
+    let tokenizer = tiktoken_encoding(name).load()?;
+    Ok(harmony_encoding(name, tokenizer))
+}
+
+/// Load an encoding from an in-memory copy of its tokenizer's vocab file
+/// instead of the bundled or downloaded one.
+pub fn load_harmony_encoding_from_data(
+    name: HarmonyEncodingName,
+    vocab_data: &[u8],
+) -> anyhow::Result<HarmonyEncoding> {
+    let tokenizer = tiktoken_encoding(name).load_from_bytes(vocab_data)?;
+    Ok(harmony_encoding(name, tokenizer))
+}
+
+fn tiktoken_encoding(name: HarmonyEncodingName) -> tiktoken_ext::Encoding {
+    match name {
+        HarmonyEncodingName::HarmonyGptOss => tiktoken_ext::Encoding::O200kHarmony,
+    }
+}
+
+fn harmony_encoding(name: HarmonyEncodingName, tokenizer: CoreBPE) -> HarmonyEncoding {
     match name {
         HarmonyEncodingName::HarmonyGptOss => {
             let n_ctx = 1_048_576; // 2^20
             let max_action_length = 524_288; // 2^19
-            let encoding_ext = tiktoken_ext::Encoding::O200kHarmony;
-            Ok(HarmonyEncoding {
+            let encoding_ext = tiktoken_encoding(name);
+            HarmonyEncoding {
                 name: name.to_string(),
                 n_ctx,
-                tokenizer: Arc::new(encoding_ext.load()?),
+                tokenizer: Arc::new(tokenizer),
                 tokenizer_name: encoding_ext.name().to_owned(),
                 max_message_tokens: n_ctx - max_action_length,
                 max_action_length,
@@ -76,7 +98,7 @@ pub fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<Harmon
                     FormattingToken::EndMessageDoneSampling,
                     FormattingToken::EndMessageAssistantToTool,
                 ]),
-            })
+            }
         }
     }
 }
@@ -3,11 +3,11 @@ use std::path::Path;
 use crate::{
     chat::{
         Author, Conversation, DeveloperContent, Message, ReasoningEffort, Role, SystemContent,
-        ToolDescription,
+        ToolDescription, ToolNamespaceConfig,
     },
     load_harmony_encoding,
     tiktoken::{CoreBPE, Rank},
-    HarmonyEncodingName, StreamableParser,
+    HarmonyEncoding, HarmonyEncodingName, StreamableParser,
 };
 use pretty_assertions::{assert_eq, Comparison};
 use serde_json::json;
@@ -477,6 +477,84 @@ fn test_does_not_drop_if_ongoing_analysis() {
     assert_eq!(decoded, expected_output);
 }
 
+#[test]
+fn test_tool_response_continuation() {
+    let encoding = load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap();
+    let expected_output = load_test_data("../test-data/test_tool_response_continuation.txt");
+
+    let convo = Conversation::from_messages([
+        Message::from_role_and_content(Role::User, "What is the weather in SF?"),
+        Message::from_role_and_content(
+            Role::Assistant,
+            "User asks for the weather in SF. We need to use the lookup_weather tool.",
+        )
+        .with_channel("analysis"),
+        Message::from_role_and_content(Role::Assistant, "{\"location\": \"San Francisco\"}")
+            .with_channel("commentary")
+            .with_recipient("functions.lookup_weather")
+            .with_content_type("<|constrain|>json"),
+    ]);
+
+    let tokens = encoding
+        .render_tool_response_for_completion(
+            &convo,
+            "{\"temperature\": 20, \"description\": \"sunny\"}",
+            Some(&crate::encoding::RenderConversationConfig {
+                auto_drop_analysis: true,
+            }),
+        )
+        .unwrap();
+
+    let decoded = encoding.tokenizer.decode_utf8(&tokens).unwrap();
+    assert_eq!(decoded, expected_output);
+
+    // Without a pending tool call there is nothing to respond to
+    let answered = Conversation::from_messages([Message::from_role_and_content(
+        Role::User,
+        "What is the weather in SF?",
+    )]);
+    assert!(encoding
+        .render_tool_response_for_completion(&answered, "{}", None)
+        .is_err());
+}
+
+#[test]
+fn test_tool_results_keep_their_authors() {
+    let encoding = load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap();
+    let call = |tool: &str| {
+        Message::from_role_and_content(Role::Assistant, "{\"city\": \"Paris\"}")
+            .with_channel("commentary")
+            .with_recipient(tool)
+    };
+    let result = |tool: &str, output: &str| {
+        Message::from_author_and_content(Author::new(Role::Tool, tool), output)
+            .with_recipient("assistant")
+            .with_channel("commentary")
+    };
+    let convo = Conversation::from_messages([
+        Message::from_role_and_content(Role::User, "Weather and time in Paris?"),
+        call("functions.get_weather"),
+        result("functions.get_weather", "{\"temp\": 20}"),
+        call("functions.get_time"),
+        result("functions.get_time", "{\"time\": \"12:00\"}"),
+    ]);
+
+    let tokens = encoding.render_conversation(&convo, None).unwrap();
+    let decoded = encoding.tokenizer.decode_utf8(&tokens).unwrap();
+    assert!(decoded.contains(
+        "<|start|>functions.get_weather to=assistant<|channel|>commentary<|message|>{\"temp\": 20}<|end|>"
+    ));
+    assert!(decoded.ends_with(
+        "<|start|>functions.get_time to=assistant<|channel|>commentary<|message|>{\"time\": \"12:00\"}<|end|>"
+    ));
+
+    // The authors survive the JSON schema too
+    let json = serde_json::to_value(&convo).unwrap();
+    assert_eq!(json["messages"][2]["name"], "functions.get_weather");
+    let roundtrip: Conversation = serde_json::from_value(json).unwrap();
+    assert_eq!(roundtrip, convo);
+}
+
 #[test]
 fn test_preserve_cot() {
     let encoding = load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap();
@@ -709,3 +787,63 @@ fn test_tool_call_with_channel_before_recipient_and_constrain_adjacent() {
     .with_content_type("<|constrain|>json")];
     assert_eq!(parsed, expected);
 }
+
+#[test]
+fn test_harmony_encoding_is_send_and_sync() {
+    // Bindings share one encoding across threads, so this must keep compiling.
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<HarmonyEncoding>();
+    assert_send_sync::<CoreBPE>();
+}
+
+#[test]
+fn test_developer_content_namespace_for_recipient() {
+    let lookup = |name: &str| ToolDescription::new(name, "Looks something up.", None);
+    let developer = DeveloperContent::new()
+        .with_function_tools(vec![lookup("lookup")])
+        .with_tools(ToolNamespaceConfig::new(
+            "crm",
+            None,
+            vec![lookup("lookup"), lookup("update")],
+        ))
+        .with_tools(ToolNamespaceConfig::new("python", None, vec![]));
+
+    let namespace = |recipient| {
+        developer
+            .namespace_for_recipient(recipient)
+            .map(|namespace| namespace.name.as_str())
+    };
+    assert_eq!(namespace("functions.lookup"), Some("functions"));
+    assert_eq!(namespace("crm.lookup"), Some("crm"));
+    assert_eq!(namespace("crm.update"), Some("crm"));
+    assert_eq!(namespace("python"), Some("python"));
+    assert_eq!(namespace("functions.update"), None);
+    assert_eq!(namespace("crmx.lookup"), None);
+    assert_eq!(namespace("lookup"), None);
+}
+
+#[test]
+fn test_streamable_parser_lenient_recovers_unexpected_tokens() {
+    let encoding = load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap();
+    let text = "<|start|>assistant<|channel|>final<|message|>Hi<|end|><|message|>oops<|end|><|start|>assistant<|channel|><|message|>again<|end|>";
+    let tokens = encoding.tokenizer().encode_with_special_tokens(text);
+
+    let mut strict = StreamableParser::new(encoding.clone(), None).unwrap();
+    assert!(tokens.iter().any(|&token| strict.process(token).is_err()));
+
+    let mut parser = StreamableParser::new(encoding, None).unwrap();
+    for token in tokens {
+        parser.process_lenient(token).unwrap();
+    }
+    assert_eq!(
+        parser.messages(),
+        [
+            Message::from_role_and_content(Role::Assistant, "Hi").with_channel("final"),
+            Message::from_role_and_content(Role::Assistant, "<|message|>oops"),
+            Message::from_role_and_content(Role::Assistant, "assistant<|channel|>again"),
+        ]
+    );
+    assert_eq!(parser.warnings().len(), 2);
+    assert!(parser.warnings()[0].starts_with("Unexpected token"));
+    assert!(parser.warnings()[1].starts_with("Invalid message header"));
+}
@@ -183,6 +183,14 @@ impl CoreBPE {
         &self.special_regex_tls[hash_current_thread() % MAX_NUM_THREADS]
     }
 
+    /// The bytes a single token decodes to, or `None` for an unknown token.
+    pub fn token_bytes(&self, token: Rank) -> Option<&[u8]> {
+        self.decoder
+            .get(&token)
+            .or_else(|| self.special_tokens_decoder.get(&token))
+            .map(Vec::as_slice)
+    }
+
     pub fn decode_bytes<S, E>(&self, tokens: S) -> Result<Vec<u8>, DecodeKeyError>
     where
         S: IntoIterator<Item = E>,
@@ -233,7 +241,48 @@ impl CoreBPE {
         ret
     }
 
+    /// Like [`Self::encode_ordinary`], but gives up and returns `None` as soon as
+    /// more than `max_tokens` tokens have been produced.
+    pub fn encode_ordinary_up_to(&self, text: &str, max_tokens: usize) -> Option<Vec<Rank>> {
+        let regex = self._get_tl_regex();
+        let mut ret = vec![];
+        for mat in regex.find_iter(text) {
+            let piece = mat.unwrap().as_str().as_bytes();
+            match self.encoder.get(piece) {
+                Some(token) => ret.push(*token),
+                None => ret.extend(&byte_pair_encode(piece, &self.encoder)),
+            }
+            if ret.len() > max_tokens {
+                return None;
+            }
+        }
+        Some(ret)
+    }
+
     pub fn encode(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<Rank>, usize) {
+        self.encode_with_limit(text, allowed_special, None)
+            .expect("encoding without a limit always completes")
+    }
+
+    /// Like [`Self::encode`], but gives up and returns `None` as soon as more
+    /// than `max_tokens` tokens have been produced.
+    pub fn encode_up_to(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        max_tokens: usize,
+    ) -> Option<Vec<Rank>> {
+        self.encode_with_limit(text, allowed_special, Some(max_tokens))
+            .map(|(tokens, _)| tokens)
+    }
+
+    fn encode_with_limit(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        max_tokens: Option<usize>,
+    ) -> Option<(Vec<Rank>, usize)> {
+        let over_limit = |tokens: &[Rank]| max_tokens.is_some_and(|max| tokens.len() > max);
         let special_regex = self._get_tl_special_regex();
         let regex = self._get_tl_regex();
         let mut ret = vec![];
@@ -264,11 +313,14 @@ impl CoreBPE {
                 if let Some(token) = self.encoder.get(piece) {
                     last_piece_token_len = 1;
                     ret.push(*token);
-                    continue;
+                } else {
+                    let tokens = byte_pair_encode(piece, &self.encoder);
+                    last_piece_token_len = tokens.len();
+                    ret.extend(&tokens);
+                }
+                if over_limit(&ret) {
+                    return None;
                 }
-                let tokens = byte_pair_encode(piece, &self.encoder);
-                last_piece_token_len = tokens.len();
-                ret.extend(&tokens);
             }
 
             match next_special {
@@ -279,6 +331,9 @@ impl CoreBPE {
                     ret.push(token);
                     start = m.end();
                     last_piece_token_len = 0;
+                    if over_limit(&ret) {
+                        return None;
+                    }
                 }
                 None => break,
             }
@@ -286,7 +341,7 @@ impl CoreBPE {
 
         // last_piece_token_len is how many tokens came from the last regex split. This is used
         // for determining unstable tokens, since you can't merge across (stable) regex splits
-        (ret, last_piece_token_len)
+        Some((ret, last_piece_token_len))
     }
 
     fn _increase_last_piece_token_len(
@@ -514,6 +569,12 @@ impl CoreBPE {
             .collect()
     }
 
+    pub fn special_token_ranks(&self) -> impl Iterator<Item = (&str, Rank)> + '_ {
+        self.special_tokens_encoder
+            .iter()
+            .map(|(s, rank)| (s.as_str(), *rank))
+    }
+
     pub fn encode_with_special_tokens(&self, text: &str) -> Vec<Rank> {
         let allowed_special = self.special_tokens();
         self.encode(text, &allowed_special).0
@@ -522,4 +583,18 @@ impl CoreBPE {
     pub fn is_special_token(&self, token: Rank) -> bool {
         self.special_tokens_decoder.contains_key(&token)
     }
+
+    /// Number of token ids the tokenizer knows, ordinary and special.
+    pub fn vocab_size(&self) -> usize {
+        self.decoder.len() + self.special_tokens_decoder.len()
+    }
+
+    /// The largest token id, ordinary or special.
+    pub fn max_token_id(&self) -> Option<Rank> {
+        self.decoder
+            .keys()
+            .chain(self.special_tokens_decoder.keys())
+            .copied()
+            .max()
+    }
 }
@@ -136,70 +136,12 @@ impl Encoding {
                 )
             };
 
-        match self {
-            Self::O200kHarmony => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((200014..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        specials,
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-                }
-            }
-            Self::O200kBase => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((199998..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        specials,
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-                }
-            }
-            _ => {
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        self.special_tokens().iter().cloned(),
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(
-                        &vocab_bytes,
-                        None,
-                        self.special_tokens().iter().cloned(),
-                        &self.pattern(),
-                    )
-                }
-            }
-        }
+        load_encoding_from_file(
+            vocab_file_path,
+            check_hash.then(|| self.expected_hash()),
+            self.specials(),
+            &self.pattern(),
+        )
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -209,32 +151,19 @@ impl Encoding {
             .await
             .map_err(LoadError::DownloadOrLoadVocabFile)?;
 
-        match self {
-            Self::O200kHarmony => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((200014..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-            }
-            Self::O200kBase => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((199998..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-            }
-            _ => load_encoding_from_bytes(
-                &vocab_bytes,
-                None,
-                self.special_tokens().iter().cloned(),
-                &self.pattern(),
-            ),
-        }
+        load_encoding_from_bytes(&vocab_bytes, None, self.specials(), &self.pattern())
+    }
+
+    /// Build the encoding from an in-memory copy of its vocab file, e.g. one
+    /// shipped as an application resource. The data must match the hash of the
+    /// published vocab file.
+    pub fn load_from_bytes(&self, vocab_bytes: &[u8]) -> Result<CoreBPE, LoadError> {
+        load_encoding_from_bytes(
+            vocab_bytes,
+            Some(self.expected_hash()),
+            self.specials(),
+            &self.pattern(),
+        )
     }
 
     fn public_vocab_file_url(&self) -> String {
@@ -295,6 +224,23 @@ impl Encoding {
         }
     }
 
+    /// Every special token of the encoding, including the unnamed
+    /// `<|reserved_N|>` ranks that fill the rest of the vocab.
+    fn specials(&self) -> Vec<(String, Rank)> {
+        let mut specials: Vec<(String, Rank)> = self
+            .special_tokens()
+            .iter()
+            .map(|(s, r)| ((*s).to_string(), *r))
+            .collect();
+        let reserved = match self {
+            Self::O200kHarmony => 200014..=201088,
+            Self::O200kBase => 199998..=201088,
+            Self::Cl100kBase => return specials,
+        };
+        specials.extend(reserved.map(|id| (format!("<|reserved_{id}|>"), id)));
+        specials
+    }
+
     fn pattern(&self) -> String {
         match self {
             Self::O200kBase => {
@@ -411,6 +357,26 @@ where
     .map_err(LoadError::CoreBPECreationFailed)
 }
 
+pub fn load_encoding_from_bytes<S, TS>(
+    vocab_bytes: &[u8],
+    expected_hash: Option<&str>,
+    special_tokens: S,
+    pattern: &str,
+) -> Result<CoreBPE, LoadError>
+where
+    S: IntoIterator<Item = (TS, Rank)>,
+    TS: Into<String>,
+{
+    let encoder = load_tiktoken_vocab(vocab_bytes, expected_hash)
+        .map_err(LoadError::InvalidTiktokenVocabFile)?;
+    CoreBPE::new(
+        encoder,
+        special_tokens.into_iter().map(|(k, v)| (k.into(), v)),
+        pattern,
+    )
+    .map_err(LoadError::CoreBPECreationFailed)
+}
+
 /// This returns the path to a file containing the data at `url`. If the file is
 /// cached, it is used. Otherwise, the file is downloaded and cached.
 #[cfg(not(target_arch = "wasm32"))]